@@ -0,0 +1,120 @@
+//! Compare how fast and how well each compression setting handles camera-like frames.
+//! Run with `cargo bench --bench compression`
+//!
+//! Frames are synthetic: a dark background with read noise and a few bright spots, which is what
+//! most of our fluorescence data looks like. A C11440-22CU streams 2048x2048 frames at up to 100 fps,
+//! a setting needs to reach that frame rate to keep up with the camera
+
+use free_willy::{
+    AcquisitionInfo, CompressedFrame, CompressedWriter, Compression, CompressionStage, DcamFrame,
+    FrameWriter,
+};
+use image::{ImageBuffer, Luma};
+use std::io;
+use std::time::{Duration, Instant};
+
+const WIDTH: u32 = 2048;
+const HEIGHT: u32 = 2048;
+const FRAMES: usize = 50;
+
+/// `CompressedWriter` that throws everything away, so we only measure compression
+struct NullWriter;
+
+impl CompressedWriter for NullWriter {
+    fn begin(&mut self, _info: &AcquisitionInfo) -> io::Result<()> {
+        Ok(())
+    }
+    fn write_compressed(&mut self, _frame: &CompressedFrame) -> io::Result<()> {
+        Ok(())
+    }
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// build a dark, noisy frame with a few bright spots
+fn synthetic_frame(seed: u64) -> DcamFrame {
+    //xorshift, we just need something noise-like
+    let mut state = seed.wrapping_mul(0x9E3779B97F4A7C15) | 1;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    let spots: Vec<(u32, u32)> = (0..20)
+        .map(|_| {
+            (
+                (next() % WIDTH as u64) as u32,
+                (next() % HEIGHT as u64) as u32,
+            )
+        })
+        .collect();
+    let image = ImageBuffer::<Luma<u16>, Vec<u16>>::from_fn(WIDTH, HEIGHT, |x, y| {
+        let noise = (next() % 8) as u16;
+        let bright = spots
+            .iter()
+            .any(|(sx, sy)| x.abs_diff(*sx) < 6 && y.abs_diff(*sy) < 6);
+        Luma([100 + noise + if bright { 2000 } else { 0 }])
+    });
+    DcamFrame::new(image, Duration::from_millis(seed * 10), seed as i32)
+}
+
+fn main() {
+    let frames: Vec<DcamFrame> = (0..8).map(synthetic_frame).collect();
+    let frame_bytes = (WIDTH * HEIGHT * 2) as f64;
+    let settings = [
+        Compression::None,
+        Compression::Lz4,
+        Compression::Zstd(1),
+        Compression::Zstd(3),
+        Compression::Zstd(9),
+    ];
+    println!("single thread, {}x{} frames", WIDTH, HEIGHT);
+    for compression in settings {
+        let start = Instant::now();
+        let mut compressed_bytes = 0;
+        for i in 0..FRAMES {
+            let compressed = CompressedFrame::compress(&frames[i % frames.len()], compression)
+                .expect("compression failed");
+            compressed_bytes += compressed.data.len();
+        }
+        let secs = start.elapsed().as_secs_f64();
+        println!(
+            "{:>10}: {:7.1} MB/s {:6.1} frames/s  ratio {:.3}",
+            format!("{:?}", compression),
+            frame_bytes * FRAMES as f64 / secs / 1e6,
+            FRAMES as f64 / secs,
+            compressed_bytes as f64 / (frame_bytes * FRAMES as f64)
+        );
+    }
+    let max_threads = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+    let thread_counts: Vec<usize> = [1, 2, 4, 8, 16]
+        .into_iter()
+        .filter(|n| *n <= max_threads)
+        .collect();
+    println!("CompressionStage, frames/s by thread count");
+    for compression in [Compression::Lz4, Compression::Zstd(1), Compression::Zstd(3)] {
+        let rates: Vec<String> = thread_counts
+            .iter()
+            .map(|threads| {
+                let mut stage = CompressionStage::new(Box::new(NullWriter), compression, *threads);
+                let start = Instant::now();
+                for i in 0..FRAMES {
+                    stage
+                        .write_frame(&frames[i % frames.len()])
+                        .expect("compression failed");
+                }
+                stage.finish().expect("compression failed");
+                format!(
+                    "{} threads {:.1}",
+                    threads,
+                    FRAMES as f64 / start.elapsed().as_secs_f64()
+                )
+            })
+            .collect();
+        println!("{:>10}: {}", format!("{:?}", compression), rates.join(", "));
+    }
+}
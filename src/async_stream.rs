@@ -0,0 +1,136 @@
+//! Consume frames from async code without tying up a thread per consumer.
+//!
+//! `AsyncFrameStream` is a `futures::Stream` of `DcamFrame`s fed by the capture thread of a
+//! `DcamStream`. Frames are queued in a bounded channel, if the consumer falls behind new frames are
+//! dropped rather than holding up capture. Controlling the stream never blocks the executor, requests
+//! are answered by the capture thread through oneshot channels. Requires the `async` feature
+
+use crate::frame::DcamFrame;
+use crate::stats::StatsHandle;
+use crate::trigger::TriggerSource;
+use crate::{C11440_22CUSource, DcamStream, DcamStreamMessage, Reply};
+use futures::channel::{mpsc, oneshot};
+use futures::stream::{Stream, StreamExt};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread;
+
+/// Async wrapper around a `DcamStream`, yields every frame captured after it was created
+pub struct AsyncFrameStream {
+    stream: DcamStream,
+    frames: mpsc::Receiver<DcamFrame>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl AsyncFrameStream {
+    /// Receive frames from `stream` asynchronously, up to `capacity` frames are queued
+    /// waiting for the consumer
+    pub fn new(mut stream: DcamStream, capacity: usize) -> AsyncFrameStream {
+        let (mut frame_tx, frames) = mpsc::channel(capacity);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let thread_dropped = dropped.clone();
+        stream.subscribe(move |frame| match frame_tx.try_send(frame.clone()) {
+            Ok(()) => true,
+            //the consumer went away, stop sending
+            Err(e) if e.is_disconnected() => false,
+            //the consumer is behind, skip this frame
+            Err(_) => {
+                thread_dropped.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+        });
+        AsyncFrameStream {
+            stream,
+            frames,
+            dropped,
+        }
+    }
+    /// Number of frames thrown away because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+    /// Frame rate, throughput and latency of the underlying stream
+    pub fn stats(&self) -> StatsHandle {
+        self.stream.stats()
+    }
+    /// Send a request to the capture thread and wait for the answer
+    async fn request<T: Send + 'static>(
+        &self,
+        message: impl FnOnce(Reply<T>) -> DcamStreamMessage,
+    ) -> T {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.stream
+            .control_tx
+            .send(message(Box::new(move |result| {
+                let _ = reply_tx.send(result);
+            })))
+            .expect("Couldn't communicate with frame grabber");
+        reply_rx
+            .await
+            .expect("Couldn't communicate with frame grabber")
+    }
+    /// Change the exposure while the stream is running, returns the exposure the camera actually used
+    pub async fn set_exposure(&mut self, exposure: f64) -> Result<f64, i32> {
+        self.request(|reply| DcamStreamMessage::SetExposure(exposure, reply))
+            .await
+    }
+    /// Change the region of interest `[hpos, vpos, hsize, vsize]` while the stream is running,
+    /// see `DcamStream::set_roi`
+    pub async fn set_roi(&mut self, roi: [usize; 4]) -> Result<[usize; 4], i32> {
+        self.request(|reply| DcamStreamMessage::SetRoi(roi, reply))
+            .await
+    }
+    /// Change the binning while the stream is running, see `DcamStream::set_binning`
+    pub async fn set_binning(&mut self, binning: i32) -> Result<i32, i32> {
+        self.request(|reply| DcamStreamMessage::SetBinning(binning, reply))
+            .await
+    }
+    /// Change where the camera gets its trigger from while the stream is running
+    pub async fn set_trigger_source(
+        &mut self,
+        source: TriggerSource,
+    ) -> Result<TriggerSource, i32> {
+        self.request(|reply| DcamStreamMessage::SetTrigger(source, reply))
+            .await
+    }
+    /// Send a software trigger to the camera, this only starts an exposure if the
+    /// camera's trigger source is set to software
+    pub async fn fire_trigger(&self) -> Result<(), i32> {
+        self.request(DcamStreamMessage::FireTrigger).await
+    }
+    /// Stop capturing without releasing the camera or buffer, see `DcamStream::pause`
+    pub async fn pause(&mut self) -> Result<(), i32> {
+        self.request(DcamStreamMessage::Pause).await
+    }
+    /// Start capturing again after `pause`
+    pub async fn resume(&mut self) -> Result<(), i32> {
+        self.request(DcamStreamMessage::Resume).await
+    }
+    /// Stop pulling frames and wait for the capture thread to shut down
+    pub async fn stop(self) {
+        let (done_tx, done_rx) = oneshot::channel();
+        //joining blocks, so do it somewhere other than the executor
+        thread::spawn(move || {
+            self.stream.stop();
+            let _ = done_tx.send(());
+        });
+        done_rx.await.expect("Couldn't shut down frame grabber");
+    }
+}
+
+impl Stream for AsyncFrameStream {
+    type Item = DcamFrame;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<DcamFrame>> {
+        self.frames.poll_next_unpin(cx)
+    }
+}
+
+impl C11440_22CUSource {
+    /// Start streaming with frames delivered to an `AsyncFrameStream` instead of a `Sender<Frame>`,
+    /// up to `capacity` frames are queued waiting for the consumer
+    pub fn start_async(&self, capacity: usize) -> AsyncFrameStream {
+        AsyncFrameStream::new(self.start_stream(), capacity)
+    }
+}
@@ -0,0 +1,610 @@
+//! Run a `FrameWriter` on its own thread behind a bounded queue, so a slow disk doesn't hold up capture.
+//!
+//! `AsyncWriter` is itself a `FrameWriter`, so it can be handed to `DcamStream::add_writer` in place
+//! of the writer it wraps. What happens when the queue is full is chosen with an `OverflowPolicy`.
+//!
+//! An `AsyncWriter` started with `AsyncWriter::spool` also takes raw frames for its `SpoolWriter`,
+//! streams use one for every spool so the capture thread only copies the frame out of the buffer
+
+use crate::frame::DcamFrame;
+use crate::spool::SpoolWriter;
+use crate::writer::{AcquisitionInfo, FrameWriter};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// What to do with a new frame when the writer's queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// wait for the writer to catch up, this holds up whoever is sending frames
+    Block,
+    /// throw away the oldest queued frame to make room
+    DropOldest,
+    /// throw away the new frame
+    DropNewest,
+    /// throw away the new frame and report an error, what that means depends on who owns the queue
+    Error,
+}
+
+/// Counters describing how an `AsyncWriter` is keeping up
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct WriterStats {
+    /// frames handed to the wrapped writer
+    pub frames_written: u64,
+    /// frames thrown away because the queue was full
+    pub frames_dropped: u64,
+    /// bytes of pixel data handed to the wrapped writer
+    pub bytes_written: u64,
+    /// frames currently waiting in the queue
+    pub queued: usize,
+    /// most frames that have been waiting in the queue at once
+    pub peak_queued: usize,
+    /// time since the first frame was queued
+    pub elapsed: Duration,
+    /// `true` once the writer has been finished or has failed
+    pub finished: bool,
+}
+
+impl WriterStats {
+    /// Average rate pixel data has been written at in bytes per second
+    pub fn throughput(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.bytes_written as f64 / secs,
+            _ => 0.0,
+        }
+    }
+    /// Average number of frames written per second
+    pub fn frame_rate(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.frames_written as f64 / secs,
+            _ => 0.0,
+        }
+    }
+}
+
+impl fmt::Display for WriterStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "wrote {} frames ({:.1} MB/s, {:.1} frames/s), dropped {}",
+            self.frames_written,
+            self.throughput() / 1e6,
+            self.frame_rate(),
+            self.frames_dropped
+        )
+    }
+}
+
+/// Work for the writer thread
+enum Job {
+    Begin(AcquisitionInfo),
+    Frame(DcamFrame),
+    /// a copy of a frame as it sat in the camera buffer, with its timestamp and framestamp
+    Raw(Vec<u8>, Duration, i32),
+}
+
+impl Job {
+    /// frames count towards the queue's capacity, everything else is always queued
+    fn is_frame(&self) -> bool {
+        matches!(self, Job::Frame(_) | Job::Raw(..))
+    }
+}
+
+/// What the writer thread writes to
+enum Sink {
+    Writer(Box<dyn FrameWriter>),
+    /// spools can also be given raw frames
+    Spool(Box<SpoolWriter>),
+}
+
+impl Sink {
+    fn writer(&mut self) -> &mut dyn FrameWriter {
+        match self {
+            Sink::Writer(writer) => writer.as_mut(),
+            Sink::Spool(spool) => spool.as_mut(),
+        }
+    }
+    fn run(&mut self, job: &Job) -> io::Result<()> {
+        match (job, self) {
+            (Job::Begin(info), sink) => sink.writer().begin(info),
+            (Job::Frame(frame), sink) => sink.writer().write_frame(frame),
+            (Job::Raw(raw, timestamp, framestamp), Sink::Spool(spool)) => {
+                spool.write_raw(raw, *timestamp, *framestamp)
+            }
+            //`AsyncWriter` only queues raw jobs for spools
+            (_, Sink::Writer(_)) => Err(io::Error::other("only spools take raw frames")),
+        }
+    }
+}
+
+/// State shared between an `AsyncWriter`, its thread and any `WriterMonitor`s
+struct Shared {
+    capacity: usize,
+    policy: OverflowPolicy,
+    state: Mutex<State>,
+    /// signalled when a job is queued or the writer is closed
+    job_ready: Condvar,
+    /// signalled when a job is taken off the queue or the thread exits
+    space_ready: Condvar,
+}
+
+struct State {
+    jobs: VecDeque<Job>,
+    /// number of `Job::Frame`s in `jobs`
+    frames_queued: usize,
+    /// no more jobs will be queued
+    closed: bool,
+    /// first error returned by the wrapped writer, nothing is written after this
+    error: Option<io::Error>,
+    stats: WriterStats,
+    started: Option<Instant>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("writer thread panicked")
+    }
+    fn stats(&self) -> WriterStats {
+        let state = self.lock();
+        let mut stats = state.stats;
+        stats.queued = state.frames_queued;
+        if !stats.finished {
+            stats.elapsed = state.started.map(|s| s.elapsed()).unwrap_or_default();
+        }
+        stats
+    }
+}
+
+/// Wraps a `FrameWriter` so frames are written on a separate thread
+pub struct AsyncWriter {
+    shared: Arc<Shared>,
+    thread_handle: Option<JoinHandle<()>>,
+    /// the wrapped writer is a spool, so raw frames can be queued
+    raw: bool,
+}
+
+/// Handle for checking on an `AsyncWriter` after it has been handed off to a stream
+#[derive(Clone)]
+pub struct WriterMonitor {
+    shared: Arc<Shared>,
+}
+
+impl AsyncWriter {
+    /// Start a thread writing frames with `writer`. At most `capacity` frames wait in the queue,
+    /// `policy` decides what happens to frames that don't fit
+    pub fn new(
+        writer: Box<dyn FrameWriter>,
+        capacity: usize,
+        policy: OverflowPolicy,
+    ) -> AsyncWriter {
+        AsyncWriter::start(Sink::Writer(writer), capacity, policy)
+    }
+    /// Start a thread writing to `spool` like `new`, raw frames can be queued for it with `write_raw`
+    pub fn spool(spool: SpoolWriter, capacity: usize, policy: OverflowPolicy) -> AsyncWriter {
+        AsyncWriter::start(Sink::Spool(Box::new(spool)), capacity, policy)
+    }
+    fn start(sink: Sink, capacity: usize, policy: OverflowPolicy) -> AsyncWriter {
+        assert!(capacity > 0, "queue must hold at least one frame");
+        let shared = Arc::new(Shared {
+            capacity,
+            policy,
+            state: Mutex::new(State {
+                jobs: VecDeque::with_capacity(capacity + 1),
+                frames_queued: 0,
+                closed: false,
+                error: None,
+                stats: WriterStats::default(),
+                started: None,
+            }),
+            job_ready: Condvar::new(),
+            space_ready: Condvar::new(),
+        });
+        let thread_shared = shared.clone();
+        let raw = matches!(sink, Sink::Spool(_));
+        let thread_handle = thread::spawn(move || write_jobs(sink, &thread_shared));
+        AsyncWriter {
+            shared,
+            thread_handle: Some(thread_handle),
+            raw,
+        }
+    }
+    /// Get a handle which can be used to check on this writer from another thread
+    pub fn monitor(&self) -> WriterMonitor {
+        WriterMonitor {
+            shared: self.shared.clone(),
+        }
+    }
+    /// Current statistics
+    pub fn stats(&self) -> WriterStats {
+        self.shared.stats()
+    }
+    /// Queue a copy of `raw` to be written with `SpoolWriter::write_raw`. Only writers started with
+    /// `spool` take raw frames, and `SpoolWriter::begin_raw` must have been called before it was wrapped
+    pub fn write_raw(
+        &mut self,
+        raw: &[u8],
+        timestamp: Duration,
+        framestamp: i32,
+    ) -> io::Result<()> {
+        self.check_raw()?;
+        self.queue(Job::Raw(raw.to_vec(), timestamp, framestamp))
+    }
+    fn check_raw(&self) -> io::Result<()> {
+        match self.raw {
+            true => Ok(()),
+            false => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only spools take raw frames",
+            )),
+        }
+    }
+    /// Put `job` on the queue, applying our overflow policy to frames
+    fn queue(&self, job: Job) -> io::Result<()> {
+        let mut state = self.shared.lock();
+        if let Some(e) = &state.error {
+            return Err(io::Error::new(e.kind(), e.to_string()));
+        }
+        if state.closed {
+            return Err(io::Error::other("can't write to a finished writer"));
+        }
+        if job.is_frame() {
+            state.started.get_or_insert_with(Instant::now);
+            if state.frames_queued >= self.shared.capacity {
+                match self.shared.policy {
+                    OverflowPolicy::Block => {
+                        while state.frames_queued >= self.shared.capacity && state.error.is_none() {
+                            state = self
+                                .shared
+                                .space_ready
+                                .wait(state)
+                                .expect("writer thread panicked");
+                        }
+                        if let Some(e) = &state.error {
+                            return Err(io::Error::new(e.kind(), e.to_string()));
+                        }
+                    }
+                    OverflowPolicy::DropOldest => {
+                        if let Some(oldest) = state.jobs.iter().position(Job::is_frame) {
+                            state.jobs.remove(oldest);
+                            state.frames_queued -= 1;
+                            state.stats.frames_dropped += 1;
+                        }
+                    }
+                    OverflowPolicy::DropNewest => {
+                        state.stats.frames_dropped += 1;
+                        return Ok(());
+                    }
+                    //the frame is lost but the writer keeps going, whoever is sending decides what to do
+                    OverflowPolicy::Error => {
+                        state.stats.frames_dropped += 1;
+                        return Err(io::Error::new(
+                            io::ErrorKind::WouldBlock,
+                            "writer queue is full",
+                        ));
+                    }
+                }
+            }
+            state.frames_queued += 1;
+            state.stats.peak_queued = state.stats.peak_queued.max(state.frames_queued);
+        }
+        state.jobs.push_back(job);
+        self.shared.job_ready.notify_one();
+        Ok(())
+    }
+    /// Stop accepting frames and wait for the thread to write everything that's queued
+    fn close(&mut self) -> io::Result<()> {
+        self.shared.lock().closed = true;
+        self.shared.job_ready.notify_all();
+        if let Some(handle) = self.thread_handle.take() {
+            handle.join().expect("writer thread panicked");
+        }
+        match &self.shared.lock().error {
+            Some(e) => Err(io::Error::new(e.kind(), e.to_string())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl WriterMonitor {
+    /// Current statistics, once the writer is finished this is the final report
+    pub fn stats(&self) -> WriterStats {
+        self.shared.stats()
+    }
+}
+
+impl FrameWriter for AsyncWriter {
+    fn begin(&mut self, info: &AcquisitionInfo) -> io::Result<()> {
+        self.queue(Job::Begin(info.clone()))
+    }
+    /// Queue a copy of `frame` to be written
+    fn write_frame(&mut self, frame: &DcamFrame) -> io::Result<()> {
+        self.queue(Job::Frame(frame.clone()))
+    }
+    /// Write everything still in the queue and finish the wrapped writer. If it failed it isn't
+    /// finished and the error it failed with is returned
+    fn finish(&mut self) -> io::Result<()> {
+        self.close()
+    }
+}
+
+impl Drop for AsyncWriter {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+/// Body of the writer thread, run jobs until the queue is closed and empty
+fn write_jobs(mut sink: Sink, shared: &Shared) {
+    loop {
+        let job = {
+            let mut state = shared.lock();
+            while state.jobs.is_empty() && !state.closed {
+                state = shared.job_ready.wait(state).expect("writer panicked");
+            }
+            match state.jobs.pop_front() {
+                Some(job) => {
+                    if job.is_frame() {
+                        state.frames_queued -= 1;
+                    }
+                    shared.space_ready.notify_one();
+                    job
+                }
+                //closed and empty
+                None => break,
+            }
+        };
+        let result = sink.run(&job);
+        let mut state = shared.lock();
+        match result {
+            Ok(()) => {
+                let bytes = match &job {
+                    Job::Frame(frame) => frame.image().as_raw().len() * 2,
+                    Job::Raw(raw, ..) => raw.len(),
+                    _ => continue,
+                };
+                state.stats.frames_written += 1;
+                state.stats.bytes_written += bytes as u64;
+            }
+            Err(e) => {
+                state.error = Some(e);
+                break;
+            }
+        }
+    }
+    //a writer which failed isn't finished, the same as one a stream drops after an error
+    let mut state = shared.lock();
+    if state.error.is_none() {
+        drop(state);
+        let result = sink.writer().finish();
+        state = shared.lock();
+        if let Err(e) = result {
+            state.error = Some(e);
+        }
+    }
+    //anything left in the queue after an error is never going to be written
+    state.stats.frames_dropped += state.frames_queued as u64;
+    state.frames_queued = 0;
+    state.jobs.clear();
+    state.stats.elapsed = state.started.map(|s| s.elapsed()).unwrap_or_default();
+    state.stats.finished = true;
+    shared.space_ready.notify_all();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pixel::PixelType;
+    use crate::spool::{SpoolLayout, SpoolReader};
+    use crate::test_util::{frame, info, temp_path};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::{self, Receiver, Sender};
+
+    /// framestamps of the frames a `Recorder` wrote
+    type Written = Arc<Mutex<Vec<i32>>>;
+
+    /// Keeps the framestamps it's given, optionally waiting for a go-ahead before each frame and
+    /// failing at one of them
+    struct Recorder {
+        written: Written,
+        finished: Arc<AtomicBool>,
+        gate: Option<Receiver<()>>,
+        fail_at: Option<i32>,
+    }
+
+    impl FrameWriter for Recorder {
+        fn begin(&mut self, _info: &AcquisitionInfo) -> io::Result<()> {
+            Ok(())
+        }
+        fn write_frame(&mut self, frame: &DcamFrame) -> io::Result<()> {
+            if let Some(gate) = &self.gate {
+                //a hung up sender lets everything through
+                let _ = gate.recv();
+            }
+            if self.fail_at == Some(frame.framestamp()) {
+                return Err(io::Error::other("disk full"));
+            }
+            self.written.lock().unwrap().push(frame.framestamp());
+            Ok(())
+        }
+        fn finish(&mut self) -> io::Result<()> {
+            self.finished.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    /// Start an `AsyncWriter` around a `Recorder`, with a sender for the gate if `gated`
+    fn start(
+        capacity: usize,
+        policy: OverflowPolicy,
+        gated: bool,
+        fail_at: Option<i32>,
+    ) -> (AsyncWriter, Written, Arc<AtomicBool>, Option<Sender<()>>) {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let finished = Arc::new(AtomicBool::new(false));
+        let (gate_tx, gate) = match gated {
+            true => {
+                let (tx, rx) = mpsc::channel();
+                (Some(tx), Some(rx))
+            }
+            false => (None, None),
+        };
+        let recorder = Recorder {
+            written: written.clone(),
+            finished: finished.clone(),
+            gate,
+            fail_at,
+        };
+        let writer = AsyncWriter::new(Box::new(recorder), capacity, policy);
+        (writer, written, finished, gate_tx)
+    }
+
+    /// Wait for the writer thread to get somewhere
+    fn wait_until(done: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "writer thread is stuck");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn writes_everything_in_order() {
+        let (mut writer, written, finished, _) = start(2, OverflowPolicy::Block, false, None);
+        let monitor = writer.monitor();
+        writer.begin(&info()).unwrap();
+        for i in 0..5 {
+            writer.write_frame(&frame(2, 2, i)).unwrap();
+        }
+        writer.finish().unwrap();
+        assert_eq!(*written.lock().unwrap(), [0, 1, 2, 3, 4]);
+        assert!(finished.load(Ordering::SeqCst));
+        let stats = monitor.stats();
+        assert!(stats.finished);
+        assert_eq!(stats.frames_written, 5);
+        assert_eq!(stats.frames_dropped, 0);
+        assert_eq!(stats.bytes_written, 5 * 4 * 2);
+        assert!(stats.peak_queued <= 2);
+        //nothing more once it's finished
+        assert!(writer.write_frame(&frame(2, 2, 5)).is_err());
+    }
+
+    #[test]
+    fn drop_newest_while_the_disk_is_slow() {
+        let (mut writer, written, _, gate) = start(1, OverflowPolicy::DropNewest, true, None);
+        writer.write_frame(&frame(2, 2, 0)).unwrap();
+        //the thread is stuck writing frame 0
+        wait_until(|| writer.stats().queued == 0);
+        writer.write_frame(&frame(2, 2, 1)).unwrap();
+        writer.write_frame(&frame(2, 2, 2)).unwrap();
+        drop(gate);
+        writer.finish().unwrap();
+        assert_eq!(*written.lock().unwrap(), [0, 1]);
+        assert_eq!(writer.stats().frames_dropped, 1);
+    }
+
+    #[test]
+    fn drop_oldest_while_the_disk_is_slow() {
+        let (mut writer, written, _, gate) = start(1, OverflowPolicy::DropOldest, true, None);
+        writer.write_frame(&frame(2, 2, 0)).unwrap();
+        wait_until(|| writer.stats().queued == 0);
+        writer.write_frame(&frame(2, 2, 1)).unwrap();
+        writer.write_frame(&frame(2, 2, 2)).unwrap();
+        drop(gate);
+        writer.finish().unwrap();
+        assert_eq!(*written.lock().unwrap(), [0, 2]);
+        assert_eq!(writer.stats().frames_dropped, 1);
+    }
+
+    #[test]
+    fn full_queue_error_keeps_writing() {
+        let (mut writer, written, _, gate) = start(1, OverflowPolicy::Error, true, None);
+        writer.write_frame(&frame(2, 2, 0)).unwrap();
+        wait_until(|| writer.stats().queued == 0);
+        writer.write_frame(&frame(2, 2, 1)).unwrap();
+        let full = writer.write_frame(&frame(2, 2, 2)).unwrap_err();
+        assert_eq!(full.kind(), io::ErrorKind::WouldBlock);
+        drop(gate);
+        writer.finish().unwrap();
+        assert_eq!(*written.lock().unwrap(), [0, 1]);
+        assert_eq!(writer.stats().frames_dropped, 1);
+    }
+
+    #[test]
+    fn block_waits_for_the_disk() {
+        let (mut writer, written, _, gate) = start(1, OverflowPolicy::Block, true, None);
+        let gate = gate.unwrap();
+        writer.write_frame(&frame(2, 2, 0)).unwrap();
+        wait_until(|| writer.stats().queued == 0);
+        writer.write_frame(&frame(2, 2, 1)).unwrap();
+        let capture = thread::spawn(move || {
+            writer.write_frame(&frame(2, 2, 2)).unwrap();
+            writer
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(!capture.is_finished(), "the queue is full");
+        gate.send(()).unwrap();
+        let mut writer = capture.join().unwrap();
+        drop(gate);
+        writer.finish().unwrap();
+        assert_eq!(*written.lock().unwrap(), [0, 1, 2]);
+        assert_eq!(writer.stats().frames_dropped, 0);
+    }
+
+    #[test]
+    fn writer_errors_stop_the_writer() {
+        let (mut writer, written, finished, _) = start(4, OverflowPolicy::Block, false, Some(1));
+        writer.write_frame(&frame(2, 2, 0)).unwrap();
+        writer.write_frame(&frame(2, 2, 1)).unwrap();
+        wait_until(|| writer.stats().finished);
+        //the error comes back from every call after it happened
+        let error = writer.write_frame(&frame(2, 2, 2)).unwrap_err();
+        assert_eq!(error.to_string(), "disk full");
+        assert!(writer.finish().is_err());
+        assert_eq!(*written.lock().unwrap(), [0]);
+        assert!(
+            !finished.load(Ordering::SeqCst),
+            "a failed writer isn't finished"
+        );
+    }
+
+    #[test]
+    fn raw_frames_go_to_the_spool() {
+        let dir = temp_path("async_spool");
+        let layout = SpoolLayout {
+            width: 2,
+            height: 1,
+            rowbytes: 4,
+            frame_bytes: 4,
+            pixel_type: PixelType::Mono16,
+        };
+        let mut spool = SpoolWriter::create(&dir, 2).unwrap();
+        spool.begin_raw(&info(), layout).unwrap();
+        let mut writer = AsyncWriter::spool(spool, 2, OverflowPolicy::Block);
+        for n in 0..3u8 {
+            let timestamp = Duration::from_millis(n as u64);
+            writer
+                .write_raw(&[n, 0, n, 1], timestamp, n as i32)
+                .unwrap();
+        }
+        writer.finish().unwrap();
+        assert_eq!(writer.stats().frames_written, 3);
+        assert_eq!(writer.stats().bytes_written, 12);
+        let reader = SpoolReader::open(&dir).unwrap();
+        assert_eq!(reader.len(), 3);
+        assert_eq!(reader[2], [2, 0, 2, 1]);
+        assert_eq!(reader.framestamp(2), 2);
+        drop(reader);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_spools_take_raw_frames() {
+        let (mut writer, _, _, _) = start(1, OverflowPolicy::Block, false, None);
+        let error = writer.write_raw(&[0; 4], Duration::ZERO, 0).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        writer.finish().unwrap();
+    }
+}
@@ -8,6 +8,7 @@ use libc;
 use std::mem;
 use std::os::raw;
 use std::ptr;
+use std::time::Duration;
 
 impl DCAM_GUID {
     /// Make a new `DCAM_GUID` struct initialized with zeros
@@ -84,7 +85,8 @@ impl DCAMWAIT_OPEN {
     pub fn new(camera_handle: HDCAM) -> DCAMWAIT_OPEN {
         DCAMWAIT_OPEN {
             size: mem::size_of::<Self>() as int32,
-            supportevent: DCAMWAIT_EVENT_DCAMWAIT_CAPEVENT_FRAMEREADY,
+            //filled in by dcamwait_open with the events the camera can report
+            supportevent: 0,
             hwait: ptr::null_mut::<DCAMWAIT>(),
             hdcam: camera_handle,
         }
@@ -100,4 +102,260 @@ impl DCAMWAIT_START {
             timeout: DCAMWAIT_TIMEOUT_DCAMWAIT_TIMEOUT_INFINITE,
         }
     }
+    /// Build a `DCAMWAIT_START` which waits for any of the events in `eventmask`, giving up after `timeout` ms
+    pub fn with_event(eventmask: int32, timeout: int32) -> DCAMWAIT_START {
+        DCAMWAIT_START {
+            size: mem::size_of::<Self>() as int32,
+            eventhappened: 0,
+            eventmask,
+            timeout,
+        }
+    }
+}
+
+impl DCAMPROP_ATTR {
+    /// Build a `DCAMPROP_ATTR` to query the attributes of the property `iprop`
+    pub fn new(iprop: int32) -> DCAMPROP_ATTR {
+        DCAMPROP_ATTR {
+            cbSize: mem::size_of::<Self>() as int32,
+            iProp: iprop,
+            option: 0,
+            iReserved1: 0,
+            attribute: 0,
+            iGroup: 0,
+            iUnit: 0,
+            attribute2: 0,
+            valuemin: 0.0,
+            valuemax: 0.0,
+            valuestep: 0.0,
+            valuedefault: 0.0,
+            nMaxChannel: 0,
+            iReserved3: 0,
+            nMaxView: 0,
+            iProp_NumberOfElement: 0,
+            iProp_ArrayBase: 0,
+            iPropStep_Element: 0,
+        }
+    }
+}
+
+impl DCAM_TIMESTAMP {
+    /// Convert the timestamp into a `Duration` since the timestamp epoch
+    pub fn as_duration(&self) -> Duration {
+        Duration::new(self.sec as u64, (self.microsec as u32) * 1000)
+    }
+}
+
+impl DCAMBUF_FRAME {
+    /// Build a `DCAMBUF_FRAME` to lock the frame at `iframe`
+    pub fn new(iframe: int32) -> DCAMBUF_FRAME {
+        DCAMBUF_FRAME {
+            size: mem::size_of::<Self>() as int32,
+            iKind: 0,
+            option: 0,
+            iFrame: iframe,
+            buf: ptr::null_mut(),
+            rowbytes: 0,
+            type_: 0,
+            width: 0,
+            height: 0,
+            left: 0,
+            top: 0,
+            timestamp: DCAM_TIMESTAMP {
+                sec: 0,
+                microsec: 0,
+            },
+            framestamp: 0,
+            camerastamp: 0,
+        }
+    }
+}
+
+impl DCAMREC_OPENA {
+    /// Build a `DCAMREC_OPENA` to create the file `path`.`ext` holding up to `max_frames` frames
+    pub fn new(
+        path: *const raw::c_char,
+        ext: *const raw::c_char,
+        max_frames: int32,
+    ) -> DCAMREC_OPENA {
+        DCAMREC_OPENA {
+            size: mem::size_of::<Self>() as int32,
+            reserved: 0,
+            hrec: ptr::null_mut::<DCAMREC>(),
+            path,
+            ext,
+            maxframepersession: max_frames,
+            userdatasize: 0,
+            userdatasize_session: 0,
+            userdatasize_file: 0,
+            usertextsize: 0,
+            usertextsize_session: 0,
+            usertextsize_file: 0,
+        }
+    }
+}
+
+impl DCAMREC_STATUS {
+    pub fn new() -> DCAMREC_STATUS {
+        DCAMREC_STATUS {
+            size: mem::size_of::<Self>() as int32,
+            currentsession_index: 0,
+            maxframecount_per_session: 0,
+            currentframe_index: 0,
+            missingframe_count: 0,
+            flags: 0,
+            totalframecount: 0,
+            reserved: 0,
+        }
+    }
+}
+
+impl DCAM_METADATAHDR {
+    /// Build the header for a metadata struct of type `T`
+    pub fn new<T>(kind: int32, option: int32, iframe: int32) -> DCAM_METADATAHDR {
+        DCAM_METADATAHDR {
+            size: mem::size_of::<T>() as int32,
+            iKind: kind,
+            option,
+            iFrame: iframe,
+        }
+    }
+}
+
+impl DCAM_METADATABLOCKHDR {
+    /// Build the header for a metadata block struct of type `T` covering `count` frames from `iframe`
+    pub fn new<T>(
+        kind: int32,
+        option: int32,
+        iframe: int32,
+        count: int32,
+    ) -> DCAM_METADATABLOCKHDR {
+        DCAM_METADATABLOCKHDR {
+            size: mem::size_of::<T>() as int32,
+            iKind: kind,
+            option,
+            iFrame: iframe,
+            in_count: count,
+            outcount: 0,
+        }
+    }
+}
+
+impl DCAM_USERDATATEXT {
+    /// Build a `DCAM_USERDATATEXT` pointing at `text_len` bytes of UTF-8 text
+    pub fn new(
+        option: int32,
+        iframe: int32,
+        text: *mut raw::c_char,
+        text_len: int32,
+    ) -> DCAM_USERDATATEXT {
+        DCAM_USERDATATEXT {
+            hdr: DCAM_METADATAHDR::new::<Self>(
+                DCAMREC_METADATAKIND_DCAMREC_METADATAKIND_USERDATATEXT,
+                option,
+                iframe,
+            ),
+            text,
+            text_len,
+            codepage: DCAM_CODEPAGE_DCAM_CODEPAGE__UTF8,
+        }
+    }
+}
+
+impl DCAM_USERDATABIN {
+    /// Build a `DCAM_USERDATABIN` pointing at `bin_len` bytes of binary data
+    pub fn new(
+        option: int32,
+        iframe: int32,
+        bin: *mut libc::c_void,
+        bin_len: int32,
+    ) -> DCAM_USERDATABIN {
+        DCAM_USERDATABIN {
+            hdr: DCAM_METADATAHDR::new::<Self>(
+                DCAMREC_METADATAKIND_DCAMREC_METADATAKIND_USERDATABIN,
+                option,
+                iframe,
+            ),
+            bin,
+            bin_len,
+            reserved: 0,
+        }
+    }
+}
+
+impl DCAM_METADATATEXTBLOCK {
+    /// Build a `DCAM_METADATATEXTBLOCK` to copy the text metadata of `count` frames starting at `iframe`.
+    /// `text` must hold `count * bytesperunit` bytes, `textsizes` and `codepages` must hold `count` values
+    pub fn new(
+        iframe: int32,
+        count: int32,
+        text: *mut libc::c_void,
+        textsizes: *mut int32,
+        bytesperunit: int32,
+        codepages: *mut int32,
+    ) -> DCAM_METADATATEXTBLOCK {
+        DCAM_METADATATEXTBLOCK {
+            hdr: DCAM_METADATABLOCKHDR::new::<Self>(
+                DCAMREC_METADATAKIND_DCAMREC_METADATAKIND_USERDATATEXT,
+                DCAMREC_METADATAOPTION_DCAMREC_METADATAOPTION__LOCATION_FRAME,
+                iframe,
+                count,
+            ),
+            text,
+            textsizes,
+            bytesperunit,
+            reserved: 0,
+            textcodepage: codepages,
+        }
+    }
+}
+
+impl DCAM_METADATABINBLOCK {
+    /// Build a `DCAM_METADATABINBLOCK` to copy the binary metadata of `count` frames starting at `iframe`.
+    /// `bin` must hold `count * bytesperunit` bytes and `binsizes` must hold `count` values
+    pub fn new(
+        iframe: int32,
+        count: int32,
+        bin: *mut libc::c_void,
+        binsizes: *mut int32,
+        bytesperunit: int32,
+    ) -> DCAM_METADATABINBLOCK {
+        DCAM_METADATABINBLOCK {
+            hdr: DCAM_METADATABLOCKHDR::new::<Self>(
+                DCAMREC_METADATAKIND_DCAMREC_METADATAKIND_USERDATABIN,
+                DCAMREC_METADATAOPTION_DCAMREC_METADATAOPTION__LOCATION_FRAME,
+                iframe,
+                count,
+            ),
+            bin,
+            binsizes,
+            bytesperunit,
+            reserved: 0,
+        }
+    }
+}
+
+impl DCAMREC_FRAME {
+    /// Build a `DCAMREC_FRAME` to lock the recorded frame at `iframe`
+    pub fn new(iframe: int32) -> DCAMREC_FRAME {
+        DCAMREC_FRAME {
+            size: mem::size_of::<Self>() as int32,
+            iKind: 0,
+            option: 0,
+            iFrame: iframe,
+            buf: ptr::null_mut(),
+            rowbytes: 0,
+            type_: 0,
+            width: 0,
+            height: 0,
+            left: 0,
+            top: 0,
+            timestamp: DCAM_TIMESTAMP {
+                sec: 0,
+                microsec: 0,
+            },
+            framestamp: 0,
+            camerastamp: 0,
+        }
+    }
 }
@@ -0,0 +1,153 @@
+//! Loop over frames on the current thread, without channels or a capture thread.
+//!
+//! `Camera::capture` attaches a buffer and starts a sequence capture, the returned `Capture` yields
+//! every frame in order as an `Iterator`. Frames wait in the DCAM buffer until `next` is called, as long
+//! as we stay less than a buffer's worth of frames behind none are missed. Frames which were
+//! overwritten before we got to them are counted in `FrameMetadata::lost_frames`
+
+use crate::error::DcamError;
+use crate::frame::{DcamFrame, FrameMetadata};
+use crate::pixel::PixelType;
+use crate::writer::AcquisitionInfo;
+use crate::{bindings, Camera, FrameBuffer};
+use std::marker::PhantomData;
+use std::time::Duration;
+
+/// A running sequence capture, stopped when dropped
+pub struct Capture<'a> {
+    framebuffer: FrameBuffer,
+    hwait: bindings::HDCAMWAIT,
+    timeout: Duration,
+    info: AcquisitionInfo,
+    pixel_type: PixelType,
+    width: usize,
+    height: usize,
+    rowbytes: usize,
+    /// number of frames the camera had captured when we last checked
+    captured: i32,
+    /// number of frames we have handed out or skipped
+    delivered: i32,
+    lost_frames: u64,
+    first_timestamp: Option<Duration>,
+    /// the camera can't be closed while we're capturing from it
+    _camera: PhantomData<&'a ()>,
+}
+
+impl<'a> Capture<'a> {
+    /// Attach a `num_frames` long buffer to `camera` and start capturing
+    pub(crate) fn start<T: Camera>(
+        camera: &'a T,
+        num_frames: usize,
+        timeout: Duration,
+    ) -> Result<Capture<'a>, DcamError> {
+        let pixel_type = camera.get_pixel_type()?;
+        let rowbytes = camera.get_buffer_rowbytes()?;
+        let [width, height] = camera.get_resolution()?;
+        let info = AcquisitionInfo::from_camera(camera)?;
+        let framebuffer = camera.attach_buffer(num_frames)?;
+        let hwait = framebuffer.get_wait_handle()?;
+        let capture = Capture {
+            framebuffer,
+            hwait,
+            timeout,
+            info,
+            pixel_type,
+            width: width as usize,
+            height: height as usize,
+            rowbytes,
+            captured: 0,
+            delivered: 0,
+            lost_frames: 0,
+            first_timestamp: None,
+            _camera: PhantomData,
+        };
+        //if this fails dropping `capture` cleans up the wait handle and buffer
+        camera.dcamcap_start(bindings::DCAMCAP_START_DCAMCAP_START_SEQUENCE)?;
+        Ok(capture)
+    }
+    /// The camera's settings at the start of the capture
+    pub fn info(&self) -> &AcquisitionInfo {
+        &self.info
+    }
+    /// Change how long `next` waits for a frame before returning a timeout error
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+    /// Wait until the camera has captured a frame we haven't delivered yet. The captured count is
+    /// refreshed every time, even with frames already waiting, so the overwrite check stays current
+    fn wait_for_frame(&mut self) -> Result<(), DcamError> {
+        let (_, captured) = self.framebuffer.dcamcap_transferinfo()?;
+        self.captured = captured;
+        if self.captured > self.delivered {
+            return Ok(());
+        }
+        let mut dws = bindings::DCAMWAIT_START::with_event(
+            bindings::DCAMWAIT_EVENT_DCAMWAIT_CAPEVENT_FRAMEREADY,
+            self.timeout.as_millis().min(i32::MAX as u128) as i32,
+        );
+        match unsafe { bindings::dcamwait_start(self.hwait, &mut dws) } {
+            1 => {}
+            e => return Err(DcamError(e)),
+        }
+        let (_, captured) = self.framebuffer.dcamcap_transferinfo()?;
+        self.captured = captured;
+        Ok(())
+    }
+    /// Copy out the oldest frame we haven't delivered yet
+    fn next_frame(&mut self) -> Result<DcamFrame, DcamError> {
+        self.wait_for_frame()?;
+        //skip anything that has already been overwritten. With a full buffer's worth of frames
+        //waiting the oldest one's slot is the next to be written, so that counts as lost too
+        let num_frames = self.framebuffer.num_frames as i32;
+        if self.captured - self.delivered >= num_frames {
+            let skipped = self.captured - self.delivered - num_frames + 1;
+            log_event!(WARN, skipped, "frames overwritten before we read them");
+            self.lost_frames += skipped as u64;
+            self.delivered += skipped;
+        }
+        let index = (self.delivered % num_frames) as usize;
+        let frame = self.framebuffer.copy_frame(
+            index,
+            self.pixel_type,
+            self.width,
+            self.height,
+            self.rowbytes,
+        )?;
+        self.delivered += 1;
+        log_event!(
+            TRACE,
+            index,
+            framestamp = frame.framestamp(),
+            behind = self.captured - self.delivered,
+            "frame delivered"
+        );
+        let first = *self.first_timestamp.get_or_insert(frame.timestamp());
+        let metadata = FrameMetadata::new(
+            &self.info,
+            frame.timestamp(),
+            frame.framestamp(),
+            frame.timestamp().saturating_sub(first),
+            self.lost_frames,
+        );
+        Ok(frame.with_metadata(metadata))
+    }
+}
+
+impl Iterator for Capture<'_> {
+    type Item = Result<DcamFrame, DcamError>;
+    /// Wait for the next frame, this never returns `None`. If no frame arrives within the
+    /// timeout this returns a timeout error, calling `next` again keeps waiting
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.next_frame())
+    }
+}
+
+impl Drop for Capture<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            bindings::dcamcap_stop(self.framebuffer.camera_handle);
+            bindings::dcamwait_close(self.hwait);
+        }
+        log_event!(INFO, "capture stopped");
+    }
+}
@@ -0,0 +1,591 @@
+//! Lossless compression of frames on a pool of worker threads.
+//!
+//! `CompressionStage` is a `FrameWriter` which compresses frames in parallel and hands them, in order,
+//! to a `CompressedWriter`. `CompressedFileWriter` stores compressed frames in a simple container
+//! file which can be read back with `CompressedFileReader`. LZ4 is the fastest, zstd compresses mostly
+//! dark frames noticeably better but needs more threads to keep up. `benches/compression.rs` measures
+//! both on camera-like frames to help pick a setting and thread count
+
+use crate::frame::DcamFrame;
+use crate::writer::{AcquisitionInfo, FrameWriter};
+use image::{ImageBuffer, Luma};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Magic bytes at the start of a compressed frame file
+const MAGIC: &[u8; 4] = b"FWZ1";
+
+/// Lossless compression algorithms, used by `CompressionStage` and `ZarrWriter`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// store frames as they are
+    None,
+    Lz4,
+    /// zstd at the given level, higher levels compress better but are slower
+    Zstd(i32),
+}
+
+impl Compression {
+    /// id stored in compressed frame files
+    fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd(_) => 2,
+        }
+    }
+    /// Compress `data`
+    pub fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Lz4 => Ok(lz4_flex::compress(data)),
+            Compression::Zstd(level) => zstd::bulk::compress(data, level),
+        }
+    }
+}
+
+/// Decompress `data` compressed with the algorithm with `id`, which decompresses to `raw_len` bytes
+fn decompress(id: u8, data: &[u8], raw_len: usize) -> io::Result<Vec<u8>> {
+    match id {
+        0 => Ok(data.to_vec()),
+        1 => lz4_flex::decompress(data, raw_len)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        2 => zstd::bulk::decompress(data, raw_len),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unknown compression algorithm",
+        )),
+    }
+}
+
+/// A frame's pixel data after compression
+#[derive(Debug, Clone)]
+pub struct CompressedFrame {
+    pub width: u32,
+    pub height: u32,
+    pub timestamp: Duration,
+    pub framestamp: i32,
+    pub compression: Compression,
+    /// size of the little endian pixel data before compression
+    pub raw_len: usize,
+    pub data: Vec<u8>,
+}
+
+impl CompressedFrame {
+    /// Compress the pixels of `frame`
+    pub fn compress(frame: &DcamFrame, compression: Compression) -> io::Result<CompressedFrame> {
+        let image = frame.image();
+        let pixels = image.as_raw();
+        //pixels are stored little endian, so is every machine DCAM runs on
+        let raw =
+            unsafe { std::slice::from_raw_parts(pixels.as_ptr() as *const u8, pixels.len() * 2) };
+        Ok(CompressedFrame {
+            width: image.width(),
+            height: image.height(),
+            timestamp: frame.timestamp(),
+            framestamp: frame.framestamp(),
+            compression,
+            raw_len: raw.len(),
+            data: compression.compress(raw)?,
+        })
+    }
+    /// Get the original frame back
+    pub fn decompress(&self) -> io::Result<DcamFrame> {
+        let raw = decompress(self.compression.id(), &self.data, self.raw_len)?;
+        to_frame(
+            &raw,
+            self.width,
+            self.height,
+            self.timestamp,
+            self.framestamp,
+        )
+    }
+    /// Compressed size as a fraction of the original size
+    pub fn ratio(&self) -> f64 {
+        self.data.len() as f64 / self.raw_len.max(1) as f64
+    }
+}
+
+/// build a frame out of little endian pixel data
+fn to_frame(
+    raw: &[u8],
+    width: u32,
+    height: u32,
+    timestamp: Duration,
+    framestamp: i32,
+) -> io::Result<DcamFrame> {
+    let pixels: Vec<u16> = raw
+        .chunks_exact(2)
+        .map(|p| u16::from_le_bytes([p[0], p[1]]))
+        .collect();
+    let image =
+        ImageBuffer::<Luma<u16>, Vec<u16>>::from_raw(width, height, pixels).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "frame data doesn't match its size",
+            )
+        })?;
+    Ok(DcamFrame::new(image, timestamp, framestamp))
+}
+
+/// Something which can save compressed frames
+pub trait CompressedWriter: Send {
+    /// Prepare to write frames captured with the settings in `info`
+    fn begin(&mut self, info: &AcquisitionInfo) -> io::Result<()>;
+    /// Append `frame` to the output
+    fn write_compressed(&mut self, frame: &CompressedFrame) -> io::Result<()>;
+    /// Flush everything to disk, no more frames can be written after this
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+/// Running totals for a `CompressionStage`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CompressionStats {
+    pub frames: u64,
+    /// bytes of pixel data before compression
+    pub raw_bytes: u64,
+    /// bytes of pixel data after compression
+    pub compressed_bytes: u64,
+}
+
+impl CompressionStats {
+    /// Compressed size as a fraction of the original size
+    pub fn ratio(&self) -> f64 {
+        self.compressed_bytes as f64 / self.raw_bytes.max(1) as f64
+    }
+}
+
+/// Work for the writer thread, sent with a sequence number so it's done in the order it was queued
+enum Job {
+    Begin(AcquisitionInfo),
+    Frame(io::Result<CompressedFrame>),
+}
+
+/// Compresses frames on several threads and passes them on, in order, to a `CompressedWriter`
+pub struct CompressionStage {
+    /// next sequence number to hand out, `begin` takes one too so it can't overtake earlier frames
+    next_seq: u64,
+    frame_tx: Option<SyncSender<(u64, DcamFrame)>>,
+    job_tx: Option<Sender<(u64, Job)>>,
+    workers: Vec<JoinHandle<()>>,
+    writer_thread: Option<JoinHandle<io::Result<()>>>,
+    error: Arc<Mutex<Option<io::Error>>>,
+    frames: Arc<AtomicU64>,
+    raw_bytes: Arc<AtomicU64>,
+    compressed_bytes: Arc<AtomicU64>,
+}
+
+impl CompressionStage {
+    /// Compress frames with `compression` on `threads` threads and write them with `writer`.
+    /// Up to two frames per thread can wait to be compressed, after that `write_frame` blocks
+    pub fn new(
+        writer: Box<dyn CompressedWriter>,
+        compression: Compression,
+        threads: usize,
+    ) -> CompressionStage {
+        assert!(threads > 0, "need at least one compression thread");
+        let (frame_tx, frame_rx) = sync_channel::<(u64, DcamFrame)>(threads * 2);
+        let frame_rx = Arc::new(Mutex::new(frame_rx));
+        let (job_tx, job_rx) = channel::<(u64, Job)>();
+        let workers = (0..threads)
+            .map(|_| {
+                let frame_rx = frame_rx.clone();
+                let job_tx = job_tx.clone();
+                thread::spawn(move || loop {
+                    //only hold the lock while waiting for a frame, not while compressing it
+                    let next = frame_rx.lock().expect("compression thread panicked").recv();
+                    match next {
+                        Ok((seq, frame)) => {
+                            let compressed = CompressedFrame::compress(&frame, compression);
+                            if job_tx.send((seq, Job::Frame(compressed))).is_err() {
+                                break;
+                            }
+                        }
+                        //the stage is finished
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+        let error = Arc::new(Mutex::new(None));
+        let frames = Arc::new(AtomicU64::new(0));
+        let raw_bytes = Arc::new(AtomicU64::new(0));
+        let compressed_bytes = Arc::new(AtomicU64::new(0));
+        let writer_thread = {
+            let error = error.clone();
+            let frames = frames.clone();
+            let raw_bytes = raw_bytes.clone();
+            let compressed_bytes = compressed_bytes.clone();
+            thread::spawn(move || {
+                let result = write_in_order(writer, job_rx, &frames, &raw_bytes, &compressed_bytes);
+                if let Err(e) = &result {
+                    error
+                        .lock()
+                        .expect("compression thread panicked")
+                        .get_or_insert(io::Error::new(e.kind(), e.to_string()));
+                }
+                result
+            })
+        };
+        CompressionStage {
+            next_seq: 0,
+            frame_tx: Some(frame_tx),
+            job_tx: Some(job_tx),
+            workers,
+            writer_thread: Some(writer_thread),
+            error,
+            frames,
+            raw_bytes,
+            compressed_bytes,
+        }
+    }
+    /// Totals for the frames written so far
+    pub fn stats(&self) -> CompressionStats {
+        CompressionStats {
+            frames: self.frames.load(Ordering::Acquire),
+            raw_bytes: self.raw_bytes.load(Ordering::Acquire),
+            compressed_bytes: self.compressed_bytes.load(Ordering::Acquire),
+        }
+    }
+    /// return the error the writer thread ran into, if any
+    fn check_error(&self) -> io::Result<()> {
+        match &*self.error.lock().expect("compression thread panicked") {
+            Some(e) => Err(io::Error::new(e.kind(), e.to_string())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Body of the writer thread, puts jobs back in order and runs them
+fn write_in_order(
+    mut writer: Box<dyn CompressedWriter>,
+    job_rx: Receiver<(u64, Job)>,
+    frames: &AtomicU64,
+    raw_bytes: &AtomicU64,
+    compressed_bytes: &AtomicU64,
+) -> io::Result<()> {
+    let mut next_seq = 0;
+    let mut waiting = BTreeMap::new();
+    for (seq, job) in job_rx {
+        waiting.insert(seq, job);
+        while let Some(job) = waiting.remove(&next_seq) {
+            match job {
+                Job::Begin(info) => writer.begin(&info)?,
+                Job::Frame(compressed) => {
+                    let frame = compressed?;
+                    writer.write_compressed(&frame)?;
+                    frames.fetch_add(1, Ordering::AcqRel);
+                    raw_bytes.fetch_add(frame.raw_len as u64, Ordering::AcqRel);
+                    compressed_bytes.fetch_add(frame.data.len() as u64, Ordering::AcqRel);
+                }
+            }
+            next_seq += 1;
+        }
+    }
+    writer.finish()
+}
+
+impl FrameWriter for CompressionStage {
+    fn begin(&mut self, info: &AcquisitionInfo) -> io::Result<()> {
+        self.check_error()?;
+        match &self.job_tx {
+            Some(job_tx) => {
+                job_tx
+                    .send((self.next_seq, Job::Begin(info.clone())))
+                    .map_err(|_| io::Error::other("compression stage stopped"))?;
+                self.next_seq += 1;
+                Ok(())
+            }
+            None => Err(io::Error::other(
+                "can't write to a finished compression stage",
+            )),
+        }
+    }
+    /// Queue a copy of `frame` to be compressed, blocks if the compression threads are behind
+    fn write_frame(&mut self, frame: &DcamFrame) -> io::Result<()> {
+        self.check_error()?;
+        match &self.frame_tx {
+            Some(frame_tx) => {
+                frame_tx
+                    .send((self.next_seq, frame.clone()))
+                    .map_err(|_| io::Error::other("compression stage stopped"))?;
+                self.next_seq += 1;
+                Ok(())
+            }
+            None => Err(io::Error::other(
+                "can't write to a finished compression stage",
+            )),
+        }
+    }
+    /// Wait for every queued frame to be compressed and written, then finish the wrapped writer
+    fn finish(&mut self) -> io::Result<()> {
+        //hanging up lets the threads drain their queues and exit
+        self.frame_tx = None;
+        for worker in self.workers.drain(..) {
+            worker.join().expect("compression thread panicked");
+        }
+        self.job_tx = None;
+        match self.writer_thread.take() {
+            Some(handle) => handle.join().expect("compression thread panicked"),
+            None => self.check_error(),
+        }
+    }
+}
+
+impl Drop for CompressionStage {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+/// Writes compressed frames one after another into a single file
+pub struct CompressedFileWriter {
+    file: BufWriter<File>,
+    /// the camera settings have been written after the magic bytes
+    header_written: bool,
+}
+
+impl CompressedFileWriter {
+    /// Create (or overwrite) the file at `path`
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<CompressedFileWriter> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(MAGIC)?;
+        Ok(CompressedFileWriter {
+            file,
+            header_written: false,
+        })
+    }
+    /// write the camera settings as a length prefixed JSON blob
+    fn write_header(&mut self, settings: &serde_json::Value) -> io::Result<()> {
+        let json = serde_json::to_vec(settings)?;
+        self.file.write_all(&(json.len() as u32).to_le_bytes())?;
+        self.file.write_all(&json)?;
+        self.header_written = true;
+        Ok(())
+    }
+}
+
+impl CompressedWriter for CompressedFileWriter {
+    /// The camera settings are stored as JSON at the start of the file. Only the first call writes
+    /// anything, the settings can't be changed once they are in the file
+    fn begin(&mut self, info: &AcquisitionInfo) -> io::Result<()> {
+        if self.header_written {
+            return Ok(());
+        }
+        self.write_header(&info.to_json())
+    }
+    /// `begin` has to be called before the first frame
+    fn write_compressed(&mut self, frame: &CompressedFrame) -> io::Result<()> {
+        if !self.header_written {
+            return Err(io::Error::other(
+                "begin must be called before writing frames",
+            ));
+        }
+        self.file.write_all(&frame.width.to_le_bytes())?;
+        self.file.write_all(&frame.height.to_le_bytes())?;
+        self.file
+            .write_all(&(frame.timestamp.as_nanos() as u64).to_le_bytes())?;
+        self.file.write_all(&frame.framestamp.to_le_bytes())?;
+        self.file.write_all(&[frame.compression.id()])?;
+        self.file.write_all(&(frame.raw_len as u64).to_le_bytes())?;
+        self.file
+            .write_all(&(frame.data.len() as u64).to_le_bytes())?;
+        self.file.write_all(&frame.data)
+    }
+    /// A file which was never begun gets empty camera settings so it can still be opened
+    fn finish(&mut self) -> io::Result<()> {
+        if !self.header_written {
+            self.write_header(&serde_json::Value::Null)?;
+        }
+        self.file.flush()
+    }
+}
+
+/// Reads frames back out of a file written by `CompressedFileWriter`. Iterating gives each frame in order
+pub struct CompressedFileReader {
+    file: BufReader<File>,
+    info: serde_json::Value,
+}
+
+impl CompressedFileReader {
+    /// Open the file at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<CompressedFileReader> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 4];
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a compressed frame file",
+            ));
+        }
+        let json_len = u32::from_le_bytes(read_array(&mut file)?) as usize;
+        let mut json = vec![0; json_len];
+        file.read_exact(&mut json)?;
+        Ok(CompressedFileReader {
+            file,
+            info: serde_json::from_slice(&json)?,
+        })
+    }
+    /// Camera settings stored at the start of the file, as JSON. `null` if the writer was never begun
+    pub fn camera_settings(&self) -> &serde_json::Value {
+        &self.info
+    }
+    /// read the next frame, `None` at the end of the file
+    fn read_frame(&mut self) -> io::Result<Option<DcamFrame>> {
+        //a clean end of the file falls between frames, anywhere else it was cut short
+        if self.file.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        self.read_next().map(Some).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file ends partway through a frame",
+            ),
+            _ => e,
+        })
+    }
+    fn read_next(&mut self) -> io::Result<DcamFrame> {
+        let width = u32::from_le_bytes(read_array(&mut self.file)?);
+        let height = u32::from_le_bytes(read_array(&mut self.file)?);
+        let timestamp = Duration::from_nanos(u64::from_le_bytes(read_array(&mut self.file)?));
+        let framestamp = i32::from_le_bytes(read_array(&mut self.file)?);
+        let [id] = read_array(&mut self.file)?;
+        let raw_len = u64::from_le_bytes(read_array(&mut self.file)?);
+        let data_len = u64::from_le_bytes(read_array(&mut self.file)?);
+        //check the lengths before allocating anything for them
+        if raw_len != width as u64 * height as u64 * 2 || data_len > compress_bound(raw_len) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "frame lengths don't match its size",
+            ));
+        }
+        let mut data = vec![0; data_len as usize];
+        self.file.read_exact(&mut data)?;
+        let raw = decompress(id, &data, raw_len as usize)?;
+        to_frame(&raw, width, height, timestamp, framestamp)
+    }
+}
+
+impl Iterator for CompressedFileReader {
+    type Item = io::Result<DcamFrame>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_frame().transpose()
+    }
+}
+
+/// Most bytes LZ4 or zstd could turn `raw_len` bytes into, with room to spare
+fn compress_bound(raw_len: u64) -> u64 {
+    raw_len + raw_len / 128 + 1024
+}
+
+/// read exactly `N` bytes
+fn read_array<const N: usize, R: Read>(reader: &mut R) -> io::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{frame, info, temp_path};
+    use std::fs;
+
+    #[test]
+    fn file_round_trip() {
+        let path = temp_path("round_trip.fwz");
+        let mut writer = CompressedFileWriter::create(&path).unwrap();
+        writer.begin(&info()).unwrap();
+        //a second begin doesn't add another header
+        writer.begin(&info()).unwrap();
+        let compressions = [Compression::None, Compression::Lz4, Compression::Zstd(3)];
+        for (n, compression) in compressions.into_iter().enumerate() {
+            let compressed =
+                CompressedFrame::compress(&frame(4, 3, n as i32), compression).unwrap();
+            writer.write_compressed(&compressed).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let reader = CompressedFileReader::open(&path).unwrap();
+        assert_eq!(*reader.camera_settings(), info().to_json());
+        let frames: Vec<DcamFrame> = reader.collect::<io::Result<_>>().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(frames.len(), 3);
+        for (n, read) in frames.iter().enumerate() {
+            let written = frame(4, 3, n as i32);
+            assert_eq!(read.image(), written.image());
+            assert_eq!(read.timestamp(), written.timestamp());
+            assert_eq!(read.framestamp(), written.framestamp());
+        }
+    }
+
+    #[test]
+    fn frames_need_begin() {
+        let path = temp_path("nobegin.fwz");
+        let mut writer = CompressedFileWriter::create(&path).unwrap();
+        let compressed = CompressedFrame::compress(&frame(4, 3, 1), Compression::Lz4).unwrap();
+        assert!(writer.write_compressed(&compressed).is_err());
+        //a file that was never begun still opens, with no settings and no frames
+        writer.finish().unwrap();
+        let reader = CompressedFileReader::open(&path).unwrap();
+        assert!(reader.camera_settings().is_null());
+        assert_eq!(reader.count(), 0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// write a file holding one 2x2 frame, returning where the frame header starts
+    fn one_frame_file(path: &Path) -> u64 {
+        let mut writer = CompressedFileWriter::create(path).unwrap();
+        writer.begin(&info()).unwrap();
+        let compressed = CompressedFrame::compress(&frame(2, 2, 0), Compression::None).unwrap();
+        writer.write_compressed(&compressed).unwrap();
+        writer.finish().unwrap();
+        //the header is 37 bytes and the frame 8
+        fs::metadata(path).unwrap().len() - 45
+    }
+
+    #[test]
+    fn truncated_frames_are_invalid() {
+        let path = temp_path("truncated.fwz");
+        let header = one_frame_file(&path);
+        for len in [header + 10, header + 40] {
+            let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+            file.set_len(len).unwrap();
+            drop(file);
+            let mut reader = CompressedFileReader::open(&path).unwrap();
+            let error = reader.next().unwrap().unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bad_lengths_are_invalid() {
+        let path = temp_path("bad_lengths.fwz");
+        let header = one_frame_file(&path);
+        let bytes = fs::read(&path).unwrap();
+        let raw_len = header as usize + 21;
+        //a raw length that doesn't match the frame, then a huge compressed length
+        for (at, value) in [(raw_len, 9u64), (raw_len + 8, u64::MAX)] {
+            let mut bad = bytes.clone();
+            bad[at..at + 8].copy_from_slice(&value.to_le_bytes());
+            fs::write(&path, &bad).unwrap();
+            let mut reader = CompressedFileReader::open(&path).unwrap();
+            let error = reader.next().unwrap().unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+        //the untouched file still reads
+        fs::write(&path, &bytes).unwrap();
+        let reader = CompressedFileReader::open(&path).unwrap();
+        assert_eq!(reader.count(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+}
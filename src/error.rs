@@ -0,0 +1,90 @@
+//! Error type wrapping the `DCAMERR` codes returned by the API.
+//!
+//! Most of the crate passes the raw code around as `Err(i32)`, `DcamError` converts from that with `?`
+//! and knows the names of the codes you are likely to run into
+
+use crate::bindings;
+use std::error::Error;
+use std::fmt;
+
+/// An error code returned by the DCAM API
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DcamError(pub i32);
+
+impl DcamError {
+    /// The raw `DCAMERR` code
+    pub fn code(&self) -> i32 {
+        self.0
+    }
+    /// `true` if we gave up waiting for the camera
+    pub fn is_timeout(&self) -> bool {
+        self.0 == bindings::DCAMERR_DCAMERR_TIMEOUT
+    }
+    /// `true` if the camera has gone away, e.g. the cable was pulled or the bus reset
+    pub fn is_connection_lost(&self) -> bool {
+        matches!(
+            self.0,
+            bindings::DCAMERR_DCAMERR_NOCONNECTION
+                | bindings::DCAMERR_DCAMERR_NOCAMERA
+                | bindings::DCAMERR_DCAMERR_DEVICEPROBLEM
+                | bindings::DCAMERR_DCAMERR_FAILREADCAMERA
+                | bindings::DCAMERR_DCAMERR_FAILWRITECAMERA
+        )
+    }
+    /// Name of the error code, if it's one we know about
+    pub fn name(&self) -> Option<&'static str> {
+        let name = match self.0 {
+            bindings::DCAMERR_DCAMERR_BUSY => "BUSY",
+            bindings::DCAMERR_DCAMERR_NOTREADY => "NOTREADY",
+            bindings::DCAMERR_DCAMERR_NOTSTABLE => "NOTSTABLE",
+            bindings::DCAMERR_DCAMERR_UNSTABLE => "UNSTABLE",
+            bindings::DCAMERR_DCAMERR_NOTBUSY => "NOTBUSY",
+            bindings::DCAMERR_DCAMERR_ABORT => "ABORT",
+            bindings::DCAMERR_DCAMERR_TIMEOUT => "TIMEOUT",
+            bindings::DCAMERR_DCAMERR_LOSTFRAME => "LOSTFRAME",
+            bindings::DCAMERR_DCAMERR_MISSINGFRAME_TROUBLE => "MISSINGFRAME_TROUBLE",
+            bindings::DCAMERR_DCAMERR_INVALIDIMAGE => "INVALIDIMAGE",
+            bindings::DCAMERR_DCAMERR_NOMEMORY => "NOMEMORY",
+            bindings::DCAMERR_DCAMERR_NOCAMERA => "NOCAMERA",
+            bindings::DCAMERR_DCAMERR_NOCONNECTION => "NOCONNECTION",
+            bindings::DCAMERR_DCAMERR_FAILOPEN => "FAILOPEN",
+            bindings::DCAMERR_DCAMERR_FAILOPENBUS => "FAILOPENBUS",
+            bindings::DCAMERR_DCAMERR_FAILOPENCAMERA => "FAILOPENCAMERA",
+            bindings::DCAMERR_DCAMERR_DEVICEPROBLEM => "DEVICEPROBLEM",
+            bindings::DCAMERR_DCAMERR_INVALIDCAMERA => "INVALIDCAMERA",
+            bindings::DCAMERR_DCAMERR_INVALIDHANDLE => "INVALIDHANDLE",
+            bindings::DCAMERR_DCAMERR_INVALIDPARAM => "INVALIDPARAM",
+            bindings::DCAMERR_DCAMERR_INVALIDVALUE => "INVALIDVALUE",
+            bindings::DCAMERR_DCAMERR_OUTOFRANGE => "OUTOFRANGE",
+            bindings::DCAMERR_DCAMERR_NOTWRITABLE => "NOTWRITABLE",
+            bindings::DCAMERR_DCAMERR_NOTREADABLE => "NOTREADABLE",
+            bindings::DCAMERR_DCAMERR_INVALIDPROPERTYID => "INVALIDPROPERTYID",
+            bindings::DCAMERR_DCAMERR_WRONGHANDSHAKE => "WRONGHANDSHAKE",
+            bindings::DCAMERR_DCAMERR_ACCESSDENY => "ACCESSDENY",
+            bindings::DCAMERR_DCAMERR_INVALIDFRAMEINDEX => "INVALIDFRAMEINDEX",
+            bindings::DCAMERR_DCAMERR_NOTSUPPORT => "NOTSUPPORT",
+            bindings::DCAMERR_DCAMERR_FAILREADCAMERA => "FAILREADCAMERA",
+            bindings::DCAMERR_DCAMERR_FAILWRITECAMERA => "FAILWRITECAMERA",
+            bindings::DCAMERR_DCAMERR_INVALIDWAITHANDLE => "INVALIDWAITHANDLE",
+            _ => return None,
+        };
+        Some(name)
+    }
+}
+
+impl From<i32> for DcamError {
+    fn from(code: i32) -> DcamError {
+        DcamError(code)
+    }
+}
+
+impl fmt::Display for DcamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "DCAM error {} ({:#010x})", name, self.0),
+            None => write!(f, "DCAM error {:#010x}", self.0),
+        }
+    }
+}
+
+impl Error for DcamError {}
@@ -0,0 +1,106 @@
+//! Frames copied out of the camera buffer along with the information DCAM reports about them
+
+use crate::pixel::PixelType;
+use crate::writer::AcquisitionInfo;
+use image::{ImageBuffer, Luma};
+use std::time::Duration;
+
+/// Everything we know about how a frame was captured
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameMetadata {
+    /// frame counter from the camera
+    pub framestamp: i32,
+    /// timestamp from the camera, see `DcamFrame::timestamp`
+    pub timestamp: Duration,
+    /// time since the stream started, this matches the time in the ralston `Frame`
+    pub elapsed: Duration,
+    /// exposure time in seconds
+    pub exposure: f64,
+    /// sensor region as `[left, top, width, height]`
+    pub roi: [usize; 4],
+    /// value of `DCAM_IDPROP_BINNING`
+    pub binning: i32,
+    /// pixel format the camera delivered before unpacking
+    pub pixel_type: PixelType,
+    /// most recent reading of the sensor temperature in degrees Celsius
+    pub sensor_temperature: Option<f64>,
+    /// serial number of the camera which captured the frame, empty if the camera didn't report one
+    pub serial_number: String,
+    /// frames the camera captured since the stream started that were never delivered
+    pub lost_frames: u64,
+}
+
+impl FrameMetadata {
+    /// Describe a frame captured with the settings in `info`
+    pub fn new(
+        info: &AcquisitionInfo,
+        timestamp: Duration,
+        framestamp: i32,
+        elapsed: Duration,
+        lost_frames: u64,
+    ) -> FrameMetadata {
+        FrameMetadata {
+            framestamp,
+            timestamp,
+            elapsed,
+            exposure: info.exposure,
+            roi: info.roi,
+            binning: info.binning,
+            pixel_type: info.pixel_type,
+            sensor_temperature: info.sensor_temperature,
+            serial_number: info.serial_number.clone(),
+            lost_frames,
+        }
+    }
+}
+
+/// A single frame captured by the camera
+#[derive(Debug, Clone)]
+pub struct DcamFrame {
+    image: ImageBuffer<Luma<u16>, Vec<u16>>,
+    timestamp: Duration,
+    framestamp: i32,
+    metadata: Option<FrameMetadata>,
+}
+
+impl DcamFrame {
+    /// Bundle an image with the timestamp and framestamp DCAM reported for it
+    pub fn new(
+        image: ImageBuffer<Luma<u16>, Vec<u16>>,
+        timestamp: Duration,
+        framestamp: i32,
+    ) -> DcamFrame {
+        DcamFrame {
+            image,
+            timestamp,
+            framestamp,
+            metadata: None,
+        }
+    }
+    /// Attach `metadata` to this frame
+    pub fn with_metadata(mut self, metadata: FrameMetadata) -> DcamFrame {
+        self.metadata = Some(metadata);
+        self
+    }
+    /// Get the metadata attached to this frame. Frames from streams and snaps always have metadata
+    pub fn metadata(&self) -> Option<&FrameMetadata> {
+        self.metadata.as_ref()
+    }
+    /// Get the image data
+    pub fn image(&self) -> &ImageBuffer<Luma<u16>, Vec<u16>> {
+        &self.image
+    }
+    /// Take the image data, throwing away the timestamps
+    pub fn into_image(self) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+        self.image
+    }
+    /// The timestamp DCAM attached to this frame. What this is measured from depends on
+    /// the camera, see `DCAM_IDPROP_TIMESTAMP_PRODUCER`
+    pub fn timestamp(&self) -> Duration {
+        self.timestamp
+    }
+    /// The frame counter DCAM attached to this frame, this can be used to check for dropped frames
+    pub fn framestamp(&self) -> i32 {
+        self.framestamp
+    }
+}
@@ -1,16 +1,95 @@
-use image::{DynamicImage, ImageBuffer, Luma};
+use image::{ImageBuffer, Luma};
 use libc;
+#[cfg(feature = "ralston")]
 use ralston::{Frame, FrameSource, FrameStream};
+use std::collections::VecDeque;
 use std::ffi::CStr;
+use std::io;
 use std::ops::Index;
 use std::ops::IndexMut;
 use std::os::raw;
+use std::path::{Path, PathBuf};
 use std::ptr;
-use std::sync::mpsc::{channel, Sender, TryRecvError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::Arc;
 use std::thread::{self, JoinHandle};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
+#[macro_use]
+mod log;
+
+#[cfg(feature = "async")]
+pub mod async_stream;
+pub mod async_writer;
 pub mod bindings;
+pub mod capture;
+pub mod compression;
+pub mod error;
+pub mod frame;
+pub mod multicam;
+pub mod ome_tiff;
+#[cfg(feature = "ralston")]
+pub mod output;
+pub mod pixel;
+#[cfg(feature = "ralston")]
+pub mod playback;
+pub mod pretrigger;
+pub mod preview;
+pub mod reconnect;
+pub mod recording;
+pub mod snapshot;
+pub mod spool;
+pub mod stats;
+pub mod subscription;
+#[cfg(test)]
+mod test_util;
+pub mod trigger;
+pub mod wait;
+pub mod writer;
+pub mod zarr;
+
+#[cfg(feature = "async")]
+pub use async_stream::AsyncFrameStream;
+pub use async_writer::{AsyncWriter, OverflowPolicy, WriterMonitor, WriterStats};
+pub use capture::Capture;
+pub use compression::{
+    CompressedFileReader, CompressedFileWriter, CompressedFrame, CompressedWriter, Compression,
+    CompressionStage, CompressionStats,
+};
+pub use error::DcamError;
+pub use frame::{DcamFrame, FrameMetadata};
+pub use multicam::{FrameSet, MatchBy, MultiCamera, SyncedCapture, Unmatched};
+pub use ome_tiff::OmeTiffWriter;
+#[cfg(feature = "ralston")]
+pub use output::OutputStats;
+pub use pixel::PixelType;
+#[cfg(feature = "ralston")]
+pub use playback::{RecordingSource, RecordingStream};
+pub use pretrigger::{Detector, PreTriggerWriter, TriggerHandle};
+pub use preview::{Contrast, Preview, PreviewFrame, PreviewSettings};
+pub use reconnect::{ConnectionEvent, ReconnectPolicy};
+pub use recording::{
+    MetadataCapacity, MetadataKind, MetadataLocation, RecordedFrames, Recording, RecordingStatus,
+    UserMetadata,
+};
+pub use snapshot::{save_snapshot, save_snapshot_as, SnapshotError, SnapshotFormat};
+pub use spool::{SpoolLayout, SpoolReader, SpoolWriter};
+pub use stats::{StatsHandle, StreamStats};
+pub use subscription::Subscription;
+pub use trigger::TriggerSource;
+pub use wait::{CaptureStatus, WaitEvents, WaitHandle};
+pub use writer::{AcquisitionInfo, FrameWriter, WriteStage, WriterError};
+pub use zarr::{ZarrFormat, ZarrWriter};
+
+/// Counts how many times the API has been released. Releasing the API invalidates every handle opened
+/// through it, wherever it came from, so handles which outlive a `DcamAPI` check this before use
+static API_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// The number of times the API has been released so far
+pub(crate) fn api_generation() -> u64 {
+    API_GENERATION.load(Ordering::Acquire)
+}
 
 /// `struct` to represent an instance of the DCAM API
 pub struct DcamAPI {
@@ -27,8 +106,10 @@ impl DcamAPI {
             err = bindings::dcamapi_init(&mut api);
         }
         if err == 1 {
+            log_event!(INFO, devices = api.iDeviceCount, "DCAM API initialized");
             Ok(DcamAPI { api_init: api })
         } else {
+            log_event!(ERROR, error = %DcamError(err), "couldn't initialize the DCAM API");
             Err(err)
         }
     }
@@ -39,8 +120,13 @@ impl DcamAPI {
 
     /// Get a handle to a camera
     pub fn open_cam<T: Camera>(&self, cam_id: i32) -> Result<T, i32> {
-        if cam_id > self.ncam() {
-            eprintln!("camera index cannot be greater than (ncam - 1)");
+        if cam_id < 0 || cam_id >= self.ncam() {
+            log_event!(
+                WARN,
+                cam_id,
+                ncam = self.ncam(),
+                "camera index must be less than the number of cameras"
+            );
             return Err(bindings::DCAMERR_DCAMERR_INVALIDCAMERA);
         }
         //make a new DCAMDEV_OPEN struct and try to open the camera
@@ -48,21 +134,47 @@ impl DcamAPI {
         unsafe {
             let err = bindings::dcamdev_open(&mut dco);
             if err != 1 {
+                log_event!(WARN, cam_id, error = %DcamError(err), "couldn't open camera");
                 return Err(err);
             }
         }
         //if the open worked, the hdcam pointer should not be null
         assert!(!dco.hdcam.is_null(), "null camera pointer");
-        Ok(T::new(dco.hdcam))
+        let cam = T::new(dco.hdcam);
+        log_event!(
+            INFO,
+            cam_id,
+            model = cam.model().unwrap_or_default(),
+            serial_number = cam.serial_number().unwrap_or_default(),
+            "camera opened"
+        );
+        Ok(cam)
+    }
+    /// Get a handle to the camera with serial number `serial`, wherever it is in the device list.
+    /// Returns `Err(DCAMERR_NOCAMERA)` if it isn't connected
+    pub fn open_serial<T: Camera>(&self, serial: &str) -> Result<T, i32> {
+        for cam_id in 0..self.ncam() {
+            //cameras someone else has open can't be ours
+            if let Ok(cam) = self.open_cam::<T>(cam_id) {
+                if cam.serial_number().as_deref() == Ok(serial) {
+                    return Ok(cam);
+                }
+            }
+        }
+        Err(bindings::DCAMERR_DCAMERR_NOCAMERA)
     }
 }
 
-/// Automatically release the API when our API handle is dropped
+/// Automatically release the API when our API handle is dropped, closing any `Recording` still open
 impl Drop for DcamAPI {
     fn drop(&mut self) {
+        //recordings can't be closed once the API is gone
+        recording::close_all();
         unsafe {
             bindings::dcamapi_uninit();
         }
+        API_GENERATION.fetch_add(1, Ordering::AcqRel);
+        log_event!(INFO, "DCAM API released");
     }
 }
 
@@ -135,28 +247,287 @@ pub trait Camera: Drop {
     fn serial_number(&self) -> Result<String, String> {
         self.dcamdev_getstring(bindings::DCAM_IDSTR_DCAM_IDSTR_CAMERAID)
     }
+    /// call the API dcamprop_getname to get the name of the property associated with `i_prop`
+    fn dcamprop_getname(&self, i_prop: bindings::int32) -> Result<String, i32> {
+        let mut carray: [raw::c_char; 64] = [0; 64];
+        let err = unsafe {
+            bindings::dcamprop_getname(
+                self.handle(),
+                i_prop,
+                carray.as_mut_ptr(),
+                carray.len() as bindings::int32,
+            )
+        };
+        match err {
+            1 => Ok(unsafe { CStr::from_ptr(carray.as_ptr()) }
+                .to_string_lossy()
+                .into_owned()),
+            e => Err(e),
+        }
+    }
     /// call the API dcamprop_getvalue to get the property associated with `i_prop`
     fn dcamprop_getvalue(&self, i_prop: bindings::int32) -> Result<f64, i32> {
         let mut val: f64 = 0.0;
         let err = unsafe { bindings::dcamprop_getvalue(self.handle(), i_prop, &mut val) };
         match err {
-            1 => Ok(val),
-            e => Err(e),
+            1 => {
+                log_event!(
+                    TRACE,
+                    property = self.dcamprop_getname(i_prop).unwrap_or_default(),
+                    i_prop,
+                    value = val,
+                    "property read"
+                );
+                Ok(val)
+            }
+            e => {
+                log_event!(
+                    DEBUG,
+                    property = self.dcamprop_getname(i_prop).unwrap_or_default(),
+                    i_prop,
+                    error = %DcamError(e),
+                    "couldn't read property"
+                );
+                Err(e)
+            }
         }
     }
     /// call the API dcamprop_getvalue to set the property associated with `i_prop` to `f_value`
     fn dcamprop_setvalue(&self, i_prop: bindings::int32, f_value: f64) -> Result<(), i32> {
         let err = unsafe { bindings::dcamprop_setvalue(self.handle(), i_prop, f_value) };
         match err {
-            1 => Ok(()),
+            1 => {
+                log_event!(
+                    DEBUG,
+                    property = self.dcamprop_getname(i_prop).unwrap_or_default(),
+                    i_prop,
+                    value = f_value,
+                    "property written"
+                );
+                Ok(())
+            }
+            e => {
+                log_event!(
+                    WARN,
+                    property = self.dcamprop_getname(i_prop).unwrap_or_default(),
+                    i_prop,
+                    value = f_value,
+                    error = %DcamError(e),
+                    "couldn't write property"
+                );
+                Err(e)
+            }
+        }
+    }
+    /// get the pixel type the camera is currently delivering
+    fn get_pixel_type(&self) -> Result<PixelType, i32> {
+        let val = self.dcamprop_getvalue(bindings::_DCAMIDPROP_DCAM_IDPROP_IMAGE_PIXELTYPE)?;
+        PixelType::from_dcam(val as i32).ok_or(bindings::DCAMERR_DCAMERR_INVALIDVALUE)
+    }
+    /// select the pixel type the camera should deliver, this must be done before a buffer is attached
+    fn set_pixel_type(&self, pixel_type: PixelType) -> Result<(), i32> {
+        self.dcamprop_setvalue(
+            bindings::_DCAMIDPROP_DCAM_IDPROP_IMAGE_PIXELTYPE,
+            pixel_type.to_dcam() as f64,
+        )
+    }
+    /// call the API dcamprop_getattr to get the attributes of the property associated with `i_prop`
+    fn dcamprop_getattr(&self, i_prop: bindings::int32) -> Result<bindings::DCAMPROP_ATTR, i32> {
+        let mut attr = bindings::DCAMPROP_ATTR::new(i_prop);
+        match unsafe { bindings::dcamprop_getattr(self.handle(), &mut attr) } {
+            1 => Ok(attr),
             e => Err(e),
         }
     }
+    /// check whether the property associated with `i_prop` can be written while the camera is capturing
+    fn writable_while_busy(&self, i_prop: bindings::int32) -> Result<bool, i32> {
+        let attr = self.dcamprop_getattr(i_prop)?;
+        Ok(attr.attribute & bindings::_DCAMPROPATTRIBUTE_DCAMPROP_ATTR_ACCESSBUSY != 0)
+    }
+    /// set the region of interest `[hpos, vpos, hsize, vsize]` in pixels, returns the region the camera
+    /// actually applied after rounding
+    fn set_roi(&self, roi: [usize; 4]) -> Result<[usize; 4], i32> {
+        self.dcamprop_setvalue(
+            bindings::_DCAMIDPROP_DCAM_IDPROP_SUBARRAYMODE,
+            bindings::_DCAMPROPMODEVALUE_DCAMPROP_MODE__ON as f64,
+        )?;
+        //move to the corner first so the new size always fits on the sensor
+        self.dcamprop_setvalue(bindings::_DCAMIDPROP_DCAM_IDPROP_SUBARRAYHPOS, 0.0)?;
+        self.dcamprop_setvalue(bindings::_DCAMIDPROP_DCAM_IDPROP_SUBARRAYVPOS, 0.0)?;
+        let props = [
+            bindings::_DCAMIDPROP_DCAM_IDPROP_SUBARRAYHSIZE,
+            bindings::_DCAMIDPROP_DCAM_IDPROP_SUBARRAYVSIZE,
+            bindings::_DCAMIDPROP_DCAM_IDPROP_SUBARRAYHPOS,
+            bindings::_DCAMIDPROP_DCAM_IDPROP_SUBARRAYVPOS,
+        ];
+        for (prop, value) in props.iter().zip([roi[2], roi[3], roi[0], roi[1]]) {
+            self.dcamprop_setvalue(*prop, value as f64)?;
+        }
+        let [hsize, vsize, hpos, vpos] = props.map(|prop| self.dcamprop_getvalue(prop));
+        Ok([
+            hpos? as usize,
+            vpos? as usize,
+            hsize? as usize,
+            vsize? as usize,
+        ])
+    }
+    /// set the binning factor, returns the value the camera actually applied
+    fn set_binning(&self, binning: i32) -> Result<i32, i32> {
+        self.dcamprop_setvalue(bindings::_DCAMIDPROP_DCAM_IDPROP_BINNING, binning as f64)?;
+        Ok(self.dcamprop_getvalue(bindings::_DCAMIDPROP_DCAM_IDPROP_BINNING)? as i32)
+    }
+    /// get where the camera gets the signal to start each exposure
+    fn get_trigger_source(&self) -> Result<TriggerSource, i32> {
+        let val = self.dcamprop_getvalue(bindings::_DCAMIDPROP_DCAM_IDPROP_TRIGGERSOURCE)?;
+        TriggerSource::from_dcam(val as i32).ok_or(bindings::DCAMERR_DCAMERR_INVALIDVALUE)
+    }
+    /// select where the camera gets the signal to start each exposure
+    fn set_trigger_source(&self, source: TriggerSource) -> Result<TriggerSource, i32> {
+        self.dcamprop_setvalue(
+            bindings::_DCAMIDPROP_DCAM_IDPROP_TRIGGERSOURCE,
+            source.to_dcam() as f64,
+        )?;
+        self.get_trigger_source()
+    }
+    /// get the number of bytes between the start of each row in the frame buffer
+    fn get_buffer_rowbytes(&self) -> Result<usize, i32> {
+        match self.dcamprop_getvalue(bindings::_DCAMIDPROP_DCAM_IDPROP_BUFFER_ROWBYTES) {
+            Ok(f) => Ok(f as usize),
+            Err(e) => Err(e),
+        }
+    }
     /// allocate and attach a `FrameBuffer` capable of holding `num_frames`
     fn attach_buffer(&self, num_frames: usize) -> Result<FrameBuffer, i32> {
         let frame_size = self.get_framebytes()?;
         FrameBuffer::attach(self.handle(), frame_size, num_frames)
     }
+    /// call the API dcamcap_start to start capturing, `mode` should be
+    /// `DCAMCAP_START_SEQUENCE` or `DCAMCAP_START_SNAP`
+    fn dcamcap_start(&self, mode: i32) -> Result<(), i32> {
+        match unsafe { bindings::dcamcap_start(self.handle(), mode) } {
+            1 => {
+                log_event!(INFO, mode, "capture started");
+                Ok(())
+            }
+            e => {
+                log_event!(ERROR, mode, error = %DcamError(e), "couldn't start capture");
+                Err(e)
+            }
+        }
+    }
+    /// call the API dcamcap_stop to stop capturing
+    fn dcamcap_stop(&self) -> Result<(), i32> {
+        match unsafe { bindings::dcamcap_stop(self.handle()) } {
+            1 => {
+                log_event!(INFO, "capture stopped");
+                Ok(())
+            }
+            e => {
+                log_event!(ERROR, error = %DcamError(e), "couldn't stop capture");
+                Err(e)
+            }
+        }
+    }
+    /// call the API dcamcap_firetrigger to start an exposure when the camera is waiting for a
+    /// software trigger
+    fn dcamcap_firetrigger(&self) -> Result<(), i32> {
+        match unsafe { bindings::dcamcap_firetrigger(self.handle(), 0) } {
+            1 => Ok(()),
+            e => Err(e),
+        }
+    }
+    /// call the API dcamcap_status to find out what the camera is doing
+    fn dcamcap_status(&self) -> Result<CaptureStatus, i32> {
+        let mut status = 0;
+        match unsafe { bindings::dcamcap_status(self.handle(), &mut status) } {
+            1 => CaptureStatus::from_dcam(status).ok_or(bindings::DCAMERR_DCAMERR_INVALIDVALUE),
+            e => Err(e),
+        }
+    }
+    /// open a wait handle for capture and recording events on this camera, it can be used from
+    /// another thread while this one captures
+    fn wait_handle(&self) -> Result<WaitHandle<'_>, DcamError> {
+        Ok(WaitHandle::open(self.handle())?)
+    }
+    /// call the API dcamcap_record to write everything captured from now on to `recording`.
+    /// This must be called before capture is started
+    fn dcamcap_record(&self, recording: &Recording) -> Result<(), i32> {
+        let _open = recording.check()?;
+        match unsafe { bindings::dcamcap_record(self.handle(), recording.handle()) } {
+            1 => Ok(()),
+            e => Err(e),
+        }
+    }
+    /// Capture exactly `num_frames` frames with the current settings and return them in order.
+    /// This blocks until the camera reports the capture has stopped, if that takes longer than
+    /// `timeout` the capture is aborted and `Err(DCAMERR_TIMEOUT)` is returned. If the camera
+    /// delivered fewer frames than we asked for this returns `Err(DCAMERR_LOSTFRAME)`.
+    /// The camera must not have another buffer attached when this is called
+    fn snap(&self, num_frames: usize, timeout: Duration) -> Result<Vec<DcamFrame>, i32>
+    where
+        Self: Sized,
+    {
+        let pixel_type = self.get_pixel_type()?;
+        let rowbytes = self.get_buffer_rowbytes()?;
+        let [width, height] = self.get_resolution()?;
+        let info = AcquisitionInfo::from_camera(self)?;
+        let framebuffer = self.attach_buffer(num_frames)?;
+        let hwait = framebuffer.get_wait_handle()?;
+        let captured = framebuffer.snap(hwait, timeout);
+        unsafe {
+            bindings::dcamwait_close(hwait);
+        }
+        captured?;
+        let mut first_timestamp = None;
+        (0..num_frames)
+            .map(|i| {
+                let frame = framebuffer.copy_frame(
+                    i,
+                    pixel_type,
+                    width as usize,
+                    height as usize,
+                    rowbytes,
+                )?;
+                //snaps fail if any frames are lost, time is measured from the first frame
+                let first = *first_timestamp.get_or_insert(frame.timestamp());
+                let metadata = FrameMetadata::new(
+                    &info,
+                    frame.timestamp(),
+                    frame.framestamp(),
+                    frame.timestamp().saturating_sub(first),
+                    0,
+                );
+                Ok(frame.with_metadata(metadata))
+            })
+            .collect()
+    }
+    /// Start a sequence capture into a `num_frames` long buffer and loop over the frames on this thread.
+    /// Each call to `next` waits up to `timeout` for a frame. The camera must not have another buffer
+    /// attached when this is called, capture stops when the `Capture` is dropped
+    fn capture(&self, num_frames: usize, timeout: Duration) -> Result<Capture<'_>, DcamError>
+    where
+        Self: Sized,
+    {
+        Capture::start(self, num_frames, timeout)
+    }
+    /// Capture a single frame and save it to `path` as a 16 bit PNG or TIFF, chosen by the extension,
+    /// with the camera's settings embedded in the file. The captured frame is returned
+    fn snapshot<P: AsRef<Path>>(
+        &self,
+        path: P,
+        timeout: Duration,
+    ) -> Result<DcamFrame, SnapshotError>
+    where
+        Self: Sized,
+    {
+        let info = AcquisitionInfo::from_camera(self).map_err(SnapshotError::Dcam)?;
+        let frame = self
+            .snap(1, timeout)
+            .map_err(SnapshotError::Dcam)?
+            .remove(0);
+        save_snapshot(path, &frame, &info)?;
+        Ok(frame)
+    }
 }
 
 impl Camera for C11440_22CU {
@@ -265,6 +636,7 @@ impl Drop for C11440_22CU {
         unsafe {
             bindings::dcamdev_close(self.handle);
         }
+        log_event!(INFO, "camera closed");
     }
 }
 
@@ -314,8 +686,20 @@ impl FrameBuffer {
         let dcba = bindings::DCAMBUF_ATTACH::new(bufptr, num_frames);
         // attach the buffer to the API
         match unsafe { bindings::dcambuf_attach(camera_handle, &dcba) } {
-            1 => Ok(me),
-            e => Err(e),
+            1 => {
+                log_event!(DEBUG, frame_size, num_frames, "buffer attached");
+                Ok(me)
+            }
+            e => {
+                log_event!(
+                    ERROR,
+                    frame_size,
+                    num_frames,
+                    error = %DcamError(e),
+                    "couldn't attach buffer"
+                );
+                Err(e)
+            }
         }
     }
     /// call the API's dcamcap_transferinfo function to recieve a tuple containing
@@ -334,13 +718,87 @@ impl FrameBuffer {
             Err(e) => Err(e),
         }
     }
-    ///get a copy of the most recently captured frame
-    fn copy_most_recent_frame(&self) -> Result<Vec<u16>, i32> {
-        match self.most_recent_frame_index() {
-            Ok(i) => Ok(self[i].to_vec()),
-            Err(e) => Err(e),
+    /// get the raw bytes of the frame at `index`, needed for pixel types which aren't 16 bit
+    fn frame_bytes(&self, index: usize) -> &[u8] {
+        let frame = &self[index];
+        unsafe { std::slice::from_raw_parts(frame.as_ptr() as *const u8, frame.len() * 2) }
+    }
+    /// get a copy of the frame at `index` converted from `pixel_type` into 16 bit pixels
+    fn copy_frame_as(
+        &self,
+        index: usize,
+        pixel_type: PixelType,
+        width: usize,
+        height: usize,
+        rowbytes: usize,
+    ) -> Vec<u16> {
+        match pixel_type {
+            //no conversion needed if the rows aren't padded
+            PixelType::Mono16 if rowbytes == width * 2 => self[index].to_vec(),
+            _ => pixel_type.to_mono16(self.frame_bytes(index), width, height, rowbytes),
         }
     }
+    /// call the API's dcambuf_lockframe function to get the information DCAM has about the frame at `index`
+    fn lock_frame(&self, index: usize) -> Result<bindings::DCAMBUF_FRAME, i32> {
+        let mut frame = bindings::DCAMBUF_FRAME::new(index as i32);
+        match unsafe { bindings::dcambuf_lockframe(self.camera_handle, &mut frame) } {
+            1 => Ok(frame),
+            e => Err(e),
+        }
+    }
+    /// copy the frame at `index` into a `DcamFrame` along with its timestamp and framestamp
+    fn copy_frame(
+        &self,
+        index: usize,
+        pixel_type: PixelType,
+        width: usize,
+        height: usize,
+        rowbytes: usize,
+    ) -> Result<DcamFrame, i32> {
+        let info = self.lock_frame(index)?;
+        let mut pixels = self.copy_frame_as(index, pixel_type, width, height, rowbytes);
+        //our buffer can be slightly larger than the image
+        pixels.truncate(width * height);
+        let image =
+            ImageBuffer::<Luma<u16>, Vec<u16>>::from_raw(width as u32, height as u32, pixels)
+                .ok_or(bindings::DCAMERR_DCAMERR_INVALIDIMAGE)?;
+        Ok(DcamFrame::new(
+            image,
+            info.timestamp.as_duration(),
+            info.framestamp,
+        ))
+    }
+    /// Fill the whole buffer with a single snap capture, waiting on `hwait` for up to `timeout`
+    fn snap(&self, hwait: bindings::HDCAMWAIT, timeout: Duration) -> Result<(), i32> {
+        let err = unsafe {
+            bindings::dcamcap_start(
+                self.camera_handle,
+                bindings::DCAMCAP_START_DCAMCAP_START_SNAP,
+            )
+        };
+        if err != 1 {
+            return Err(err);
+        }
+        //the camera goes back to ready on its own once the buffer is full
+        let mut dws = bindings::DCAMWAIT_START::with_event(
+            bindings::DCAMWAIT_EVENT_DCAMWAIT_CAPEVENT_STOPPED,
+            timeout.as_millis().min(i32::MAX as u128) as i32,
+        );
+        let err = unsafe { bindings::dcamwait_start(hwait, &mut dws) };
+        if err != 1 {
+            //make sure we don't leave the camera running
+            unsafe {
+                bindings::dcamcap_stop(self.camera_handle);
+            }
+            return Err(err);
+        }
+        //check that every frame made it into the buffer
+        let (_, frame_count) = self.dcamcap_transferinfo()?;
+        if (frame_count as usize) < self.num_frames {
+            return Err(bindings::DCAMERR_DCAMERR_LOSTFRAME);
+        }
+        Ok(())
+    }
     /// get an api wait handle
     fn get_wait_handle(&self) -> Result<bindings::HDCAMWAIT, i32> {
         let mut dcwo = bindings::DCAMWAIT_OPEN::new(self.camera_handle);
@@ -359,6 +817,7 @@ impl Drop for FrameBuffer {
                 bindings::DCAM_ATTACHKIND_DCAMBUF_ATTACHKIND_FRAME,
             );
         }
+        log_event!(DEBUG, num_frames = self.num_frames, "buffer released");
     }
 }
 
@@ -367,7 +826,12 @@ pub struct C11440_22CUSource {
     camid: i32,
     exposure: f64,
     resolution: [usize; 2],
+    pixel_type: PixelType,
+    recording: Option<Arc<Recording>>,
     bufsize: usize,
+    reconnect: Option<ReconnectPolicy>,
+    #[cfg(feature = "ralston")]
+    output_queue: (usize, OverflowPolicy),
 }
 
 ///Struct for representing a stream of frames. can call recv to grab frames, stop to stop
@@ -375,6 +839,11 @@ pub struct DcamStream {
     ///channel we use to kill the capture thread
     control_tx: Sender<DcamStreamMessage>,
     thread_handle: JoinHandle<()>,
+    stats: StatsHandle,
+    #[cfg(feature = "ralston")]
+    output: Arc<output::OutputCounters>,
+    ///number handed to the next writer or spool added
+    next_writer: usize,
 }
 
 impl C11440_22CUSource {
@@ -386,47 +855,236 @@ impl C11440_22CUSource {
             camid,
             exposure: 0.00999771,
             resolution: [2048, 2048],
+            pixel_type: PixelType::Mono16,
+            recording: None,
             bufsize,
+            reconnect: None,
+            #[cfg(feature = "ralston")]
+            output_queue: (bufsize, OverflowPolicy::Block),
         }
     }
-    ///Stream from a C11440_22CU. `bufsize` is the size of the image buffer
-    ///as well as the size of the buffer the frames are written to by the thread spawned here
-    fn stream(&self, sender: Sender<Frame>) -> DcamStream {
+    ///Choose how streams try to get the camera back if it goes away, `None` lets the capture thread
+    ///panic instead. Reconnecting is off by default, so losing the camera stops the stream as it always
+    ///has. Streams which are recording stop when the camera goes away, whatever the policy
+    pub fn set_reconnect(&mut self, policy: Option<ReconnectPolicy>) {
+        self.reconnect = policy;
+    }
+    ///Choose how many frames can wait for the ralston consumer and what happens when it falls further
+    ///behind than that. The default is `bufsize` frames with `OverflowPolicy::Block`
+    #[cfg(feature = "ralston")]
+    pub fn set_output_queue(&mut self, capacity: usize, policy: OverflowPolicy) {
+        self.output_queue = (capacity, policy);
+    }
+    ///Write every frame captured by streams started from this source to `recording`.
+    ///Keep a clone of the `Arc` to check on progress or pause the recording while the stream runs.
+    ///The stream releases the API when it stops, which closes the recording
+    pub fn record_to(&mut self, recording: Arc<Recording>) {
+        self.recording = Some(recording);
+    }
+    ///Stop recording streams started from this source
+    pub fn stop_recording(&mut self) {
+        self.recording = None;
+    }
+    ///Choose the pixel type the camera should deliver. Packed 12 bit data is unpacked
+    ///into 16 bit images in the stream, so this only changes the bandwidth used by the camera
+    pub fn set_pixel_type(&mut self, pixel_type: PixelType) {
+        self.pixel_type = pixel_type;
+    }
+    ///Get the pixel type that will be requested from the camera
+    pub fn get_pixel_type(&self) -> PixelType {
+        self.pixel_type
+    }
+    ///Change the exposure used by streams started from this source. Minimum is `0.001003669` Maximum is `10.0`
+    pub fn set_exposure(&mut self, exposure: f64) {
+        self.exposure = exposure;
+    }
+    ///Change the resolution used by streams started from this source
+    pub fn set_resolution(&mut self, resolution: [usize; 2]) {
+        self.resolution = resolution;
+    }
+    ///Get the exposure streams will be started with
+    pub fn get_exposure(&self) -> f64 {
+        self.exposure
+    }
+    ///Get the resolution streams will be started with
+    pub fn get_resolution(&self) -> [usize; 2] {
+        self.resolution
+    }
+    ///Stream from a C11440_22CU without a ralston consumer, use `subscribe` or `add_writer`
+    ///on the returned stream to get at the frames. `bufsize` is the size of the image buffer
+    pub fn start_stream(&self) -> DcamStream {
         stream::<C11440_22CU>(
             self.camid,
             self.bufsize,
             self.exposure,
             self.resolution,
-            sender,
+            self.pixel_type,
+            self.recording.clone(),
+            self.reconnect,
         )
     }
 }
 
+#[cfg(feature = "ralston")]
 impl FrameSource for C11440_22CUSource {
     type Stream = DcamStream;
     ///Change the exposure value. Minimum is `0.001003669` Maximum is `10.0`
     fn set_exposure(&mut self, exposure: f64) {
-        self.exposure = exposure;
+        C11440_22CUSource::set_exposure(self, exposure);
     }
     fn set_resolution(&mut self, resolution: [usize; 2]) {
-        self.resolution = resolution;
+        C11440_22CUSource::set_resolution(self, resolution);
     }
     fn get_exposure(&self) -> f64 {
-        self.exposure
+        C11440_22CUSource::get_exposure(self)
     }
     fn get_resolution(&self) -> [usize; 2] {
-        self.resolution
+        C11440_22CUSource::get_resolution(self)
     }
     fn start(&self, sender: Sender<Frame>) -> Self::Stream {
-        self.stream(sender)
+        let mut stream = self.start_stream();
+        //these are handled before the first frame is captured
+        let (capacity, policy) = self.output_queue;
+        stream.set_output_queue(capacity, policy);
+        stream.change_consumer(sender);
+        stream
     }
 }
 
+/// Called by the capture thread with the result of a request
+type Reply<T> = Box<dyn FnOnce(T) + Send>;
+/// Called by the capture thread with every new frame, returns `false` once it doesn't want any more
+type Subscriber = Box<dyn FnMut(&DcamFrame) -> bool + Send>;
+
 ///Messages to send to our streaming thread
 enum DcamStreamMessage {
+    #[cfg(feature = "ralston")]
     ChangeConsumer(Sender<Frame>),
+    #[cfg(feature = "ralston")]
+    SetOutputQueue(usize, OverflowPolicy),
+    Subscribe(Subscriber),
+    SetExposure(f64, Reply<Result<f64, i32>>),
+    SetRoi([usize; 4], Reply<Result<[usize; 4], i32>>),
+    SetBinning(i32, Reply<Result<i32, i32>>),
+    SetTrigger(TriggerSource, Reply<Result<TriggerSource, i32>>),
+    FireTrigger(Reply<Result<(), i32>>),
+    GetStatus(Reply<Result<CaptureStatus, i32>>),
+    //the handle's lifetime is tied to the `DcamStream` by `DcamStream::wait_handle`
+    OpenWait(Reply<Result<WaitHandle<'static>, i32>>),
+    Pause(Reply<Result<(), i32>>),
+    Resume(Reply<Result<(), i32>>),
+    AddWriter(usize, Box<dyn FrameWriter>),
+    AddSpool(usize, Box<SpoolWriter>),
+    Snapshot(PathBuf, Sender<Result<(), SnapshotError>>),
+    ChangeMetadataConsumer(Option<Sender<FrameMetadata>>),
+    ChangeConnectionConsumer(Option<Sender<ConnectionEvent>>),
+    ChangeWriterErrorConsumer(Option<Sender<WriterError>>),
     Stop,
 }
+///Start a sequence capture on `cam`, recordings have to be attached before every start
+fn start_sequence<T: Camera>(cam: &T, recording: Option<&Recording>) -> Result<(), i32> {
+    if let Some(rec) = recording {
+        cam.dcamcap_record(rec)?;
+    }
+    cam.dcamcap_start(bindings::DCAMCAP_START_DCAMCAP_START_SEQUENCE)
+}
+///Change camera settings with `set` while `cam` is streaming into `framebuffer`. If any of `props` can't be
+///written while the camera is busy, capture is stopped around the change and restarted. If the change can
+///`resize` the frame the buffer is released first and a new one attached afterwards, whether or not we were
+///capturing. If the stream is `paused` capture is left stopped. If stopping fails nothing has changed, if the buffer can't be reattached or
+///capture can't be restarted that error is returned and the stream is left paused, `resume` tries again
+#[allow(clippy::too_many_arguments)]
+fn reconfigure<T: Camera, R>(
+    cam: &T,
+    framebuffer: &mut Option<FrameBuffer>,
+    bufsize: usize,
+    recording: Option<&Recording>,
+    paused: &mut bool,
+    resize: bool,
+    props: &[bindings::int32],
+    set: impl FnOnce(&T) -> Result<R, i32>,
+) -> Result<R, i32> {
+    let capturing = !*paused;
+    let busy_ok = props
+        .iter()
+        .all(|prop| cam.writable_while_busy(*prop).unwrap_or(false));
+    //a new frame size always needs a new buffer, otherwise we only stop if the camera insists
+    if !(resize || capturing && !busy_ok) {
+        return set(cam);
+    }
+    if capturing {
+        cam.dcamcap_stop()?;
+        *paused = true;
+    }
+    //release the old buffer before the new one is attached
+    if resize {
+        *framebuffer = None;
+    }
+    let result = set(cam);
+    attach_if_released(cam, framebuffer, bufsize)?;
+    if capturing {
+        start_sequence(cam, recording)?;
+        *paused = false;
+    }
+    result
+}
+///Attach a new buffer if the last one was released
+fn attach_if_released<T: Camera>(
+    cam: &T,
+    framebuffer: &mut Option<FrameBuffer>,
+    bufsize: usize,
+) -> Result<(), i32> {
+    if framebuffer.is_none() {
+        *framebuffer = Some(cam.attach_buffer(bufsize)?);
+    }
+    Ok(())
+}
+
+///Read back how frames are laid out, and the settings writers are given, after the frame size changed
+fn frame_settings<T: Camera>(
+    cam: &T,
+    pixel_type: PixelType,
+) -> Result<(SpoolLayout, AcquisitionInfo), i32> {
+    let [width, height] = cam.get_resolution()?;
+    let layout = SpoolLayout {
+        width: width as usize,
+        height: height as usize,
+        rowbytes: cam.get_buffer_rowbytes()?,
+        frame_bytes: cam.get_framebytes()?,
+        pixel_type,
+    };
+    Ok((layout, AcquisitionInfo::from_camera(cam)?))
+}
+///Hand the settings read back after a change to `apply`, even if the change failed since it may have
+///been partly applied. If they couldn't be read nothing is applied and the read error is returned,
+///unless the change had already failed
+fn refresh<R, S>(
+    result: Result<R, i32>,
+    read: Result<S, i32>,
+    apply: impl FnOnce(S),
+) -> Result<R, i32> {
+    match read {
+        Ok(settings) => {
+            apply(settings);
+            result
+        }
+        Err(e) => result.and(Err(e)),
+    }
+}
+
+/// How often the camera should deliver a frame with its current settings, `None` if frames come from a
+/// trigger and can arrive whenever
+fn expected_frame_interval<T: Camera>(cam: &T, trigger: Option<TriggerSource>) -> Option<Duration> {
+    match trigger {
+        Some(TriggerSource::Internal) => cam
+            .dcamprop_getvalue(bindings::_DCAMIDPROP_DCAM_IDPROP_INTERNAL_FRAMEINTERVAL)
+            //not every camera reports its frame interval, it can't be shorter than the exposure
+            .or_else(|_| cam.get_exposure())
+            .ok()
+            .and_then(|interval| Duration::try_from_secs_f64(interval).ok()),
+        _ => None,
+    }
+}
 ///Stream from a camera with type `T` and API index `camid`. `bufsize` is the size of the image buffer
 ///as well as the size of the buffer the frames are written to by the thread spawned here.
 fn stream<T: Camera>(
@@ -434,16 +1092,26 @@ fn stream<T: Camera>(
     bufsize: usize,
     exposure: f64,
     resolution: [usize; 2],
-    mut frame_tx: Sender<Frame>,
+    pixel_type: PixelType,
+    mut recording: Option<Arc<Recording>>,
+    reconnect: Option<ReconnectPolicy>,
 ) -> DcamStream {
     //build our channels
-    //let (frame_tx, frame_rx) = sync_channel::<ImageBuffer<Luma<u16>, Vec<u16>>>(bufsize);
     let (control_tx, control_rx) = channel::<DcamStreamMessage>();
+    let stats = StatsHandle::new(Duration::from_secs(5));
+    let recorder = stats.clone();
+    #[cfg(feature = "ralston")]
+    let output_stats = Arc::new(output::OutputCounters::default());
+    #[cfg(feature = "ralston")]
+    let output_counters = output_stats.clone();
     //make a copy of our camid
     //spawn a thread that initializes the camera and starts shoving frames into frametx
     let thread_handle = thread::spawn(move || {
-        let api = DcamAPI::connect().expect("couldn't communicate with API");
-        let cam = api
+        //everything logged by the capture thread belongs to this stream
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("dcam_stream", camid, bufsize).entered();
+        let mut api = DcamAPI::connect().expect("couldn't communicate with API");
+        let mut cam = api
             .open_cam::<T>(camid)
             .expect("Couldn't get camera handle");
         //update our capture settings here
@@ -451,70 +1119,626 @@ fn stream<T: Camera>(
             .expect("couldn't change exposure");
         cam.set_resolution(resolution)
             .expect("couldn't change resolution");
-        let framebuffer = cam.attach_buffer(bufsize).expect("couldn't attach buffer");
+        cam.set_pixel_type(pixel_type)
+            .expect("couldn't change pixel type");
+        let buffer = cam.attach_buffer(bufsize).expect("couldn't attach buffer");
         //get our image size
         let imsize = cam.get_resolution().expect("couldn't get image resolution");
-        //get a wait handle
-        let mut dws = bindings::DCAMWAIT_START::new();
-        let hwait = framebuffer
-            .get_wait_handle()
-            .expect("Couldn't get wait handle");
-        //start capturing
-        //pick up here, add logic for safely stopping the thread through controlrx, return the struct
-        let err = unsafe {
-            bindings::dcamcap_start(
-                framebuffer.camera_handle,
-                bindings::DCAMCAP_START_DCAMCAP_START_SEQUENCE,
-            )
+        //we need the row stride to unpack anything that isn't 16 bit
+        let rowbytes = cam
+            .get_buffer_rowbytes()
+            .expect("couldn't get buffer row size");
+        //writers need to know how the data was captured
+        let mut info = AcquisitionInfo::from_camera(&cam).expect("couldn't read camera settings");
+        let mut metadata_tx: Option<Sender<FrameMetadata>> = None;
+        let mut connection_tx: Option<Sender<ConnectionEvent>> = None;
+        //the trigger source isn't part of `info` but has to be put back if the camera reconnects
+        let mut trigger = cam.get_trigger_source().ok();
+        //messages which arrived while the camera was gone
+        let mut pending: VecDeque<DcamStreamMessage> = VecDeque::new();
+        //reading the temperature every frame would slow us down, once a second is plenty
+        let mut last_temperature_read = Instant::now();
+        let mut last_framestamp: Option<i32> = None;
+        let mut lost_frames: u64 = 0;
+        //writers are kept with the number `add_writer` gave them
+        let mut writers: Vec<(usize, Box<dyn FrameWriter>)> = Vec::new();
+        let mut writer_error_tx: Option<Sender<WriterError>> = None;
+        //spools write on their own threads, we only copy frames out of the buffer for them
+        let mut spools: Vec<(usize, AsyncWriter)> = Vec::new();
+        let mut snapshots: Vec<(PathBuf, Sender<Result<(), SnapshotError>>)> = Vec::new();
+        let mut subscribers: Vec<Subscriber> = Vec::new();
+        //frames for the ralston consumer go through a bounded queue
+        #[cfg(feature = "ralston")]
+        let mut output_queue = (bufsize, OverflowPolicy::Block);
+        #[cfg(feature = "ralston")]
+        let mut consumer: Option<Sender<Frame>> = None;
+        #[cfg(feature = "ralston")]
+        let mut output: Option<output::Output> = None;
+        //how frames are laid out in the buffer, spools get them exactly like this
+        let mut layout = SpoolLayout {
+            width: imsize[0] as usize,
+            height: imsize[1] as usize,
+            rowbytes,
+            frame_bytes: buffer.frame_size,
+            pixel_type,
         };
-        assert_eq!(1, err, "couldn't start acquisition");
+        //get a wait handle. Don't wait forever, otherwise we'd never see messages
+        //(like a software trigger) while the camera is waiting to be triggered
+        let mut dws = bindings::DCAMWAIT_START::with_event(
+            bindings::DCAMWAIT_EVENT_DCAMWAIT_CAPEVENT_FRAMEREADY,
+            100,
+        );
+        let mut hwait = buffer.get_wait_handle().expect("Couldn't get wait handle");
+        //settings which change the frame size release the buffer, if a new one can't be attached
+        //we're left paused without one
+        let mut framebuffer = Some(buffer);
+        //start capturing
+        start_sequence(&cam, recording.as_deref()).expect("couldn't start acquisition");
+        recorder.capture_started(expected_frame_interval(&cam, trigger));
+        let mut paused = false;
         //Start a timer for frame timestamps
         let start_time = Instant::now();
+        //settings which change how often frames arrive, or a restart, reset the frame timeout
+        let mut retimed = false;
         loop {
+            //while paused there's nothing to do until we get a message
+            let message = if let Some(message) = pending.pop_front() {
+                Ok(message)
+            } else if paused {
+                control_rx.recv().map_err(|_| TryRecvError::Disconnected)
+            } else {
+                control_rx.try_recv()
+            };
             //check to see if we've been asked to stop
-            match control_rx.try_recv() {
+            match message {
                 //No messages, channel still open so we continue
                 Err(TryRecvError::Empty) => {}
                 //stop if the channel is disconnected
                 Err(TryRecvError::Disconnected) => break,
                 //stop if we've been asked to
                 Ok(DcamStreamMessage::Stop) => break,
+                //stop capturing but keep the buffer and wait handle around
+                Ok(DcamStreamMessage::Pause(reply)) => {
+                    if paused {
+                        reply(Ok(()));
+                    } else {
+                        let result = cam.dcamcap_stop();
+                        paused = result.is_ok();
+                        reply(result);
+                    }
+                }
+                Ok(DcamStreamMessage::Resume(reply)) => {
+                    if paused {
+                        let result = attach_if_released(&cam, &mut framebuffer, bufsize)
+                            .and_then(|()| start_sequence(&cam, recording.as_deref()));
+                        paused = result.is_err();
+                        retimed = true;
+                        reply(result);
+                    } else {
+                        reply(Ok(()));
+                    }
+                }
                 //change our consumer
-                Ok(DcamStreamMessage::ChangeConsumer(new_tx)) => frame_tx = new_tx,
+                #[cfg(feature = "ralston")]
+                Ok(DcamStreamMessage::ChangeConsumer(new_tx)) => {
+                    //the old consumer gets everything that was already queued for it
+                    if let Some(old) = output.take() {
+                        old.close();
+                    }
+                    let (capacity, policy) = output_queue;
+                    output = Some(output::Output::connect(
+                        new_tx.clone(),
+                        capacity,
+                        policy,
+                        &output_counters,
+                    ));
+                    consumer = Some(new_tx);
+                }
+                //reconnect the current consumer through a new queue
+                #[cfg(feature = "ralston")]
+                Ok(DcamStreamMessage::SetOutputQueue(capacity, policy)) => {
+                    output_queue = (capacity, policy);
+                    if let Some(tx) = &consumer {
+                        if let Some(old) = output.take() {
+                            old.close();
+                        }
+                        output = Some(output::Output::connect(
+                            tx.clone(),
+                            capacity,
+                            policy,
+                            &output_counters,
+                        ));
+                    }
+                }
+                //hand frames to someone else as well
+                Ok(DcamStreamMessage::Subscribe(subscriber)) => subscribers.push(subscriber),
+                //the exposure can usually be changed without stopping the capture
+                Ok(DcamStreamMessage::SetExposure(exposure, reply)) => {
+                    let result = reconfigure(
+                        &cam,
+                        &mut framebuffer,
+                        bufsize,
+                        recording.as_deref(),
+                        &mut paused,
+                        false,
+                        &[bindings::_DCAMIDPROP_DCAM_IDPROP_EXPOSURETIME],
+                        |cam| cam.set_exposure(exposure),
+                    );
+                    retimed = true;
+                    reply(refresh(result, AcquisitionInfo::from_camera(&cam), |new| {
+                        info = new
+                    }));
+                }
+                //writers and spools expect every frame to be the same size
+                Ok(DcamStreamMessage::SetRoi(roi, reply))
+                    if (!writers.is_empty() || !spools.is_empty()) && roi[2..] != info.roi[2..] =>
+                {
+                    reply(Err(bindings::DCAMERR_DCAMERR_BUSY))
+                }
+                Ok(DcamStreamMessage::SetBinning(binning, reply))
+                    if (!writers.is_empty() || !spools.is_empty()) && binning != info.binning =>
+                {
+                    reply(Err(bindings::DCAMERR_DCAMERR_BUSY))
+                }
+                Ok(DcamStreamMessage::SetRoi(roi, reply)) => {
+                    let result = reconfigure(
+                        &cam,
+                        &mut framebuffer,
+                        bufsize,
+                        recording.as_deref(),
+                        &mut paused,
+                        //moving the ROI without changing its size keeps the frame size
+                        roi[2..] != info.roi[2..],
+                        &[
+                            bindings::_DCAMIDPROP_DCAM_IDPROP_SUBARRAYMODE,
+                            bindings::_DCAMIDPROP_DCAM_IDPROP_SUBARRAYHPOS,
+                            bindings::_DCAMIDPROP_DCAM_IDPROP_SUBARRAYVPOS,
+                            bindings::_DCAMIDPROP_DCAM_IDPROP_SUBARRAYHSIZE,
+                            bindings::_DCAMIDPROP_DCAM_IDPROP_SUBARRAYVSIZE,
+                        ],
+                        |cam| cam.set_roi(roi),
+                    );
+                    retimed = true;
+                    reply(refresh(result, frame_settings(&cam, pixel_type), |new| {
+                        (layout, info) = new
+                    }));
+                }
+                Ok(DcamStreamMessage::SetBinning(binning, reply)) => {
+                    let result = reconfigure(
+                        &cam,
+                        &mut framebuffer,
+                        bufsize,
+                        recording.as_deref(),
+                        &mut paused,
+                        binning != info.binning,
+                        &[bindings::_DCAMIDPROP_DCAM_IDPROP_BINNING],
+                        |cam| cam.set_binning(binning),
+                    );
+                    retimed = true;
+                    reply(refresh(result, frame_settings(&cam, pixel_type), |new| {
+                        (layout, info) = new
+                    }));
+                }
+                Ok(DcamStreamMessage::SetTrigger(source, reply)) => {
+                    let result = reconfigure(
+                        &cam,
+                        &mut framebuffer,
+                        bufsize,
+                        recording.as_deref(),
+                        &mut paused,
+                        false,
+                        &[bindings::_DCAMIDPROP_DCAM_IDPROP_TRIGGERSOURCE],
+                        |cam| cam.set_trigger_source(source),
+                    );
+                    if let Ok(source) = result {
+                        trigger = Some(source);
+                    }
+                    retimed = true;
+                    reply(refresh(result, AcquisitionInfo::from_camera(&cam), |new| {
+                        info = new
+                    }));
+                }
+                Ok(DcamStreamMessage::FireTrigger(reply)) => reply(cam.dcamcap_firetrigger()),
+                Ok(DcamStreamMessage::GetStatus(reply)) => reply(cam.dcamcap_status()),
+                Ok(DcamStreamMessage::OpenWait(reply)) => reply(WaitHandle::open(cam.handle())),
+                //start saving frames
+                Ok(DcamStreamMessage::AddWriter(id, mut writer)) => match writer.begin(&info) {
+                    Ok(()) => writers.push((id, writer)),
+                    Err(error) => writer::report(
+                        &mut writer_error_tx,
+                        WriterError {
+                            writer: id,
+                            stage: WriteStage::Begin,
+                            error,
+                        },
+                    ),
+                },
+                //start spooling raw frames
+                Ok(DcamStreamMessage::AddSpool(id, mut spool)) => {
+                    match spool.begin_raw(&info, layout) {
+                        Ok(()) => spools.push((
+                            id,
+                            AsyncWriter::spool(*spool, bufsize, OverflowPolicy::Block),
+                        )),
+                        Err(error) => writer::report(
+                            &mut writer_error_tx,
+                            WriterError {
+                                writer: id,
+                                stage: WriteStage::Begin,
+                                error,
+                            },
+                        ),
+                    }
+                }
+                //save the next frame
+                Ok(DcamStreamMessage::Snapshot(path, reply_tx)) => snapshots.push((path, reply_tx)),
+                //send metadata somewhere else (or nowhere)
+                Ok(DcamStreamMessage::ChangeMetadataConsumer(new_tx)) => metadata_tx = new_tx,
+                Ok(DcamStreamMessage::ChangeConnectionConsumer(new_tx)) => connection_tx = new_tx,
+                Ok(DcamStreamMessage::ChangeWriterErrorConsumer(new_tx)) => {
+                    writer_error_tx = new_tx
+                }
+            }
+            if retimed {
+                recorder.capture_started(expected_frame_interval(&cam, trigger));
+                retimed = false;
+            }
+            if paused {
+                continue;
             }
             let err = unsafe {
                 // Wait for the API to tell us about a new frame
                 bindings::dcamwait_start(hwait, &mut dws)
             };
-            assert_eq!(1, err);
+            if err == bindings::DCAMERR_DCAMERR_TIMEOUT {
+                //no frame yet, go check our messages again
+                log_event!(TRACE, "timed out waiting for a frame");
+                recorder.record_timeout();
+                continue;
+            }
+            if err != 1 {
+                let error = DcamError(err);
+                let policy = match reconnect {
+                    Some(policy) if error.is_connection_lost() => policy,
+                    _ => panic!("failed waiting for a frame: {}", error),
+                };
+                reconnect::notify(&mut connection_tx, ConnectionEvent::Lost(error));
+                //let go of the old camera, the API has to be restarted to find it again
+                unsafe {
+                    bindings::dcamwait_close(hwait);
+                }
+                drop(framebuffer.take());
+                drop(cam);
+                //a recording belongs to the API it was opened under and can't follow the camera
+                //into a new one, stop rather than carry on capturing without it
+                if let Some(recording) = recording.take() {
+                    drop(recording);
+                    drop(api);
+                    log_event!(
+                        ERROR,
+                        error = %error,
+                        "lost the camera while recording, stopping"
+                    );
+                    reconnect::notify(&mut connection_tx, ConnectionEvent::GaveUp(error));
+                    finish_outputs(
+                        writers,
+                        spools,
+                        #[cfg(feature = "ralston")]
+                        output,
+                        &mut writer_error_tx,
+                    );
+                    return;
+                }
+                drop(api);
+                log_event!(WARN, error = %error, "lost the camera, reconnecting");
+                let lost_at = Instant::now();
+                let mut attempt = 0;
+                loop {
+                    //a stop request still stops us, everything else waits for the camera
+                    let deadline = Instant::now() + policy.retry_interval;
+                    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+                        match control_rx.recv_timeout(remaining) {
+                            Ok(DcamStreamMessage::Stop) | Err(RecvTimeoutError::Disconnected) => {
+                                finish_outputs(
+                                    writers,
+                                    spools,
+                                    #[cfg(feature = "ralston")]
+                                    output,
+                                    &mut writer_error_tx,
+                                );
+                                return;
+                            }
+                            Ok(message) => pending.push_back(message),
+                            Err(RecvTimeoutError::Timeout) => break,
+                        }
+                    }
+                    attempt += 1;
+                    log_event!(DEBUG, attempt, "looking for the camera");
+                    reconnect::notify(
+                        &mut connection_tx,
+                        ConnectionEvent::Reconnecting { attempt },
+                    );
+                    match reopen::<T>(&info, trigger, bufsize) {
+                        Ok((new_api, new_cam, buffer, new_hwait)) => {
+                            (api, cam, hwait) = (new_api, new_cam, new_hwait);
+                            framebuffer = Some(buffer);
+                            break;
+                        }
+                        Err(e) if policy.max_attempts.is_some_and(|max| attempt >= max) => {
+                            log_event!(
+                                ERROR,
+                                attempt,
+                                error = %DcamError(e),
+                                "couldn't get the camera back, stopping"
+                            );
+                            reconnect::notify(
+                                &mut connection_tx,
+                                ConnectionEvent::GaveUp(DcamError(e)),
+                            );
+                            finish_outputs(
+                                writers,
+                                spools,
+                                #[cfg(feature = "ralston")]
+                                output,
+                                &mut writer_error_tx,
+                            );
+                            return;
+                        }
+                        //only logged, the next attempt may well work
+                        Err(_e) => {
+                            log_event!(
+                                WARN,
+                                attempt,
+                                error = %DcamError(_e),
+                                "camera isn't back yet"
+                            );
+                        }
+                    }
+                }
+                log_event!(
+                    INFO,
+                    attempts = attempt,
+                    downtime = ?lost_at.elapsed(),
+                    "camera reconnected"
+                );
+                reconnect::notify(
+                    &mut connection_tx,
+                    ConnectionEvent::Reconnected {
+                        attempts: attempt,
+                        downtime: lost_at.elapsed(),
+                    },
+                );
+                //the camera counts frames from zero again
+                last_framestamp = None;
+                match frame_settings(&cam, pixel_type) {
+                    Ok(new) => (layout, info) = new,
+                    //the settings were put back as they were, so the old ones are still right
+                    Err(_e) => {
+                        log_event!(
+                            WARN,
+                            error = %DcamError(_e),
+                            "couldn't read camera settings after reconnecting"
+                        );
+                    }
+                }
+                retimed = true;
+                continue;
+            }
+            let ready = Instant::now();
+            //we only capture with a buffer attached
+            let buffer = framebuffer.as_ref().expect("capturing without a buffer");
+            let index = buffer
+                .most_recent_frame_index()
+                .expect("failed to find newest frame");
+            //copy the raw data for the spools before we spend any time converting it
+            if !spools.is_empty() {
+                let locked = buffer.lock_frame(index).expect("failed to lock frame");
+                spools.retain_mut(|(id, spool)| {
+                    match spool.write_raw(
+                        buffer.frame_bytes(index),
+                        locked.timestamp.as_duration(),
+                        locked.framestamp,
+                    ) {
+                        Ok(()) => true,
+                        Err(error) => {
+                            writer::report(
+                                &mut writer_error_tx,
+                                WriterError {
+                                    writer: *id,
+                                    stage: WriteStage::Frame(locked.framestamp),
+                                    error,
+                                },
+                            );
+                            false
+                        }
+                    }
+                });
+            }
             //grab the newest frame
-            let new_frame_raw = framebuffer
-                .copy_most_recent_frame()
+            let new_frame = buffer
+                .copy_frame(
+                    index,
+                    pixel_type,
+                    layout.width,
+                    layout.height,
+                    layout.rowbytes,
+                )
                 .expect("failed to copy frame");
-            //convert to an image
-            let new_frame = ImageBuffer::<Luma<u16>, Vec<u16>>::from_raw(
-                imsize[0] as u32,
-                imsize[1] as u32,
-                new_frame_raw,
-            )
-            .expect("failed to convert to image");
+            //the temperature may have changed since we started
+            if last_temperature_read.elapsed() >= Duration::from_secs(1) {
+                info.sensor_temperature = cam
+                    .dcamprop_getvalue(bindings::_DCAMIDPROP_DCAM_IDPROP_SENSORTEMPERATURE)
+                    .ok();
+                last_temperature_read = Instant::now();
+            }
+            //gaps in the framestamps are frames we never saw
+            let lost = match last_framestamp {
+                Some(last) => new_frame
+                    .framestamp()
+                    .wrapping_sub(last)
+                    .saturating_sub(1)
+                    .max(0) as u64,
+                None => 0,
+            };
+            lost_frames += lost;
+            if lost > 0 {
+                log_event!(WARN, lost, total = lost_frames, "frames lost");
+            }
+            last_framestamp = Some(new_frame.framestamp());
+            let elapsed = start_time.elapsed();
+            let metadata = FrameMetadata::new(
+                &info,
+                new_frame.timestamp(),
+                new_frame.framestamp(),
+                elapsed,
+                lost_frames,
+            );
+            let new_frame = new_frame.with_metadata(metadata.clone());
+            //encoding and saving the file would hold up the next frame, do it somewhere else
+            if !snapshots.is_empty() {
+                let requests = std::mem::take(&mut snapshots);
+                let (frame, info) = (new_frame.clone(), info.clone());
+                thread::spawn(move || {
+                    for (path, reply_tx) in requests {
+                        let saved = save_snapshot(path, &frame, &info);
+                        //nobody may be waiting anymore
+                        let _ = reply_tx.send(saved.map_err(SnapshotError::Io));
+                    }
+                });
+            }
+            //a writer which fails is dropped, it would most likely fail again on the next frame
+            writers.retain_mut(|(id, writer)| match writer.write_frame(&new_frame) {
+                Ok(()) => true,
+                Err(error) => {
+                    writer::report(
+                        &mut writer_error_tx,
+                        WriterError {
+                            writer: *id,
+                            stage: WriteStage::Frame(new_frame.framestamp()),
+                            error,
+                        },
+                    );
+                    false
+                }
+            });
+            subscribers.retain_mut(|subscriber| subscriber(&new_frame));
             //send the new frame down the buffer
-            match frame_tx.send(Frame::new(
-                start_time.elapsed(),
-                DynamicImage::ImageLuma16(new_frame),
-            )) {
-                Ok(()) => {}
-                Err(_) => panic!("Couldn't send new frame to buffer"),
+            #[cfg(feature = "ralston")]
+            if let Some(out) = &output {
+                if !out.publish(&new_frame) {
+                    //the consumer hung up or fell too far behind, keep capturing for everyone else
+                    if let Some(out) = output.take() {
+                        out.close();
+                    }
+                }
             }
+            //metadata goes out in the same order as the frames
+            if let Some(tx) = &metadata_tx {
+                if tx.send(metadata).is_err() {
+                    //the consumer hung up, stop sending
+                    metadata_tx = None;
+                }
+            }
+            #[cfg(feature = "ralston")]
+            let queue_depth = output.as_ref().map(|out| out.queued());
+            #[cfg(not(feature = "ralston"))]
+            let queue_depth = None;
+            log_event!(
+                TRACE,
+                index,
+                framestamp = new_frame.framestamp(),
+                lost,
+                latency = ?ready.elapsed(),
+                "frame delivered"
+            );
+            recorder.record_frame(ready, layout.frame_bytes, lost, queue_depth);
         }
         //make sure the camera is stopped
-        let err = unsafe { bindings::dcamcap_stop(framebuffer.camera_handle) };
-        assert_eq!(1, err, "couldn't stop acquisition");
+        if !paused {
+            let err = unsafe { bindings::dcamcap_stop(cam.handle()) };
+            assert_eq!(1, err, "couldn't stop acquisition");
+            log_event!(INFO, "capture stopped");
+        }
+        finish_outputs(
+            writers,
+            spools,
+            #[cfg(feature = "ralston")]
+            output,
+            &mut writer_error_tx,
+        );
     });
     DcamStream {
         control_tx,
         thread_handle,
+        stats,
+        #[cfg(feature = "ralston")]
+        output: output_stats,
+        next_writer: 0,
+    }
+}
+
+/// Finish everything the capture thread was feeding once capture has ended
+fn finish_outputs(
+    writers: Vec<(usize, Box<dyn FrameWriter>)>,
+    spools: Vec<(usize, AsyncWriter)>,
+    #[cfg(feature = "ralston")] output: Option<output::Output>,
+    writer_errors: &mut Option<Sender<WriterError>>,
+) {
+    for (id, mut writer) in writers {
+        if let Err(error) = writer.finish() {
+            writer::report(
+                writer_errors,
+                WriterError {
+                    writer: id,
+                    stage: WriteStage::Finish,
+                    error,
+                },
+            );
+        }
+    }
+    for (id, mut spool) in spools {
+        if let Err(error) = spool.finish() {
+            writer::report(
+                writer_errors,
+                WriterError {
+                    writer: id,
+                    stage: WriteStage::Finish,
+                    error,
+                },
+            );
+        }
+    }
+    #[cfg(feature = "ralston")]
+    if let Some(out) = output {
+        out.close();
+    }
+}
+
+/// Find the camera with the serial number in `info` again after it went away, put back the settings it
+/// had and start capturing
+fn reopen<T: Camera>(
+    info: &AcquisitionInfo,
+    trigger: Option<TriggerSource>,
+    bufsize: usize,
+) -> Result<(DcamAPI, T, FrameBuffer, bindings::HDCAMWAIT), i32> {
+    let api = DcamAPI::connect()?;
+    let cam = api.open_serial::<T>(&info.serial_number)?;
+    cam.set_pixel_type(info.pixel_type)?;
+    cam.set_binning(info.binning)?;
+    cam.set_roi(info.roi)?;
+    if let Some(source) = trigger {
+        cam.set_trigger_source(source)?;
     }
+    cam.set_exposure(info.exposure)?;
+    let framebuffer = cam.attach_buffer(bufsize)?;
+    let hwait = framebuffer.get_wait_handle()?;
+    if let Err(e) = start_sequence(&cam, None) {
+        unsafe {
+            bindings::dcamwait_close(hwait);
+        }
+        return Err(e);
+    }
+    Ok((api, cam, framebuffer, hwait))
 }
 
 impl DcamStream {
@@ -529,6 +1753,181 @@ impl DcamStream {
     }
 }
 
+impl DcamStream {
+    /// Save every frame captured from now on with `writer`. `begin` is called with the camera's
+    /// settings before the first frame, `finish` is called when the stream is stopped. Returns the
+    /// number `WriterError` uses for this writer if it fails
+    pub fn add_writer(&mut self, writer: Box<dyn FrameWriter>) -> usize {
+        let id = self.next_writer;
+        self.next_writer += 1;
+        self.control_tx
+            .send(DcamStreamMessage::AddWriter(id, writer))
+            .expect("Couldn't communicate with frame grabber");
+        id
+    }
+    /// Send the `FrameMetadata` of every frame captured from now on to `sender`, in the same order
+    /// as the frames are sent to the frame consumer. Pass `None` to stop sending metadata
+    pub fn change_metadata_consumer(&mut self, sender: Option<Sender<FrameMetadata>>) {
+        self.control_tx
+            .send(DcamStreamMessage::ChangeMetadataConsumer(sender))
+            .expect("Couldn't communicate with frame grabber");
+    }
+    /// Frame rate, throughput and latency over the last few seconds, the handle can be kept and read
+    /// from another thread while the stream runs
+    pub fn stats(&self) -> StatsHandle {
+        self.stats.clone()
+    }
+    /// Send a `WriterError` to `sender` whenever a writer or spool fails and is dropped, errors are only logged
+    /// until this is called. Pass `None` to stop sending errors
+    pub fn change_writer_error_consumer(&mut self, sender: Option<Sender<WriterError>>) {
+        self.control_tx
+            .send(DcamStreamMessage::ChangeWriterErrorConsumer(sender))
+            .expect("Couldn't communicate with frame grabber");
+    }
+    /// Send a `ConnectionEvent` to `sender` whenever the camera goes away or comes back.
+    /// Pass `None` to stop sending events
+    pub fn change_connection_consumer(&mut self, sender: Option<Sender<ConnectionEvent>>) {
+        self.control_tx
+            .send(DcamStreamMessage::ChangeConnectionConsumer(sender))
+            .expect("Couldn't communicate with frame grabber");
+    }
+    /// Save the next frame to `path` as a 16 bit PNG or TIFF, chosen by the extension, with the
+    /// camera's settings embedded in the file. Blocks until the frame has been saved, the file is
+    /// written on a separate thread so capture carries on in the meantime
+    pub fn snapshot<P: AsRef<Path>>(&self, path: P) -> Result<(), SnapshotError> {
+        let (reply_tx, reply_rx) = channel();
+        self.control_tx
+            .send(DcamStreamMessage::Snapshot(
+                path.as_ref().to_path_buf(),
+                reply_tx,
+            ))
+            .expect("Couldn't communicate with frame grabber");
+        match reply_rx.recv() {
+            Ok(result) => result,
+            Err(_) => Err(SnapshotError::Io(io::Error::other(
+                "stream stopped before a frame was captured",
+            ))),
+        }
+    }
+    /// Call `subscriber` with every frame captured from now on, until it returns `false`.
+    /// This runs on the capture thread, so it should hand the frame off rather than process it
+    pub fn subscribe<F: FnMut(&DcamFrame) -> bool + Send + 'static>(&mut self, subscriber: F) {
+        self.control_tx
+            .send(DcamStreamMessage::Subscribe(Box::new(subscriber)))
+            .expect("Couldn't communicate with frame grabber");
+    }
+    /// Send a request to the capture thread and block until it answers
+    fn request<R: Send + 'static>(&self, message: impl FnOnce(Reply<R>) -> DcamStreamMessage) -> R {
+        let (reply_tx, reply_rx) = channel();
+        self.control_tx
+            .send(message(Box::new(move |result| {
+                let _ = reply_tx.send(result);
+            })))
+            .expect("Couldn't communicate with frame grabber");
+        reply_rx
+            .recv()
+            .expect("Couldn't communicate with frame grabber")
+    }
+    /// Get a queue of every frame captured from now on, holding at most `capacity` frames. `policy`
+    /// decides what happens when the queue is full, `OverflowPolicy::Block` holds up the capture thread
+    /// so use it only for consumers which have to see every frame. Drop the `Subscription` to unsubscribe
+    pub fn add_subscriber(&mut self, capacity: usize, policy: OverflowPolicy) -> Subscription {
+        let (publisher, subscription) =
+            subscription::subscription(capacity, policy, Arc::default());
+        self.subscribe(move |frame| publisher.publish(frame));
+        subscription
+    }
+    /// Get a reduced rate 8 bit preview of the stream for display, see `PreviewSettings`. Only the
+    /// newest frame is kept waiting, frames going to writers and other subscribers aren't affected
+    pub fn add_preview(&mut self, settings: PreviewSettings) -> Preview {
+        let (publisher, subscription) =
+            subscription::subscription(1, OverflowPolicy::DropOldest, Arc::default());
+        let interval = settings.interval();
+        let mut last_sent: Option<Instant> = None;
+        self.subscribe(move |frame| {
+            //the rate check is all that happens on the capture thread
+            if last_sent.is_some_and(|last| last.elapsed() < interval) {
+                return true;
+            }
+            last_sent = Some(Instant::now());
+            publisher.publish(frame)
+        });
+        Preview::new(subscription, &settings)
+    }
+    /// Change the exposure while the stream is running, returns the exposure the camera actually used.
+    /// Blocks until the next time the capture thread checks its messages. If capture has to be stopped
+    /// to change a setting and can't be started again the error is returned and the stream is left
+    /// paused, this goes for every setting. `resume` tries again
+    pub fn set_exposure(&mut self, exposure: f64) -> Result<f64, i32> {
+        self.request(|reply| DcamStreamMessage::SetExposure(exposure, reply))
+    }
+    /// Change the region of interest `[hpos, vpos, hsize, vsize]` while the stream is running, returns the
+    /// region the camera actually used. If the camera can't change it while capturing, capture is briefly
+    /// stopped and restarted. Changing the size fails with `DCAMERR_BUSY` while writers or spools are attached
+    pub fn set_roi(&mut self, roi: [usize; 4]) -> Result<[usize; 4], i32> {
+        self.request(|reply| DcamStreamMessage::SetRoi(roi, reply))
+    }
+    /// Change the binning while the stream is running, returns the binning the camera actually used.
+    /// This fails with `DCAMERR_BUSY` while writers or spools are attached
+    pub fn set_binning(&mut self, binning: i32) -> Result<i32, i32> {
+        self.request(|reply| DcamStreamMessage::SetBinning(binning, reply))
+    }
+    /// Change where the camera gets its trigger from while the stream is running
+    pub fn set_trigger_source(&mut self, source: TriggerSource) -> Result<TriggerSource, i32> {
+        self.request(|reply| DcamStreamMessage::SetTrigger(source, reply))
+    }
+    /// Send a software trigger to the camera, this only starts an exposure if the
+    /// camera's trigger source is set to software
+    pub fn fire_trigger(&self) -> Result<(), i32> {
+        self.request(DcamStreamMessage::FireTrigger)
+    }
+    /// Ask the camera what it's doing, this is `CaptureStatus::Ready` while the stream is paused
+    pub fn capture_status(&self) -> Result<CaptureStatus, i32> {
+        self.request(DcamStreamMessage::GetStatus)
+    }
+    /// Open a wait handle on the stream's camera, e.g. to wait for `WaitEvents::EXPOSURE_END` on
+    /// another thread. The capture thread keeps waiting for frames on its own handle. If the camera
+    /// is reconnected the handle returns `DCAMERR_INVALIDWAITHANDLE`, open a new one
+    pub fn wait_handle(&self) -> Result<WaitHandle<'_>, DcamError> {
+        Ok(self.request(DcamStreamMessage::OpenWait)?)
+    }
+    /// Stop capturing without releasing the camera or buffer, frames stop arriving until `resume`
+    /// is called. Settings can still be changed while paused, snapshots wait for the next frame
+    pub fn pause(&mut self) -> Result<(), i32> {
+        self.request(DcamStreamMessage::Pause)
+    }
+    /// Start capturing again after `pause`, frame timestamps continue from where they left off
+    pub fn resume(&mut self) -> Result<(), i32> {
+        self.request(DcamStreamMessage::Resume)
+    }
+    /// Change how many frames can wait for the ralston consumer and what happens when it falls further
+    /// behind than that, frames already waiting are still delivered
+    #[cfg(feature = "ralston")]
+    pub fn set_output_queue(&mut self, capacity: usize, policy: OverflowPolicy) {
+        self.control_tx
+            .send(DcamStreamMessage::SetOutputQueue(capacity, policy))
+            .expect("Couldn't communicate with frame grabber");
+    }
+    /// How the ralston consumer is keeping up
+    #[cfg(feature = "ralston")]
+    pub fn output_stats(&self) -> OutputStats {
+        self.output.stats()
+    }
+    /// Spool every frame captured from now on to `spool` without converting it,
+    /// the spool is finished when the stream is stopped. Frames are written on a separate thread, up to
+    /// `bufsize` of them can wait for the disk before capture is held up. Returns the number `WriterError`
+    /// uses for this spool if it fails, spools and writers are numbered together
+    pub fn spool_to(&mut self, spool: SpoolWriter) -> usize {
+        let id = self.next_writer;
+        self.next_writer += 1;
+        self.control_tx
+            .send(DcamStreamMessage::AddSpool(id, Box::new(spool)))
+            .expect("Couldn't communicate with frame grabber");
+        id
+    }
+}
+
+#[cfg(feature = "ralston")]
 impl FrameStream for DcamStream {
     fn stop(self) {
         Self::stop(self);
@@ -539,3 +1938,30 @@ impl FrameStream for DcamStream {
             .expect("Couldn't communicate with frame grabber");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem;
+
+    #[test]
+    fn copy_frame_as_mono16_strips_padding() {
+        //two 3 pixel rows with one pixel of padding each, in a buffer nobody attached
+        let buffer = FrameBuffer {
+            camera_handle: ptr::null_mut(),
+            frame_size: 16,
+            num_frames: 1,
+            buffer: vec![1, 2, 3, 0xEEEE, 4, 5, 6, 0xEEEE],
+        };
+        assert_eq!(
+            buffer.copy_frame_as(0, PixelType::Mono16, 3, 2, 8),
+            vec![1, 2, 3, 4, 5, 6]
+        );
+        assert_eq!(
+            buffer.copy_frame_as(0, PixelType::Mono16, 4, 2, 8),
+            vec![1, 2, 3, 0xEEEE, 4, 5, 6, 0xEEEE]
+        );
+        //there is no camera to release the buffer from
+        mem::forget(buffer);
+    }
+}
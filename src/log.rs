@@ -0,0 +1,15 @@
+//! Structured logging through `tracing`, enabled with the `tracing` feature.
+//!
+//! `log_event!` takes a level followed by anything `tracing::event!` accepts. Without the feature it
+//! expands to nothing and its arguments are never evaluated, so call sites don't need their own `cfg`s.
+//! API calls and property access are logged at `DEBUG` and `TRACE`, capture start and stop at `INFO`
+//! and each frame at `TRACE` inside a `dcam_stream` span
+
+macro_rules! log_event {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        {
+            tracing::event!(tracing::Level::$level, $($arg)+);
+        }
+    };
+}
@@ -0,0 +1,499 @@
+//! Capture from several cameras at the same instants.
+//!
+//! `MultiCamera` opens its cameras through one `DcamAPI`. `configure` makes one of them the master, which
+//! runs from its own clock and puts out a pulse during each exposure, and the others slaves started by
+//! that pulse on their trigger input, the cables have to be connected to match. `capture` starts the
+//! slaves before the master so none of them miss the first pulse and hands out a `FrameSet` with one
+//! frame from every camera per pulse. Frames are matched by framestamp counted from each camera's first
+//! frame and checked against the master's timestamps, or by timestamp within a tolerance. Frames with no
+//! partner from every other camera are thrown away and reported in `FrameSet::unmatched`
+
+use crate::capture::Capture;
+use crate::error::DcamError;
+use crate::frame::DcamFrame;
+use crate::trigger::TriggerSource;
+use crate::{bindings, Camera, DcamAPI};
+use std::collections::VecDeque;
+use std::mem;
+use std::time::Duration;
+
+/// How frames from different cameras are matched up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchBy {
+    /// frames with the same framestamp counted from each camera's first frame belong together.
+    /// A camera which misses a pulse counts the next one with the framestamp the missed one should have
+    /// had, so every set is also checked against the master: a frame more than half the master's frame
+    /// interval away from the master's frame goes to `FrameSet::unmatched` and the cameras are lined up
+    /// again. The first set waits for two frames from the master to measure its interval
+    Framestamp,
+    /// frames whose timestamps are no further apart than the tolerance belong together
+    Timestamp(Duration),
+}
+
+/// A frame which was thrown away because not every camera had a matching frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Unmatched {
+    /// index of the camera in the `MultiCamera`
+    pub camera: usize,
+    pub framestamp: i32,
+    pub timestamp: Duration,
+}
+
+/// One frame from every camera, captured at the same instant
+#[derive(Debug, Clone)]
+pub struct FrameSet {
+    /// frames in the same order as the cameras in the `MultiCamera`
+    pub frames: Vec<DcamFrame>,
+    /// frames thrown away since the last `FrameSet`
+    pub unmatched: Vec<Unmatched>,
+}
+
+/// Several cameras opened through one API handle
+pub struct MultiCamera<T: Camera> {
+    cameras: Vec<T>,
+    master: usize,
+    //fields are dropped in order, the cameras have to be closed before the API is released
+    _api: DcamAPI,
+}
+
+/// make sure `master` is one of the `count` cameras before any of them are opened
+fn check_master(master: usize, count: usize) -> Result<(), DcamError> {
+    if master >= count {
+        log_event!(ERROR, master, count, "master must be one of the cameras");
+        return Err(DcamError(bindings::DCAMERR_DCAMERR_INVALIDPARAM));
+    }
+    Ok(())
+}
+
+impl<T: Camera> MultiCamera<T> {
+    /// Open the cameras with API indices `cam_ids`, the camera at `cam_ids[master]` will be the master.
+    /// Fails with `DCAMERR_INVALIDPARAM` if `master` isn't one of them
+    pub fn open(cam_ids: &[i32], master: usize) -> Result<MultiCamera<T>, DcamError> {
+        check_master(master, cam_ids.len())?;
+        let api = DcamAPI::connect()?;
+        let cameras = cam_ids
+            .iter()
+            .map(|id| api.open_cam::<T>(*id))
+            .collect::<Result<Vec<T>, i32>>()?;
+        Ok(MultiCamera {
+            cameras,
+            master,
+            _api: api,
+        })
+    }
+    /// Open the cameras with serial numbers `serials`, the camera with `serials[master]` will be the master.
+    /// Serial numbers don't change when cameras are plugged into different ports
+    pub fn open_serials(serials: &[&str], master: usize) -> Result<MultiCamera<T>, DcamError> {
+        check_master(master, serials.len())?;
+        let api = DcamAPI::connect()?;
+        let cameras = serials
+            .iter()
+            .map(|serial| api.open_serial::<T>(serial))
+            .collect::<Result<Vec<T>, i32>>()?;
+        Ok(MultiCamera {
+            cameras,
+            master,
+            _api: api,
+        })
+    }
+    /// The cameras, in the order they were opened
+    pub fn cameras(&self) -> &[T] {
+        &self.cameras
+    }
+    /// The camera which triggers the others
+    pub fn master(&self) -> &T {
+        &self.cameras[self.master]
+    }
+    /// Set the same exposure time on every camera, returns the exposure each camera actually applied
+    pub fn set_exposure(&self, exposure: f64) -> Result<Vec<f64>, i32> {
+        self.cameras
+            .iter()
+            .map(|cam| cam.set_exposure(exposure))
+            .collect()
+    }
+    /// Set up the trigger wiring. The master is triggered from `master_source`, `TriggerSource::Internal`
+    /// to free run or `TriggerSource::MasterPulse` to run from its pulse generator, and puts out a
+    /// positive pulse on its first output trigger connector for each exposure. The other cameras start
+    /// an exposure on each rising edge of their trigger input
+    pub fn configure(&self, master_source: TriggerSource) -> Result<(), i32> {
+        for (i, cam) in self.cameras.iter().enumerate() {
+            if i == self.master {
+                cam.set_trigger_source(master_source)?;
+                cam.dcamprop_setvalue(
+                    bindings::_DCAMIDPROP_DCAM_IDPROP_OUTPUTTRIGGER_KIND,
+                    bindings::_DCAMPROPMODEVALUE_DCAMPROP_OUTPUTTRIGGER_KIND__EXPOSURE as f64,
+                )?;
+                cam.dcamprop_setvalue(
+                    bindings::_DCAMIDPROP_DCAM_IDPROP_OUTPUTTRIGGER_POLARITY,
+                    bindings::_DCAMPROPMODEVALUE_DCAMPROP_OUTPUTTRIGGER_POLARITY__POSITIVE as f64,
+                )?;
+            } else {
+                cam.set_trigger_source(TriggerSource::External)?;
+                cam.dcamprop_setvalue(
+                    bindings::_DCAMIDPROP_DCAM_IDPROP_TRIGGERACTIVE,
+                    bindings::_DCAMPROPMODEVALUE_DCAMPROP_TRIGGERACTIVE__EDGE as f64,
+                )?;
+                cam.dcamprop_setvalue(
+                    bindings::_DCAMIDPROP_DCAM_IDPROP_TRIGGERPOLARITY,
+                    bindings::_DCAMPROPMODEVALUE_DCAMPROP_TRIGGERPOLARITY__POSITIVE as f64,
+                )?;
+            }
+        }
+        log_event!(
+            INFO,
+            master = self.master,
+            cameras = self.cameras.len(),
+            "cameras configured for synchronized capture"
+        );
+        Ok(())
+    }
+    /// Start a sequence capture into a `num_frames` long buffer on every camera, slaves first.
+    /// Each call to `next` on the returned `SyncedCapture` waits up to `timeout` for each camera's frame.
+    /// None of the cameras may have another buffer attached when this is called
+    pub fn capture(
+        &self,
+        num_frames: usize,
+        timeout: Duration,
+        match_by: MatchBy,
+    ) -> Result<SyncedCapture<'_>, DcamError> {
+        let mut captures: Vec<Option<Capture<'_>>> = self.cameras.iter().map(|_| None).collect();
+        //the slaves have to be waiting before the master sends its first pulse
+        let order = (0..self.cameras.len())
+            .filter(|i| *i != self.master)
+            .chain([self.master]);
+        for i in order {
+            captures[i] = Some(Capture::start(&self.cameras[i], num_frames, timeout)?);
+        }
+        Ok(SyncedCapture {
+            captures: captures.into_iter().flatten().collect(),
+            master: self.master,
+            matcher: Matcher::new(self.cameras.len(), self.master, match_by),
+        })
+    }
+}
+
+/// A running synchronized capture, stopped when dropped
+pub struct SyncedCapture<'a> {
+    captures: Vec<Capture<'a>>,
+    master: usize,
+    matcher: Matcher,
+}
+
+impl SyncedCapture<'_> {
+    /// Change how long `next` waits for each camera's frame before returning a timeout error
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        for capture in self.captures.iter_mut() {
+            capture.set_timeout(timeout);
+        }
+    }
+    /// Wait until every camera has a frame from the same instant
+    fn next_set(&mut self) -> Result<FrameSet, DcamError> {
+        loop {
+            //frames wait in each camera's buffer, so waiting on the cameras one at a time is fine
+            while let Some(camera) = self.matcher.missing() {
+                let frame = self.captures[camera].next().expect("captures never end")?;
+                self.matcher.push(camera, frame);
+            }
+            if let Some(set) = self.matcher.take_set() {
+                return Ok(set);
+            }
+        }
+    }
+}
+
+impl Iterator for SyncedCapture<'_> {
+    type Item = Result<FrameSet, DcamError>;
+    /// Wait for the next set of frames, this never returns `None`. If a camera's frame doesn't arrive
+    /// within the timeout this returns a timeout error, calling `next` again keeps waiting
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.next_set())
+    }
+}
+
+impl Drop for SyncedCapture<'_> {
+    fn drop(&mut self) {
+        //stop the master first so the slaves don't see a pulse after they've stopped
+        drop(self.captures.remove(self.master));
+    }
+}
+
+/// Lines up frames from several cameras
+struct Matcher {
+    match_by: MatchBy,
+    /// the camera the others are checked against
+    master: usize,
+    /// frames from each camera which haven't been matched yet
+    queues: Vec<VecDeque<DcamFrame>>,
+    /// framestamp of the first frame from each camera
+    first_framestamps: Vec<Option<i32>>,
+    /// pulses each camera missed, added to its framestamps to line it up with the others
+    offsets: Vec<i128>,
+    /// the newest framestamp and timestamp from the master
+    last_master: Option<(i32, Duration)>,
+    /// time between the master's frames
+    interval: Option<Duration>,
+    unmatched: Vec<Unmatched>,
+}
+
+impl Matcher {
+    fn new(cameras: usize, master: usize, match_by: MatchBy) -> Matcher {
+        Matcher {
+            match_by,
+            master,
+            queues: (0..cameras).map(|_| VecDeque::new()).collect(),
+            first_framestamps: vec![None; cameras],
+            offsets: vec![0; cameras],
+            last_master: None,
+            interval: None,
+            unmatched: Vec::new(),
+        }
+    }
+    /// A camera we need another frame from before we can match anything
+    fn missing(&self) -> Option<usize> {
+        //framestamps can't be checked until we know the master's frame interval
+        if self.match_by == MatchBy::Framestamp
+            && self.interval.is_none()
+            && self.queues[self.master].len() < 2
+        {
+            return Some(self.master);
+        }
+        self.queues.iter().position(|queue| queue.is_empty())
+    }
+    fn push(&mut self, camera: usize, frame: DcamFrame) {
+        self.first_framestamps[camera].get_or_insert(frame.framestamp());
+        if camera == self.master {
+            if let Some((framestamp, timestamp)) = self.last_master {
+                let frames = frame.framestamp().wrapping_sub(framestamp);
+                if frames > 0 && frame.timestamp() > timestamp {
+                    self.interval = Some((frame.timestamp() - timestamp) / frames as u32);
+                }
+            }
+            self.last_master = Some((frame.framestamp(), frame.timestamp()));
+        }
+        self.queues[camera].push_back(frame);
+    }
+    /// Where `frame` from `camera` falls in the sequence, frames match if these are within `tolerance`
+    fn position(&self, camera: usize, frame: &DcamFrame) -> i128 {
+        match self.match_by {
+            MatchBy::Framestamp => {
+                let first = self.first_framestamps[camera].unwrap_or(frame.framestamp());
+                frame.framestamp().wrapping_sub(first) as i128 + self.offsets[camera]
+            }
+            MatchBy::Timestamp(_) => frame.timestamp().as_nanos() as i128,
+        }
+    }
+    fn tolerance(&self) -> i128 {
+        match self.match_by {
+            MatchBy::Framestamp => 0,
+            MatchBy::Timestamp(tolerance) => tolerance.as_nanos() as i128,
+        }
+    }
+    /// Cameras whose oldest frame is from an earlier pulse than the newest of the oldest frames, only
+    /// when matching by framestamp
+    fn out_of_step(&self) -> Vec<usize> {
+        let limit = match (self.match_by, self.interval) {
+            (MatchBy::Framestamp, Some(interval)) => interval / 2,
+            _ => return Vec::new(),
+        };
+        let newest = self
+            .queues
+            .iter()
+            .map(|queue| queue[0].timestamp())
+            .max()
+            .expect("there is at least one camera");
+        (0..self.queues.len())
+            .filter(|camera| newest - self.queues[*camera][0].timestamp() > limit)
+            .collect()
+    }
+    /// Throw away the oldest frame from `camera`
+    fn discard(&mut self, camera: usize) {
+        let frame = self.queues[camera]
+            .pop_front()
+            .expect("every queue has a frame");
+        self.unmatched.push(Unmatched {
+            camera,
+            framestamp: frame.framestamp(),
+            timestamp: frame.timestamp(),
+        });
+    }
+    /// Match up the oldest frame from each camera, throwing away frames which are too old to have a
+    /// partner. Returns `None` if we need more frames first
+    fn take_set(&mut self) -> Option<FrameSet> {
+        let tolerance = self.tolerance();
+        while self.missing().is_none() {
+            let positions: Vec<i128> = self
+                .queues
+                .iter()
+                .enumerate()
+                .map(|(camera, queue)| self.position(camera, &queue[0]))
+                .collect();
+            let newest = *positions
+                .iter()
+                .max()
+                .expect("there is at least one camera");
+            if positions.iter().all(|p| newest - p <= tolerance) {
+                let behind = self.out_of_step();
+                if behind.is_empty() {
+                    let frames = self
+                        .queues
+                        .iter_mut()
+                        .map(|queue| queue.pop_front().expect("every queue has a frame"))
+                        .collect();
+                    return Some(FrameSet {
+                        frames,
+                        unmatched: mem::take(&mut self.unmatched),
+                    });
+                }
+                //these cameras' next frames go with the others' current ones
+                for camera in behind {
+                    log_event!(
+                        WARN,
+                        camera,
+                        framestamp = self.queues[camera][0].framestamp(),
+                        "frame is out of step with the master"
+                    );
+                    self.discard(camera);
+                    self.offsets[camera] -= 1;
+                }
+                continue;
+            }
+            //anything too far behind the newest frame can't be matched anymore
+            for (camera, position) in positions.into_iter().enumerate() {
+                if newest - position > tolerance {
+                    log_event!(
+                        WARN,
+                        camera,
+                        framestamp = self.queues[camera][0].framestamp(),
+                        "no matching frame from the other cameras"
+                    );
+                    self.discard(camera);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::stamped;
+
+    /// (framestamp, timestamp in ms) of each frame in each set the matcher puts out
+    fn sets(matcher: &mut Matcher) -> Vec<Vec<(i32, u128)>> {
+        let mut sets = Vec::new();
+        while let Some(set) = matcher.take_set() {
+            sets.push(
+                set.frames
+                    .iter()
+                    .map(|f| (f.framestamp(), f.timestamp().as_millis()))
+                    .collect(),
+            );
+        }
+        sets
+    }
+
+    #[test]
+    fn in_order_by_framestamp() {
+        let mut matcher = Matcher::new(2, 0, MatchBy::Framestamp);
+        for i in 0..3 {
+            matcher.push(0, stamped(Duration::from_millis(10 * i as u64), i));
+            //each camera counts from its own first frame
+            matcher.push(
+                1,
+                stamped(Duration::from_millis(10 * i as u64 + 1), 100 + i),
+            );
+        }
+        assert_eq!(
+            sets(&mut matcher),
+            vec![
+                vec![(0, 0), (100, 1)],
+                vec![(1, 10), (101, 11)],
+                vec![(2, 20), (102, 21)],
+            ]
+        );
+        assert!(matcher.unmatched.is_empty());
+    }
+
+    #[test]
+    fn dropped_frame_on_one_camera() {
+        let mut matcher = Matcher::new(2, 0, MatchBy::Framestamp);
+        for i in 0..3 {
+            matcher.push(0, stamped(Duration::from_millis(10 * i as u64), i));
+        }
+        //the slave captured its second frame but we never saw it
+        matcher.push(1, stamped(Duration::from_millis(0), 0));
+        matcher.push(1, stamped(Duration::from_millis(20), 2));
+        assert_eq!(matcher.take_set().unwrap().frames[1].framestamp(), 0);
+        let set = matcher.take_set().unwrap();
+        assert_eq!(set.frames[0].framestamp(), 2);
+        assert_eq!(set.frames[1].framestamp(), 2);
+        assert_eq!(
+            set.unmatched,
+            vec![Unmatched {
+                camera: 0,
+                framestamp: 1,
+                timestamp: Duration::from_millis(10),
+            }]
+        );
+    }
+
+    #[test]
+    fn slave_misses_the_first_pulse() {
+        let mut matcher = Matcher::new(2, 0, MatchBy::Framestamp);
+        for i in 0..3 {
+            matcher.push(0, stamped(Duration::from_millis(10 * i as u64), i));
+        }
+        //the slave's first frame is from the master's second pulse
+        matcher.push(1, stamped(Duration::from_millis(11), 0));
+        matcher.push(1, stamped(Duration::from_millis(21), 1));
+        let first = matcher.take_set().unwrap();
+        assert_eq!(first.frames[0].framestamp(), 1);
+        assert_eq!(first.frames[1].framestamp(), 0);
+        assert_eq!(
+            first.unmatched,
+            vec![Unmatched {
+                camera: 0,
+                framestamp: 0,
+                timestamp: Duration::ZERO,
+            }]
+        );
+        assert_eq!(
+            sets(&mut matcher),
+            vec![vec![(2, 20), (1, 21)]],
+            "the cameras stay lined up"
+        );
+    }
+
+    #[test]
+    fn framestamps_wait_for_the_master_interval() {
+        let mut matcher = Matcher::new(2, 1, MatchBy::Framestamp);
+        matcher.push(0, stamped(Duration::from_millis(0), 0));
+        matcher.push(1, stamped(Duration::from_millis(0), 0));
+        assert_eq!(matcher.missing(), Some(1));
+        assert!(matcher.take_set().is_none());
+    }
+
+    #[test]
+    fn timestamp_tolerance() {
+        let mut matcher = Matcher::new(2, 0, MatchBy::Timestamp(Duration::from_millis(2)));
+        for i in 0..3 {
+            matcher.push(0, stamped(Duration::from_millis(10 * i as u64), i));
+        }
+        matcher.push(1, stamped(Duration::from_millis(1), 0));
+        matcher.push(1, stamped(Duration::from_millis(13), 1));
+        matcher.push(1, stamped(Duration::from_millis(21), 2));
+        assert_eq!(
+            sets(&mut matcher),
+            vec![vec![(0, 0), (0, 1)], vec![(2, 20), (2, 21)]]
+        );
+    }
+
+    #[test]
+    fn master_out_of_range() {
+        let invalid = Err(DcamError(bindings::DCAMERR_DCAMERR_INVALIDPARAM));
+        assert_eq!(check_master(2, 2), invalid);
+        assert_eq!(check_master(0, 0), invalid);
+        assert_eq!(check_master(1, 2), Ok(()));
+    }
+}
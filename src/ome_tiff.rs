@@ -0,0 +1,277 @@
+//! Streaming writer for multi-page OME-TIFF files.
+//!
+//! Files are written as uncompressed BigTIFF so acquisitions aren't limited to 4 GB. Each frame
+//! is written as it arrives, the OME-XML describing the whole series is appended when the writer
+//! is finished and linked from the first page, which is where Fiji and napari look for it
+
+use crate::frame::DcamFrame;
+use crate::writer::{AcquisitionInfo, FrameWriter};
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// TIFF field types we use
+const TIFF_SHORT: u16 = 3;
+const TIFF_LONG: u16 = 4;
+const TIFF_ASCII: u16 = 2;
+const TIFF_LONG8: u16 = 16;
+
+/// Tag number of the ImageDescription tag we store the OME-XML in
+const TAG_IMAGE_DESCRIPTION: u16 = 270;
+/// Number of entries in each IFD
+const IFD_ENTRIES: u64 = 12;
+/// Size of an IFD in bytes: entry count, entries, next IFD offset
+const IFD_BYTES: u64 = 8 + IFD_ENTRIES * 20 + 8;
+/// Size of the BigTIFF header
+const HEADER_BYTES: u64 = 16;
+
+/// Writes frames one at a time to a BigTIFF file with OME-XML metadata
+pub struct OmeTiffWriter {
+    file: BufWriter<File>,
+    /// current write position, so we don't need to seek while streaming
+    position: u64,
+    info: Option<AcquisitionInfo>,
+    /// overrides the pixel size from `info`
+    pixel_size: Option<[f64; 2]>,
+    /// `[width, height]` of the frames in this file, set by the first frame
+    dimensions: Option<[u32; 2]>,
+    /// camera timestamp and framestamp of each plane
+    planes: Vec<(Duration, i32)>,
+    /// file offset of the next IFD pointer of the last page written
+    last_next_ifd: Option<u64>,
+    finished: bool,
+}
+
+impl OmeTiffWriter {
+    /// Create (or overwrite) the file at `path`
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<OmeTiffWriter> {
+        let mut file = BufWriter::new(File::create(path)?);
+        //BigTIFF header, first IFD comes right after it
+        file.write_all(b"II")?;
+        file.write_all(&43u16.to_le_bytes())?;
+        file.write_all(&8u16.to_le_bytes())?;
+        file.write_all(&0u16.to_le_bytes())?;
+        file.write_all(&HEADER_BYTES.to_le_bytes())?;
+        Ok(OmeTiffWriter {
+            file,
+            position: HEADER_BYTES,
+            info: None,
+            pixel_size: None,
+            dimensions: None,
+            planes: Vec::new(),
+            last_next_ifd: None,
+            finished: false,
+        })
+    }
+    /// Set the size of each pixel in the sample in micrometers as `[x, y]`.
+    /// Without this the pixel size on the camera sensor is used
+    pub fn set_pixel_size(&mut self, pixel_size: [f64; 2]) {
+        self.pixel_size = Some(pixel_size);
+    }
+    /// Number of frames written so far
+    pub fn frame_count(&self) -> usize {
+        self.planes.len()
+    }
+    /// Write one IFD entry
+    fn write_entry(&mut self, tag: u16, field_type: u16, count: u64, value: u64) -> io::Result<()> {
+        self.file.write_all(&tag.to_le_bytes())?;
+        self.file.write_all(&field_type.to_le_bytes())?;
+        self.file.write_all(&count.to_le_bytes())?;
+        self.file.write_all(&value.to_le_bytes())
+    }
+    /// Build the OME-XML for everything written so far
+    fn ome_xml(&self) -> String {
+        let [width, height] = self.dimensions.unwrap_or([0, 0]);
+        let info = self.info.as_ref();
+        let first_timestamp = self.planes.first().map(|p| p.0).unwrap_or_default();
+        let mut xml = String::new();
+        xml.push_str(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+        xml.push_str(concat!(
+            r#"<OME xmlns="http://www.openmicroscopy.org/Schemas/OME/2016-06""#,
+            r#" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance""#,
+            r#" xsi:schemaLocation="http://www.openmicroscopy.org/Schemas/OME/2016-06"#,
+            r#" http://www.openmicroscopy.org/Schemas/OME/2016-06/ome.xsd""#,
+            r#" Creator="free_willy "#,
+            env!("CARGO_PKG_VERSION"),
+            r#"">"#
+        ));
+        if let Some(info) = info {
+            xml.push_str(&format!(
+                r#"<Instrument ID="Instrument:0"><Detector ID="Detector:0" Manufacturer="Hamamatsu" Model="{}" SerialNumber="{}"/></Instrument>"#,
+                escape(&info.model),
+                escape(&info.serial_number)
+            ));
+        }
+        xml.push_str(r#"<Image ID="Image:0" Name="Image:0">"#);
+        if info.is_some() {
+            xml.push_str(r#"<InstrumentRef ID="Instrument:0"/>"#);
+        }
+        let significant_bits = info.map(|i| i.pixel_type.bits()).unwrap_or(16);
+        xml.push_str(&format!(
+            r#"<Pixels ID="Pixels:0" DimensionOrder="XYCZT" Type="uint16" SignificantBits="{}" BigEndian="false" Interleaved="false" SizeX="{}" SizeY="{}" SizeC="1" SizeZ="1" SizeT="{}""#,
+            significant_bits,
+            width,
+            height,
+            self.planes.len()
+        ));
+        if let Some([x, y]) = self.pixel_size.or(info.and_then(|i| i.pixel_size)) {
+            xml.push_str(&format!(
+                r#" PhysicalSizeX="{}" PhysicalSizeXUnit="µm" PhysicalSizeY="{}" PhysicalSizeYUnit="µm""#,
+                x, y
+            ));
+        }
+        xml.push('>');
+        xml.push_str(r#"<Channel ID="Channel:0:0" SamplesPerPixel="1">"#);
+        if let Some(info) = info {
+            let binning = match info.binning_factors() {
+                [b @ (1 | 2 | 4 | 8), v] if b == v => format!("{}x{}", b, b),
+                _ => "Other".to_string(),
+            };
+            xml.push_str(&format!(
+                r#"<DetectorSettings ID="Detector:0" Binning="{}"/>"#,
+                binning
+            ));
+        }
+        xml.push_str("</Channel>");
+        xml.push_str(&format!(
+            r#"<TiffData IFD="0" PlaneCount="{}"/>"#,
+            self.planes.len()
+        ));
+        for (t, (timestamp, _)) in self.planes.iter().enumerate() {
+            xml.push_str(&format!(
+                r#"<Plane TheC="0" TheZ="0" TheT="{}" DeltaT="{}" DeltaTUnit="s""#,
+                t,
+                timestamp.saturating_sub(first_timestamp).as_secs_f64()
+            ));
+            if let Some(info) = info {
+                xml.push_str(&format!(
+                    r#" ExposureTime="{}" ExposureTimeUnit="s""#,
+                    info.exposure
+                ));
+            }
+            xml.push_str("/>");
+        }
+        xml.push_str("</Pixels>");
+        if info.is_some() {
+            xml.push_str(r#"<AnnotationRef ID="Annotation:0"/>"#);
+        }
+        xml.push_str("</Image>");
+        //the sensor ROI and frame stamps have no place in the OME model, keep them as key/value pairs
+        if let Some(info) = info {
+            let framestamps: Vec<String> = self.planes.iter().map(|p| p.1.to_string()).collect();
+            xml.push_str(r#"<StructuredAnnotations><MapAnnotation ID="Annotation:0" Namespace="free_willy"><Value>"#);
+            for (key, value) in [
+                ("SubarrayHPos", info.roi[0].to_string()),
+                ("SubarrayVPos", info.roi[1].to_string()),
+                ("SubarrayHSize", info.roi[2].to_string()),
+                ("SubarrayVSize", info.roi[3].to_string()),
+                ("Binning", info.binning.to_string()),
+                ("PixelType", format!("{:?}", info.pixel_type)),
+                ("FrameStamps", framestamps.join(",")),
+            ] {
+                xml.push_str(&format!(r#"<M K="{}">{}</M>"#, key, value));
+            }
+            xml.push_str("</Value></MapAnnotation></StructuredAnnotations>");
+        }
+        xml.push_str("</OME>");
+        xml
+    }
+}
+
+impl FrameWriter for OmeTiffWriter {
+    fn begin(&mut self, info: &AcquisitionInfo) -> io::Result<()> {
+        self.info = Some(info.clone());
+        Ok(())
+    }
+    fn write_frame(&mut self, frame: &DcamFrame) -> io::Result<()> {
+        if self.finished {
+            return Err(io::Error::other("can't write to a finished OME-TIFF"));
+        }
+        let image = frame.image();
+        let dimensions = [image.width(), image.height()];
+        if *self.dimensions.get_or_insert(dimensions) != dimensions {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "all frames in an OME-TIFF must be the same size",
+            ));
+        }
+        let data_bytes = image.as_raw().len() as u64 * 2;
+        //each page is the IFD followed by its pixel data, so we know where the next IFD goes before writing it
+        let ifd_start = self.position;
+        let data_start = ifd_start + IFD_BYTES;
+        let next_ifd = data_start + data_bytes;
+        self.file.write_all(&IFD_ENTRIES.to_le_bytes())?;
+        self.write_entry(256, TIFF_LONG, 1, dimensions[0] as u64)?;
+        self.write_entry(257, TIFF_LONG, 1, dimensions[1] as u64)?;
+        self.write_entry(258, TIFF_SHORT, 1, 16)?;
+        //no compression
+        self.write_entry(259, TIFF_SHORT, 1, 1)?;
+        //black is zero
+        self.write_entry(262, TIFF_SHORT, 1, 1)?;
+        //filled in with the OME-XML on the first page by `finish`, the other pages get an empty string
+        self.write_entry(TAG_IMAGE_DESCRIPTION, TIFF_ASCII, 1, 0)?;
+        self.write_entry(273, TIFF_LONG8, 1, data_start)?;
+        self.write_entry(277, TIFF_SHORT, 1, 1)?;
+        self.write_entry(278, TIFF_LONG, 1, dimensions[1] as u64)?;
+        self.write_entry(279, TIFF_LONG8, 1, data_bytes)?;
+        self.write_entry(284, TIFF_SHORT, 1, 1)?;
+        //unsigned integer samples
+        self.write_entry(339, TIFF_SHORT, 1, 1)?;
+        self.file.write_all(&next_ifd.to_le_bytes())?;
+        //the file is little endian, so is every machine DCAM runs on
+        let pixels = image.as_raw();
+        self.file.write_all(unsafe {
+            std::slice::from_raw_parts(pixels.as_ptr() as *const u8, pixels.len() * 2)
+        })?;
+        self.last_next_ifd = Some(ifd_start + IFD_BYTES - 8);
+        self.position = next_ifd;
+        self.planes.push((frame.timestamp(), frame.framestamp()));
+        Ok(())
+    }
+    fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        let mut xml = self.ome_xml().into_bytes();
+        xml.push(0);
+        let xml_start = self.position;
+        self.file.write_all(&xml)?;
+        match self.last_next_ifd {
+            Some(last_next_ifd) => {
+                //the last page doesn't have a next page
+                self.file.seek(SeekFrom::Start(last_next_ifd))?;
+                self.file.write_all(&0u64.to_le_bytes())?;
+                //point the first page's ImageDescription at the OME-XML, it is the 6th entry
+                self.file
+                    .seek(SeekFrom::Start(HEADER_BYTES + 8 + 5 * 20 + 4))?;
+                self.file.write_all(&(xml.len() as u64).to_le_bytes())?;
+                self.file.write_all(&xml_start.to_le_bytes())?;
+            }
+            None => {
+                //a TIFF needs at least one page, there's nothing useful we can write
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "can't finish an OME-TIFF with no frames",
+                ));
+            }
+        }
+        self.file.flush()
+    }
+}
+
+impl Drop for OmeTiffWriter {
+    fn drop(&mut self) {
+        //make sure the file is readable even if nobody called finish
+        let _ = self.finish();
+    }
+}
+
+/// escape a string for use in an XML attribute
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
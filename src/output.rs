@@ -0,0 +1,122 @@
+//! Bounded queue between the capture thread and the ralston frame consumer.
+//!
+//! The consumer's `Sender<Frame>` is unbounded, so frames are queued in a bounded `Subscription` and a
+//! forwarding thread turns them into ralston `Frame`s and sends them on. What happens when the queue is
+//! full is chosen with an `OverflowPolicy`, `OverflowPolicy::Error` disconnects the consumer. If the
+//! consumer hangs up the forwarding thread exits and capture carries on for any writers and subscribers
+
+use crate::async_writer::OverflowPolicy;
+use crate::frame::DcamFrame;
+use crate::subscription::{self, Publisher};
+use image::DynamicImage;
+use ralston::Frame;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// Counters describing how the frame consumer is keeping up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct OutputStats {
+    /// frames handed to the consumer since the stream started
+    pub frames_sent: u64,
+    /// frames thrown away because the queue was full
+    pub frames_dropped: u64,
+    /// the current consumer was disconnected because the queue overflowed with `OverflowPolicy::Error`
+    pub overflowed: bool,
+    /// the current consumer hung up
+    pub disconnected: bool,
+}
+
+impl fmt::Display for OutputStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sent {} frames, dropped {}",
+            self.frames_sent, self.frames_dropped
+        )?;
+        if self.overflowed {
+            write!(f, ", consumer disconnected after the queue overflowed")?;
+        } else if self.disconnected {
+            write!(f, ", consumer hung up")?;
+        }
+        Ok(())
+    }
+}
+
+/// Counters shared between a `DcamStream`, its capture thread and the forwarding thread
+#[derive(Default)]
+pub(crate) struct OutputCounters {
+    sent: AtomicU64,
+    dropped: Arc<AtomicU64>,
+    overflowed: AtomicBool,
+    disconnected: AtomicBool,
+}
+
+impl OutputCounters {
+    pub(crate) fn stats(&self) -> OutputStats {
+        OutputStats {
+            frames_sent: self.sent.load(Ordering::Relaxed),
+            frames_dropped: self.dropped.load(Ordering::Relaxed),
+            overflowed: self.overflowed.load(Ordering::Relaxed),
+            disconnected: self.disconnected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The capture thread's end of the queue feeding a consumer
+pub(crate) struct Output {
+    publisher: Publisher,
+    forwarder: JoinHandle<()>,
+}
+
+impl Output {
+    /// Start forwarding frames to `consumer` through a queue holding at most `capacity` frames
+    pub(crate) fn connect(
+        consumer: Sender<Frame>,
+        capacity: usize,
+        policy: OverflowPolicy,
+        counters: &Arc<OutputCounters>,
+    ) -> Output {
+        let (publisher, subscription) =
+            subscription::subscription(capacity, policy, counters.dropped.clone());
+        counters.overflowed.store(false, Ordering::Relaxed);
+        counters.disconnected.store(false, Ordering::Relaxed);
+        let counters = counters.clone();
+        let forwarder = thread::spawn(move || {
+            while let Some(frame) = subscription.recv() {
+                //ralston frames carry the time since the stream started
+                let elapsed = frame.metadata().map(|m| m.elapsed).unwrap_or_default();
+                let frame = Frame::new(elapsed, DynamicImage::ImageLuma16(frame.into_image()));
+                if consumer.send(frame).is_err() {
+                    counters.disconnected.store(true, Ordering::Relaxed);
+                    return;
+                }
+                counters.sent.fetch_add(1, Ordering::Relaxed);
+            }
+            if subscription.overflowed() {
+                counters.overflowed.store(true, Ordering::Relaxed);
+            }
+        });
+        Output {
+            publisher,
+            forwarder,
+        }
+    }
+    /// Queue a frame for the consumer, returns `false` once the consumer is gone
+    pub(crate) fn publish(&self, frame: &DcamFrame) -> bool {
+        self.publisher.publish(frame)
+    }
+    /// Number of frames waiting for the consumer
+    pub(crate) fn queued(&self) -> usize {
+        self.publisher.queued()
+    }
+    /// Send everything still queued and wait for the forwarding thread to finish
+    pub(crate) fn close(self) {
+        drop(self.publisher);
+        self.forwarder
+            .join()
+            .expect("frame forwarding thread panicked");
+    }
+}
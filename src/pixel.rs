@@ -0,0 +1,253 @@
+//! Pixel formats the camera can deliver and helpers for turning them into 16 bit images
+
+use crate::bindings;
+
+/// Pixel formats which can be selected through `DCAM_IDPROP_IMAGE_PIXELTYPE`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelType {
+    /// one byte per pixel
+    Mono8,
+    /// 12 bit pixels, two pixels packed into three bytes with the high bits first
+    Mono12,
+    /// 12 bit pixels, two pixels packed into three bytes with the low bits first (GenICam style)
+    Mono12P,
+    /// two bytes per pixel
+    Mono16,
+}
+
+impl PixelType {
+    /// Convert a value read from `DCAM_IDPROP_IMAGE_PIXELTYPE` into a `PixelType`.
+    /// Returns `None` for pixel types we don't support (i.e. color)
+    pub fn from_dcam(value: i32) -> Option<PixelType> {
+        match value {
+            bindings::DCAM_PIXELTYPE_DCAM_PIXELTYPE_MONO8 => Some(PixelType::Mono8),
+            bindings::DCAM_PIXELTYPE_DCAM_PIXELTYPE_MONO12 => Some(PixelType::Mono12),
+            bindings::DCAM_PIXELTYPE_DCAM_PIXELTYPE_MONO12P => Some(PixelType::Mono12P),
+            bindings::DCAM_PIXELTYPE_DCAM_PIXELTYPE_MONO16 => Some(PixelType::Mono16),
+            _ => None,
+        }
+    }
+    /// Get the value we need to write to `DCAM_IDPROP_IMAGE_PIXELTYPE` to select this pixel type
+    pub fn to_dcam(self) -> i32 {
+        match self {
+            PixelType::Mono8 => bindings::DCAM_PIXELTYPE_DCAM_PIXELTYPE_MONO8,
+            PixelType::Mono12 => bindings::DCAM_PIXELTYPE_DCAM_PIXELTYPE_MONO12,
+            PixelType::Mono12P => bindings::DCAM_PIXELTYPE_DCAM_PIXELTYPE_MONO12P,
+            PixelType::Mono16 => bindings::DCAM_PIXELTYPE_DCAM_PIXELTYPE_MONO16,
+        }
+    }
+    /// Number of significant bits in each pixel
+    pub fn bits(self) -> u32 {
+        match self {
+            PixelType::Mono8 => 8,
+            PixelType::Mono12 | PixelType::Mono12P => 12,
+            PixelType::Mono16 => 16,
+        }
+    }
+    /// Minimum number of bytes needed to hold a row of `width` pixels
+    pub fn min_rowbytes(self, width: usize) -> usize {
+        match self {
+            PixelType::Mono8 => width,
+            PixelType::Mono12 | PixelType::Mono12P => (width * 3).div_ceil(2),
+            PixelType::Mono16 => width * 2,
+        }
+    }
+    /// Convert one frame of raw camera data into 16 bit pixels. `rowbytes` is the stride of
+    /// `raw` in bytes, which can be larger than the data in each row
+    pub fn to_mono16(self, raw: &[u8], width: usize, height: usize, rowbytes: usize) -> Vec<u16> {
+        match self {
+            PixelType::Mono8 => {
+                let mut out = Vec::with_capacity(width * height);
+                for row in raw.chunks(rowbytes).take(height) {
+                    out.extend(row[..width].iter().map(|p| *p as u16));
+                }
+                out
+            }
+            PixelType::Mono12 => unpack_mono12(raw, width, height, rowbytes),
+            PixelType::Mono12P => unpack_mono12p(raw, width, height, rowbytes),
+            PixelType::Mono16 => {
+                let mut out = Vec::with_capacity(width * height);
+                for row in raw.chunks(rowbytes).take(height) {
+                    out.extend(
+                        row[..width * 2]
+                            .chunks_exact(2)
+                            .map(|p| u16::from_le_bytes([p[0], p[1]])),
+                    );
+                }
+                out
+            }
+        }
+    }
+}
+
+/// Number of packed bytes we unpack in each pass of the inner loop (16 pixels)
+const BLOCK_BYTES: usize = 24;
+/// Number of pixels produced from each block
+const BLOCK_PIXELS: usize = 16;
+
+/// unpack a MONO12 pixel pair, the first byte holds the high bits of the first pixel
+#[inline(always)]
+fn mono12_pair(b0: u8, b1: u8, b2: u8) -> [u16; 2] {
+    [
+        ((b0 as u16) << 4) | (b1 as u16 & 0x0F),
+        ((b2 as u16) << 4) | (b1 as u16 >> 4),
+    ]
+}
+
+/// unpack a MONO12P pixel pair, the first byte holds the low bits of the first pixel
+#[inline(always)]
+fn mono12p_pair(b0: u8, b1: u8, b2: u8) -> [u16; 2] {
+    [
+        (b0 as u16) | ((b1 as u16 & 0x0F) << 8),
+        ((b2 as u16) << 4) | (b1 as u16 >> 4),
+    ]
+}
+
+/// Unpack one row of 12 bit data into `dst` using `pair` to decode each 3 byte group.
+/// Full blocks are handled with fixed size slices so the compiler can vectorize the inner loop,
+/// whatever is left over at the end of the row is done one pair at a time
+#[inline(always)]
+fn unpack_row<F: Fn(u8, u8, u8) -> [u16; 2]>(src: &[u8], dst: &mut [u16], pair: F) {
+    let nblocks = dst.len() / BLOCK_PIXELS;
+    let (src_blocks, src_rest) = src.split_at(nblocks * BLOCK_BYTES);
+    let (dst_blocks, dst_rest) = dst.split_at_mut(nblocks * BLOCK_PIXELS);
+    for (s, d) in src_blocks
+        .chunks_exact(BLOCK_BYTES)
+        .zip(dst_blocks.chunks_exact_mut(BLOCK_PIXELS))
+    {
+        for i in 0..BLOCK_PIXELS / 2 {
+            let p = pair(s[3 * i], s[3 * i + 1], s[3 * i + 2]);
+            d[2 * i] = p[0];
+            d[2 * i + 1] = p[1];
+        }
+    }
+    //an odd width leaves the last pixel in a partial group, missing bytes are read as zero
+    for (s, d) in src_rest.chunks(3).zip(dst_rest.chunks_mut(2)) {
+        let p = pair(
+            s[0],
+            s.get(1).copied().unwrap_or(0),
+            s.get(2).copied().unwrap_or(0),
+        );
+        d.copy_from_slice(&p[..d.len()]);
+    }
+}
+
+/// shared implementation of `unpack_mono12` and `unpack_mono12p`
+fn unpack_packed12<F: Fn(u8, u8, u8) -> [u16; 2] + Copy>(
+    packed: &[u8],
+    width: usize,
+    height: usize,
+    rowbytes: usize,
+    pair: F,
+) -> Vec<u16> {
+    if width == 0 {
+        return Vec::new();
+    }
+    let packed_row = (width * 3).div_ceil(2);
+    assert!(rowbytes >= packed_row, "rowbytes too small for image width");
+    assert!(
+        height == 0 || packed.len() >= rowbytes * (height - 1) + packed_row,
+        "packed buffer too small for image size"
+    );
+    let mut out = vec![0; width * height];
+    for (row, dst) in out.chunks_exact_mut(width).enumerate() {
+        let start = row * rowbytes;
+        unpack_row(&packed[start..start + packed_row], dst, pair);
+    }
+    out
+}
+
+/// Unpack a `width` x `height` image in `DCAM_PIXELTYPE_MONO12` format into 16 bit pixels.
+/// Each row of `packed` starts `rowbytes` after the previous one. The output pixels keep
+/// their 12 bit range, they are not scaled up to 16 bits
+pub fn unpack_mono12(packed: &[u8], width: usize, height: usize, rowbytes: usize) -> Vec<u16> {
+    unpack_packed12(packed, width, height, rowbytes, mono12_pair)
+}
+
+/// Unpack a `width` x `height` image in `DCAM_PIXELTYPE_MONO12P` format into 16 bit pixels.
+/// Each row of `packed` starts `rowbytes` after the previous one. The output pixels keep
+/// their 12 bit range, they are not scaled up to 16 bits
+pub fn unpack_mono12p(packed: &[u8], width: usize, height: usize, rowbytes: usize) -> Vec<u16> {
+    unpack_packed12(packed, width, height, rowbytes, mono12p_pair)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// one 16 pixel block, every nibble of every pixel is different from its neighbours
+    const PIXELS: [u16; 16] = [
+        0x0F0, 0x1E7, 0x2DE, 0x3C5, 0x4BC, 0x5A3, 0x69A, 0x781, 0x878, 0x96F, 0xA56, 0xB4D, 0xC34,
+        0xD2B, 0xE12, 0xF09,
+    ];
+    /// `PIXELS` packed as MONO12, 0x0F0 and 0x1E7 become 0x0F, 0x70, 0x1E
+    const MONO12: [u8; 24] = [
+        0x0F, 0x70, 0x1E, 0x2D, 0x5E, 0x3C, 0x4B, 0x3C, 0x5A, 0x69, 0x1A, 0x78, 0x87, 0xF8, 0x96,
+        0xA5, 0xD6, 0xB4, 0xC3, 0xB4, 0xD2, 0xE1, 0x92, 0xF0,
+    ];
+    /// `PIXELS` packed as MONO12P, 0x0F0 and 0x1E7 become 0xF0, 0x70, 0x1E
+    const MONO12P: [u8; 24] = [
+        0xF0, 0x70, 0x1E, 0xDE, 0x52, 0x3C, 0xBC, 0x34, 0x5A, 0x9A, 0x16, 0x78, 0x78, 0xF8, 0x96,
+        0x56, 0xDA, 0xB4, 0x34, 0xBC, 0xD2, 0x12, 0x9E, 0xF0,
+    ];
+
+    #[test]
+    fn mono12_block() {
+        assert_eq!(unpack_mono12(&MONO12, 16, 1, 24), PIXELS);
+        assert_eq!(PixelType::Mono12.to_mono16(&MONO12, 16, 1, 24), PIXELS);
+    }
+
+    #[test]
+    fn mono12p_block() {
+        assert_eq!(unpack_mono12p(&MONO12P, 16, 1, 24), PIXELS);
+        assert_eq!(PixelType::Mono12P.to_mono16(&MONO12P, 16, 1, 24), PIXELS);
+    }
+
+    #[test]
+    fn odd_width_with_padding() {
+        //17 pixels per row is a full block and half a pair, 26 bytes of data padded out to 28
+        let mut mono12 = Vec::new();
+        mono12.extend(MONO12);
+        mono12.extend([0xAB, 0x0C, 0xEE, 0xEE]);
+        mono12.extend(MONO12);
+        mono12.extend([0x12, 0x03, 0xEE, 0xEE]);
+        let mut mono12p = Vec::new();
+        mono12p.extend(MONO12P);
+        mono12p.extend([0xBC, 0x0A, 0xEE, 0xEE]);
+        mono12p.extend(MONO12P);
+        mono12p.extend([0x23, 0x01, 0xEE, 0xEE]);
+        let mut expected = Vec::new();
+        expected.extend(PIXELS);
+        expected.push(0xABC);
+        expected.extend(PIXELS);
+        expected.push(0x123);
+        assert_eq!(PixelType::Mono12.min_rowbytes(17), 26);
+        assert_eq!(unpack_mono12(&mono12, 17, 2, 28), expected);
+        assert_eq!(unpack_mono12p(&mono12p, 17, 2, 28), expected);
+    }
+
+    #[test]
+    fn last_row_without_padding() {
+        //the padding after the last row doesn't have to be there
+        let mut packed = Vec::new();
+        packed.extend(MONO12);
+        packed.extend([0xEE; 8]);
+        packed.extend(MONO12);
+        let expected: Vec<u16> = PIXELS.iter().chain(PIXELS.iter()).copied().collect();
+        assert_eq!(unpack_mono12(&packed, 16, 2, 32), expected);
+    }
+
+    #[test]
+    fn mono8_and_mono16_with_padding() {
+        let mono8 = [1, 2, 3, 0xEE, 4, 5, 6, 0xEE];
+        assert_eq!(
+            PixelType::Mono8.to_mono16(&mono8, 3, 2, 4),
+            vec![1, 2, 3, 4, 5, 6]
+        );
+        let mono16 = [1, 0, 2, 1, 0xEE, 0xEE, 3, 0, 4, 1, 0xEE, 0xEE];
+        assert_eq!(
+            PixelType::Mono16.to_mono16(&mono16, 2, 2, 6),
+            vec![1, 0x102, 3, 0x104]
+        );
+    }
+}
@@ -0,0 +1,163 @@
+//! Replay frames stored in a `Recording` as a ralston `FrameSource`, so recorded sessions can
+//! go through the same processing pipeline as a live camera
+
+use crate::recording::Recording;
+use image::DynamicImage;
+use ralston::{Frame, FrameSource, FrameStream};
+use std::sync::mpsc::{channel, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+///Struct for representing a source of frames read back from a `Recording`. Can call start() to get a frame stream.
+pub struct RecordingSource {
+    recording: Arc<Recording>,
+    exposure: f64,
+    realtime: bool,
+}
+
+///Struct for representing a stream of recorded frames. Stops on its own once every frame has been sent,
+///or when a frame can't be read
+pub struct RecordingStream {
+    ///channel we use to kill the playback thread
+    control_tx: Sender<PlaybackMessage>,
+    ///finishes with the error which stopped playback early, if there was one
+    thread_handle: JoinHandle<Result<(), i32>>,
+}
+
+///Messages to send to our playback thread
+enum PlaybackMessage {
+    ChangeConsumer(Sender<Frame>),
+    Stop,
+}
+
+impl RecordingSource {
+    /// Create a new `RecordingSource` replaying the frames in `recording`. By default frames are sent
+    /// with the same spacing they were recorded with
+    pub fn new(recording: Arc<Recording>) -> RecordingSource {
+        RecordingSource {
+            recording,
+            exposure: 0.0,
+            realtime: true,
+        }
+    }
+    ///If `realtime` is false frames are sent as fast as the consumer can take them instead of
+    ///with the spacing they were recorded with
+    pub fn set_realtime(&mut self, realtime: bool) {
+        self.realtime = realtime;
+    }
+    ///Replay the recording into `sender`
+    fn stream(&self, mut frame_tx: Sender<Frame>) -> RecordingStream {
+        let (control_tx, control_rx) = channel::<PlaybackMessage>();
+        let recording = self.recording.clone();
+        let realtime = self.realtime;
+        let thread_handle = thread::spawn(move || {
+            let frames = match recording.frames() {
+                Ok(frames) => frames,
+                Err(e) => {
+                    log_event!(
+                        ERROR,
+                        error = %crate::error::DcamError(e),
+                        "couldn't read recording status"
+                    );
+                    return Err(e);
+                }
+            };
+            let start_time = Instant::now();
+            let mut first_timestamp: Option<Duration> = None;
+            for frame in frames {
+                //check to see if we've been asked to stop
+                match control_rx.try_recv() {
+                    Err(TryRecvError::Empty) => {}
+                    Err(TryRecvError::Disconnected) => break,
+                    Ok(PlaybackMessage::Stop) => break,
+                    Ok(PlaybackMessage::ChangeConsumer(new_tx)) => frame_tx = new_tx,
+                }
+                let frame = match frame {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        log_event!(
+                            ERROR,
+                            error = %crate::error::DcamError(e),
+                            "couldn't read recorded frame, playback stopped"
+                        );
+                        return Err(e);
+                    }
+                };
+                //timestamps are relative to the first recorded frame
+                let first = *first_timestamp.get_or_insert(frame.timestamp());
+                let elapsed = frame.timestamp().saturating_sub(first);
+                if realtime {
+                    if let Some(wait) = elapsed.checked_sub(start_time.elapsed()) {
+                        thread::sleep(wait);
+                    }
+                }
+                //stop quietly if nobody is listening anymore
+                if frame_tx
+                    .send(Frame::new(
+                        elapsed,
+                        DynamicImage::ImageLuma16(frame.into_image()),
+                    ))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+            Ok(())
+        });
+        RecordingStream {
+            control_tx,
+            thread_handle,
+        }
+    }
+}
+
+impl FrameSource for RecordingSource {
+    type Stream = RecordingStream;
+    ///Recorded frames can't be changed, the value is only stored
+    fn set_exposure(&mut self, exposure: f64) {
+        self.exposure = exposure;
+    }
+    ///Recorded frames can't be resized, this does nothing
+    fn set_resolution(&mut self, _resolution: [usize; 2]) {}
+    fn get_exposure(&self) -> f64 {
+        self.exposure
+    }
+    ///Get the size of the first recorded frame, `[0, 0]` if there isn't one
+    fn get_resolution(&self) -> [usize; 2] {
+        match self.recording.read_frame(0) {
+            Ok(frame) => [
+                frame.image().width() as usize,
+                frame.image().height() as usize,
+            ],
+            Err(_) => [0, 0],
+        }
+    }
+    fn start(&self, sender: Sender<Frame>) -> Self::Stream {
+        self.stream(sender)
+    }
+}
+
+impl RecordingStream {
+    /// Stop replaying frames. Returns the error which stopped playback if a frame couldn't be read
+    pub fn stop(self) -> Result<(), i32> {
+        //the thread may have already finished and hung up, that's fine
+        let _ = self.control_tx.send(PlaybackMessage::Stop);
+        self.thread_handle
+            .join()
+            .expect("Couldn't shut down playback thread")
+    }
+}
+
+impl FrameStream for RecordingStream {
+    fn stop(self) {
+        //the error was logged when playback stopped, the trait has no way to return it
+        let _ = Self::stop(self);
+    }
+    fn change_consumer(&mut self, sender: Sender<Frame>) {
+        //if playback already finished there's nobody to hand the consumer to
+        let _ = self
+            .control_tx
+            .send(PlaybackMessage::ChangeConsumer(sender));
+    }
+}
@@ -0,0 +1,134 @@
+//! Keep the last few seconds of frames in memory so an event can be saved along with what led up to it.
+//!
+//! `PreTriggerWriter` is a `FrameWriter` which holds frames in a ring until it is triggered, either
+//! through a `TriggerHandle` or by a detector looking at each frame. The frames from before the trigger
+//! are then passed to the wrapped writer, followed by every frame until the post-trigger time has passed.
+//! The ring uses host memory, so it can be much longer than the DCAM `FrameBuffer`. Flushing the ring writes
+//! a burst of frames at once, wrap the writer in an `AsyncWriter` to keep that off the capture thread
+
+use crate::frame::DcamFrame;
+use crate::writer::{AcquisitionInfo, FrameWriter};
+use std::collections::VecDeque;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Decides from a single frame whether an event has happened
+pub type Detector = Box<dyn FnMut(&DcamFrame) -> bool + Send>;
+
+/// Handle for triggering a `PreTriggerWriter` from another thread
+#[derive(Clone)]
+pub struct TriggerHandle {
+    fired: Arc<AtomicBool>,
+    events: Arc<AtomicUsize>,
+}
+
+impl TriggerHandle {
+    /// Save the buffered frames and everything captured for the post-trigger time.
+    /// Takes effect when the next frame arrives
+    pub fn trigger(&self) {
+        self.fired.store(true, Ordering::Release);
+    }
+    /// Number of events which have been saved so far
+    pub fn events(&self) -> usize {
+        self.events.load(Ordering::Acquire)
+    }
+}
+
+/// Buffers frames and writes them to another `FrameWriter` around trigger events
+pub struct PreTriggerWriter {
+    writer: Box<dyn FrameWriter>,
+    pre_trigger: Duration,
+    post_trigger: Duration,
+    /// frames from the last `pre_trigger`, oldest first
+    ring: VecDeque<DcamFrame>,
+    /// camera timestamp we are saving frames until, `None` while waiting for a trigger
+    saving_until: Option<Duration>,
+    detector: Option<Detector>,
+    handle: TriggerHandle,
+}
+
+impl PreTriggerWriter {
+    /// Keep `pre_trigger` worth of frames and write them to `writer` when triggered,
+    /// followed by `post_trigger` worth of frames after the trigger
+    pub fn new(
+        writer: Box<dyn FrameWriter>,
+        pre_trigger: Duration,
+        post_trigger: Duration,
+    ) -> PreTriggerWriter {
+        PreTriggerWriter {
+            writer,
+            pre_trigger,
+            post_trigger,
+            ring: VecDeque::new(),
+            saving_until: None,
+            detector: None,
+            handle: TriggerHandle {
+                fired: Arc::new(AtomicBool::new(false)),
+                events: Arc::new(AtomicUsize::new(0)),
+            },
+        }
+    }
+    /// Call `detector` on every frame, if it returns `true` we trigger on that frame
+    pub fn set_detector<F: FnMut(&DcamFrame) -> bool + Send + 'static>(&mut self, detector: F) {
+        self.detector = Some(Box::new(detector));
+    }
+    /// Get a handle that can trigger this writer once it has been handed off to a stream
+    pub fn trigger_handle(&self) -> TriggerHandle {
+        self.handle.clone()
+    }
+    /// Number of frames currently buffered
+    pub fn buffered(&self) -> usize {
+        self.ring.len()
+    }
+}
+
+impl FrameWriter for PreTriggerWriter {
+    fn begin(&mut self, info: &AcquisitionInfo) -> io::Result<()> {
+        self.writer.begin(info)
+    }
+    fn write_frame(&mut self, frame: &DcamFrame) -> io::Result<()> {
+        let now = frame.timestamp();
+        let mut triggered = self.handle.fired.swap(false, Ordering::AcqRel);
+        if let Some(detector) = self.detector.as_mut() {
+            triggered |= detector(frame);
+        }
+        if triggered {
+            if self.saving_until.is_none() {
+                self.handle.events.fetch_add(1, Ordering::AcqRel);
+                //everything leading up to the trigger goes first
+                for old in self.ring.drain(..) {
+                    self.writer.write_frame(&old)?;
+                }
+            }
+            //triggering again while saving extends the event
+            self.saving_until = Some(now + self.post_trigger);
+        }
+        match self.saving_until {
+            Some(until) => {
+                self.writer.write_frame(frame)?;
+                if now >= until {
+                    self.saving_until = None;
+                }
+            }
+            None => {
+                self.ring.push_back(frame.clone());
+                //forget anything older than the pre-trigger time
+                while let Some(oldest) = self.ring.front() {
+                    if now.saturating_sub(oldest.timestamp()) > self.pre_trigger {
+                        self.ring.pop_front();
+                    } else {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Buffered frames which were never triggered are thrown away
+    fn finish(&mut self) -> io::Result<()> {
+        self.ring.clear();
+        self.writer.finish()
+    }
+}
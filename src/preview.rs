@@ -0,0 +1,284 @@
+//! Reduced rate, 8 bit frames for displaying a live view while recording at full rate.
+//!
+//! `DcamStream::add_preview` only hands the capture thread a cheap rate check, frames which pass it are
+//! queued latest-only and all the work of downsampling and scaling to 8 bit happens on the thread calling
+//! `Preview::recv`. Writers and other subscribers still get every frame
+
+use crate::frame::{DcamFrame, FrameMetadata};
+use crate::subscription::Subscription;
+use image::{ImageBuffer, Luma};
+use std::time::Duration;
+
+/// How 16 bit pixel values are mapped onto `0..=255`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Contrast {
+    /// `low` maps to black and `high` to white
+    Fixed { low: u16, high: u16 },
+    /// the darkest pixel in each frame maps to black and the brightest to white
+    MinMax,
+    /// the given percentiles (`0.0..=100.0`) of each frame map to black and white,
+    /// so a few hot pixels don't wash out the image
+    Percentile { low: f64, high: f64 },
+}
+
+/// How preview frames are produced
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PreviewSettings {
+    /// most preview frames per second, zero, negative or NaN doesn't limit the rate
+    pub max_rate: f64,
+    /// average blocks of `downsample` x `downsample` pixels, `1` keeps full resolution
+    pub downsample: u32,
+    pub contrast: Contrast,
+}
+
+impl Default for PreviewSettings {
+    fn default() -> PreviewSettings {
+        PreviewSettings {
+            max_rate: 20.0,
+            downsample: 1,
+            contrast: Contrast::Percentile {
+                low: 0.1,
+                high: 99.9,
+            },
+        }
+    }
+}
+
+impl PreviewSettings {
+    /// Shortest time between preview frames
+    pub(crate) fn interval(&self) -> Duration {
+        if self.max_rate > 0.0 {
+            //a tiny rate is as good as never
+            Duration::try_from_secs_f64(1.0 / self.max_rate).unwrap_or(Duration::MAX)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// A frame ready for display
+#[derive(Debug, Clone)]
+pub struct PreviewFrame {
+    pub image: ImageBuffer<Luma<u8>, Vec<u8>>,
+    /// 16 bit values which were mapped to black and white
+    pub range: (u16, u16),
+    /// metadata of the full frame this was made from
+    pub metadata: Option<FrameMetadata>,
+    pub timestamp: Duration,
+    pub framestamp: i32,
+}
+
+/// Preview output of a `DcamStream`
+pub struct Preview {
+    subscription: Subscription,
+    downsample: u32,
+    contrast: Contrast,
+}
+
+impl Preview {
+    pub(crate) fn new(subscription: Subscription, settings: &PreviewSettings) -> Preview {
+        Preview {
+            subscription,
+            downsample: settings.downsample.max(1),
+            contrast: settings.contrast,
+        }
+    }
+    /// Wait for the next preview frame, returns `None` once the stream has stopped
+    pub fn recv(&self) -> Option<PreviewFrame> {
+        self.subscription.recv().map(|frame| self.render(&frame))
+    }
+    /// Wait up to `timeout` for the next preview frame
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<PreviewFrame> {
+        self.subscription
+            .recv_timeout(timeout)
+            .map(|frame| self.render(&frame))
+    }
+    /// Get a preview frame if one is waiting
+    pub fn try_recv(&self) -> Option<PreviewFrame> {
+        self.subscription
+            .try_recv()
+            .map(|frame| self.render(&frame))
+    }
+    /// Change the downsampling applied to frames from now on
+    pub fn set_downsample(&mut self, downsample: u32) {
+        self.downsample = downsample.max(1);
+    }
+    /// Change the contrast scaling applied to frames from now on
+    pub fn set_contrast(&mut self, contrast: Contrast) {
+        self.contrast = contrast;
+    }
+    /// Turn a full frame into a preview frame
+    pub fn render(&self, frame: &DcamFrame) -> PreviewFrame {
+        let small = downsample(frame.image(), self.downsample);
+        let (low, high) = match self.contrast {
+            Contrast::Fixed { low, high } => (low, high),
+            Contrast::MinMax => {
+                let min = small.as_raw().iter().copied().min().unwrap_or(0);
+                let max = small.as_raw().iter().copied().max().unwrap_or(u16::MAX);
+                (min, max)
+            }
+            Contrast::Percentile { low, high } => percentiles(small.as_raw(), low, high),
+        };
+        //avoid dividing by zero on flat images
+        let span = (high.saturating_sub(low) as f32).max(1.0);
+        let pixels = small
+            .as_raw()
+            .iter()
+            .map(|p| ((p.saturating_sub(low) as f32 / span) * 255.0).min(255.0) as u8)
+            .collect();
+        PreviewFrame {
+            image: ImageBuffer::from_raw(small.width(), small.height(), pixels)
+                .expect("preview has the same size as the downsampled frame"),
+            range: (low, high),
+            metadata: frame.metadata().cloned(),
+            timestamp: frame.timestamp(),
+            framestamp: frame.framestamp(),
+        }
+    }
+    /// Number of frames which passed the rate limit but were replaced by a newer one before we got to them
+    pub fn skipped(&self) -> u64 {
+        self.subscription.dropped()
+    }
+}
+
+/// Average `factor` x `factor` blocks of `image`, leftover rows and columns at the edges are dropped
+fn downsample(
+    image: &ImageBuffer<Luma<u16>, Vec<u16>>,
+    factor: u32,
+) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+    if factor <= 1 {
+        return image.clone();
+    }
+    let width = (image.width() / factor).max(1);
+    let height = (image.height() / factor).max(1);
+    let bx = factor.min(image.width());
+    let by = factor.min(image.height());
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let mut sum = 0u64;
+        for dy in 0..by {
+            for dx in 0..bx {
+                sum += image.get_pixel(x * bx + dx, y * by + dy).0[0] as u64;
+            }
+        }
+        Luma([(sum / (bx * by) as u64) as u16])
+    })
+}
+
+/// Find the `low` and `high` percentiles of `pixels` using a histogram
+fn percentiles(pixels: &[u16], low: f64, high: f64) -> (u16, u16) {
+    if pixels.is_empty() {
+        return (0, u16::MAX);
+    }
+    let mut histogram = vec![0u32; 1 << 16];
+    for p in pixels {
+        histogram[*p as usize] += 1;
+    }
+    let rank = |percentile: f64| {
+        ((percentile.clamp(0.0, 100.0) / 100.0) * (pixels.len() - 1) as f64).round() as u64
+    };
+    let (low_rank, high_rank) = (rank(low), rank(high));
+    let (mut low_value, mut high_value) = (None, None);
+    let mut seen = 0u64;
+    for (value, count) in histogram.iter().enumerate() {
+        seen += *count as u64;
+        if low_value.is_none() && seen > low_rank {
+            low_value = Some(value as u16);
+        }
+        if seen > high_rank {
+            high_value = Some(value as u16);
+            break;
+        }
+    }
+    (low_value.unwrap_or(0), high_value.unwrap_or(u16::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_writer::OverflowPolicy;
+    use crate::subscription;
+    use crate::test_util::frame;
+    use std::sync::Arc;
+
+    fn preview(downsample: u32, contrast: Contrast) -> Preview {
+        let (_, subscription) =
+            subscription::subscription(1, OverflowPolicy::DropOldest, Arc::default());
+        let settings = PreviewSettings {
+            downsample,
+            contrast,
+            ..PreviewSettings::default()
+        };
+        Preview::new(subscription, &settings)
+    }
+
+    #[test]
+    fn interval_from_rate() {
+        let settings = |max_rate| PreviewSettings {
+            max_rate,
+            ..PreviewSettings::default()
+        };
+        assert_eq!(settings(20.0).interval(), Duration::from_millis(50));
+        assert_eq!(settings(0.0).interval(), Duration::ZERO);
+        assert_eq!(settings(-5.0).interval(), Duration::ZERO);
+        assert_eq!(settings(f64::NAN).interval(), Duration::ZERO);
+        assert_eq!(settings(f64::INFINITY).interval(), Duration::ZERO);
+        assert_eq!(settings(1e-300).interval(), Duration::MAX);
+    }
+
+    #[test]
+    fn downsample_averages_blocks() {
+        //rows of 1..=4, 11..=14, 21..=24 and 31..=34
+        let image = frame(4, 4, 0).image().clone();
+        let small = downsample(&image, 2);
+        assert_eq!((small.width(), small.height()), (2, 2));
+        assert_eq!(small.as_raw(), &[6, 8, 26, 28]);
+        //the last column and row don't make a whole block
+        let odd = frame(5, 5, 0).image().clone();
+        assert_eq!(downsample(&odd, 2).as_raw(), &[6, 8, 26, 28]);
+        assert_eq!(downsample(&image, 1), image);
+    }
+
+    #[test]
+    fn percentiles_of_a_frame() {
+        let pixels: Vec<u16> = (0..=100).collect();
+        assert_eq!(percentiles(&pixels, 1.0, 99.0), (1, 99));
+        assert_eq!(percentiles(&pixels, 0.0, 100.0), (0, 100));
+        //a few hot pixels are clipped off
+        let mut hot = vec![100u16; 1000];
+        hot[0] = 60000;
+        assert_eq!(percentiles(&hot, 0.1, 99.9), (100, 100));
+        //a constant frame clips to its only value
+        assert_eq!(percentiles(&[500; 16], 0.1, 99.9), (500, 500));
+    }
+
+    #[test]
+    fn render_small_frame() {
+        let source = frame(4, 4, 3);
+        let rendered = preview(2, Contrast::MinMax).render(&source);
+        assert_eq!((rendered.image.width(), rendered.image.height()), (2, 2));
+        assert_eq!(rendered.range, (306, 328));
+        assert_eq!(rendered.image.as_raw(), &[0, 23, 231, 255]);
+        assert_eq!(rendered.framestamp, 3);
+        assert_eq!(rendered.timestamp, source.timestamp());
+        //a flat frame doesn't divide by zero
+        let flat = DcamFrame::new(
+            ImageBuffer::from_pixel(4, 4, Luma([500])),
+            Duration::ZERO,
+            0,
+        );
+        let percentile = Contrast::Percentile {
+            low: 0.1,
+            high: 99.9,
+        };
+        let rendered = preview(1, percentile).render(&flat);
+        assert_eq!(rendered.range, (500, 500));
+        assert!(rendered.image.as_raw().iter().all(|&p| p == 0));
+        let fixed = Contrast::Fixed { low: 0, high: 1000 };
+        assert!(preview(1, fixed)
+            .render(&flat)
+            .image
+            .as_raw()
+            .iter()
+            .all(|&p| p == 127));
+    }
+}
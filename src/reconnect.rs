@@ -0,0 +1,59 @@
+//! Keep a stream running through a dropped USB or CoaXPress link.
+//!
+//! This is opt in, a stream only reconnects once a `ReconnectPolicy` has been given to its source with
+//! `set_reconnect`. Without one losing the camera panics the capture thread like it always did.
+//! When waiting for a frame fails with an error that means the camera has gone away the capture thread
+//! closes everything it holds on the camera and keeps restarting the API until a camera with the same
+//! serial number shows up again. The exposure, ROI, binning, pixel type and trigger source it had are put
+//! back and capture starts again. Writers, subscribers and the frame consumer stay attached throughout,
+//! messages sent to the stream meanwhile are handled once the camera is back. A `Recording` can't be
+//! carried over into the restarted API, so a stream which is recording stops with `ConnectionEvent::GaveUp`
+//! instead. Wait handles from `DcamStream::wait_handle` return `DCAMERR_INVALIDWAITHANDLE` once the old
+//! API is gone, open a new one after `ConnectionEvent::Reconnected`.
+//! `DcamStream::change_connection_consumer` reports what's going on
+
+use crate::error::DcamError;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+/// How the capture thread tries to get a lost camera back. The default retries every second, at most
+/// 30 times
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// time between attempts to find the camera
+    pub retry_interval: Duration,
+    /// stop the stream after this many failed attempts, `None` keeps trying until the stream is stopped
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy {
+            retry_interval: Duration::from_secs(1),
+            max_attempts: Some(30),
+        }
+    }
+}
+
+/// Something happened to the connection to the camera
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// the camera went away, no frames arrive until it's back
+    Lost(DcamError),
+    /// trying to find and reopen the camera for the `attempt`th time
+    Reconnecting { attempt: u32 },
+    /// capture started again after `downtime`, framestamps start again from zero
+    Reconnected { attempts: u32, downtime: Duration },
+    /// we ran out of attempts or the stream was recording, the stream has stopped. Holds the error
+    /// from the last attempt, or the one which lost the camera
+    GaveUp(DcamError),
+}
+
+/// Send `event` to `consumer`, stop sending if it hung up
+pub(crate) fn notify(consumer: &mut Option<Sender<ConnectionEvent>>, event: ConnectionEvent) {
+    if let Some(tx) = consumer {
+        if tx.send(event).is_err() {
+            *consumer = None;
+        }
+    }
+}
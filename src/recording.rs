@@ -0,0 +1,526 @@
+//! Recording frames straight to disk in DCIMG format with the `dcamrec_*` functions.
+//! The driver writes frames as they arrive, so this keeps up with the full camera bandwidth.
+//!
+//! Frames can be read back from a `Recording` for as long as it is open. The DCAM API has no
+//! way to open an existing `.dcimg` file for reading, that requires Hamamatsu's separate DCIMG API.
+//!
+//! Recording handles belong to the API, and dropping any `DcamAPI` releases the API for the whole
+//! process, so that closes every recording which is still open first. A `Recording` which outlives it
+//! returns `DCAMERR_INVALIDRECHANDLE` from then on, keep the API (or the stream recording into it) alive
+//! until you're done with the file
+
+use crate::bindings;
+use crate::frame::DcamFrame;
+use crate::pixel::PixelType;
+use image::{ImageBuffer, Luma};
+use std::ffi::CString;
+use std::os::raw;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{RwLock, RwLockReadGuard};
+
+/// Every `Recording` which is still open, by id. DCAM calls on a recording hold the read lock so
+/// the API can't close it in the middle of one
+static OPEN: RwLock<Vec<(u64, OpenHandle)>> = RwLock::new(Vec::new());
+/// ids for recordings, handles can be reused once the API has closed them
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// a recording handle which can sit in `OPEN`
+pub(crate) struct OpenHandle(bindings::HDCAMREC);
+
+// Handles in `OPEN` are only closed with the write lock held
+unsafe impl Send for OpenHandle {}
+unsafe impl Sync for OpenHandle {}
+
+/// Close every recording which is still open, the API is about to be released
+pub(crate) fn close_all() {
+    let mut open = OPEN.write().expect("recording list poisoned");
+    if !open.is_empty() {
+        log_event!(
+            WARN,
+            recordings = open.len(),
+            "closing recordings before the API is released"
+        );
+    }
+    for (_, handle) in open.drain(..) {
+        unsafe {
+            bindings::dcamrec_close(handle.0);
+        }
+    }
+}
+
+/// A `.dcimg` file being written by the DCAM API. The file is closed when this is dropped, or when
+/// the API is released if that happens first
+pub struct Recording {
+    id: u64,
+    handle: bindings::HDCAMREC,
+    capacity: MetadataCapacity,
+}
+
+/// Number of bytes to set aside for user metadata when a recording is created.
+/// DCAM will refuse to store metadata larger than the space reserved for it
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetadataCapacity {
+    /// bytes of text metadata for each frame
+    pub frame_text: i32,
+    /// bytes of binary metadata for each frame
+    pub frame_binary: i32,
+    /// bytes of text metadata for each session
+    pub session_text: i32,
+    /// bytes of binary metadata for each session
+    pub session_binary: i32,
+    /// bytes of text metadata for the whole file
+    pub file_text: i32,
+    /// bytes of binary metadata for the whole file
+    pub file_binary: i32,
+}
+
+/// Where a piece of user metadata is attached in a recording
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataLocation {
+    /// describes the whole file
+    File,
+    /// describes the current session
+    Session,
+    /// describes the frame with this index
+    Frame(i32),
+}
+
+impl MetadataLocation {
+    /// get the `DCAMREC_METADATAOPTION` and frame index for this location
+    fn option_and_frame(self) -> (i32, i32) {
+        match self {
+            MetadataLocation::File => (
+                bindings::DCAMREC_METADATAOPTION_DCAMREC_METADATAOPTION__LOCATION_FILE,
+                0,
+            ),
+            MetadataLocation::Session => (
+                bindings::DCAMREC_METADATAOPTION_DCAMREC_METADATAOPTION__LOCATION_SESSION,
+                0,
+            ),
+            MetadataLocation::Frame(i) => (
+                bindings::DCAMREC_METADATAOPTION_DCAMREC_METADATAOPTION__LOCATION_FRAME,
+                i,
+            ),
+        }
+    }
+}
+
+/// The two kinds of user metadata DCAM can store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataKind {
+    Text,
+    Binary,
+}
+
+/// A piece of user metadata stored in a recording
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UserMetadata {
+    /// UTF-8 text, e.g. operator notes
+    Text(String),
+    /// arbitrary bytes, e.g. a serialized stage position
+    Binary(Vec<u8>),
+}
+
+impl UserMetadata {
+    /// Which kind of metadata this is
+    pub fn kind(&self) -> MetadataKind {
+        match self {
+            UserMetadata::Text(_) => MetadataKind::Text,
+            UserMetadata::Binary(_) => MetadataKind::Binary,
+        }
+    }
+}
+
+// The DCAM API allows recording handles to be queried from other threads while the driver writes to them
+unsafe impl Send for Recording {}
+unsafe impl Sync for Recording {}
+
+/// Progress of a `Recording`, as reported by `dcamrec_status`
+#[derive(Debug, Clone, Copy)]
+pub struct RecordingStatus {
+    /// index of the session currently being written
+    pub session_index: i32,
+    /// maximum number of frames each session can hold
+    pub max_frames_per_session: i32,
+    /// index of the most recently written frame, negative if nothing has been written yet
+    pub current_frame_index: i32,
+    /// number of frames which were captured but couldn't be written
+    pub missing_frames: i32,
+    /// number of frames written to the file
+    pub total_frames: i32,
+    /// true while the recording is accepting frames
+    pub recording: bool,
+}
+
+impl Recording {
+    /// Create a new DCIMG file at `path` which can hold up to `max_frames` frames. The extension
+    /// of `path` is used for the file, if there isn't one `.dcimg` is added.
+    /// Nothing is written until the recording is attached to a camera with `Camera::dcamcap_record`
+    /// and capture is started
+    pub fn create<P: AsRef<Path>>(path: P, max_frames: i32) -> Result<Recording, i32> {
+        Recording::create_with_metadata(path, max_frames, MetadataCapacity::default())
+    }
+    /// Create a new DCIMG file like `create`, reserving `capacity` bytes for user metadata
+    pub fn create_with_metadata<P: AsRef<Path>>(
+        path: P,
+        max_frames: i32,
+        capacity: MetadataCapacity,
+    ) -> Result<Recording, i32> {
+        let path = path.as_ref();
+        let ext = match path.extension() {
+            Some(e) => e.to_str(),
+            None => Some("dcimg"),
+        };
+        let stem = path.with_extension("");
+        //the A version of dcamrec_open wants plain C strings
+        let (path_c, ext_c) = match (stem.to_str().map(CString::new), ext.map(CString::new)) {
+            (Some(Ok(p)), Some(Ok(e))) => (p, e),
+            _ => return Err(bindings::DCAMERR_DCAMERR_INVALIDPARAM),
+        };
+        let mut dro = bindings::DCAMREC_OPENA::new(path_c.as_ptr(), ext_c.as_ptr(), max_frames);
+        dro.usertextsize = capacity.frame_text;
+        dro.userdatasize = capacity.frame_binary;
+        dro.usertextsize_session = capacity.session_text;
+        dro.userdatasize_session = capacity.session_binary;
+        dro.usertextsize_file = capacity.file_text;
+        dro.userdatasize_file = capacity.file_binary;
+        //the API can't be released between opening the file and listing it
+        let mut open = OPEN.write().expect("recording list poisoned");
+        match unsafe { bindings::dcamrec_openA(&mut dro) } {
+            1 => {
+                assert!(!dro.hrec.is_null(), "null recording pointer");
+                let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+                open.push((id, OpenHandle(dro.hrec)));
+                Ok(Recording {
+                    id,
+                    handle: dro.hrec,
+                    capacity,
+                })
+            }
+            e => Err(e),
+        }
+    }
+    /// Get the api handle for this recording, it's only valid until the API is released
+    pub fn handle(&self) -> bindings::HDCAMREC {
+        self.handle
+    }
+    /// Fail if the API has closed this recording, otherwise it stays open while the guard is held
+    pub(crate) fn check(&self) -> Result<RwLockReadGuard<'static, Vec<(u64, OpenHandle)>>, i32> {
+        let open = OPEN.read().expect("recording list poisoned");
+        match open.iter().any(|(id, _)| *id == self.id) {
+            true => Ok(open),
+            false => Err(bindings::DCAMERR_DCAMERR_INVALIDRECHANDLE),
+        }
+    }
+    /// Get the current progress of the recording
+    pub fn status(&self) -> Result<RecordingStatus, i32> {
+        let _open = self.check()?;
+        let mut drs = bindings::DCAMREC_STATUS::new();
+        match unsafe { bindings::dcamrec_status(self.handle, &mut drs) } {
+            1 => Ok(RecordingStatus {
+                session_index: drs.currentsession_index,
+                max_frames_per_session: drs.maxframecount_per_session,
+                current_frame_index: drs.currentframe_index,
+                missing_frames: drs.missingframe_count,
+                total_frames: drs.totalframecount,
+                recording: drs.flags & bindings::DCAMREC_STATUSFLAG_DCAMREC_STATUSFLAG_RECORDING
+                    != 0,
+            }),
+            e => Err(e),
+        }
+    }
+    /// Stop writing frames to disk, capture keeps running
+    pub fn pause(&self) -> Result<(), i32> {
+        let _open = self.check()?;
+        match unsafe { bindings::dcamrec_pause(self.handle) } {
+            1 => Ok(()),
+            e => Err(e),
+        }
+    }
+    /// Start writing frames to disk again after a call to `pause`
+    pub fn resume(&self) -> Result<(), i32> {
+        let _open = self.check()?;
+        match unsafe { bindings::dcamrec_resume(self.handle) } {
+            1 => Ok(()),
+            e => Err(e),
+        }
+    }
+    /// Get the number of frames which can be read back from the current session
+    pub fn frame_count(&self) -> Result<usize, i32> {
+        let status = self.status()?;
+        Ok((status.current_frame_index + 1).max(0) as usize)
+    }
+    /// call the API's dcamrec_lockframe function to get the information DCAM has about the frame at `index`,
+    /// the caller checks the recording is open
+    fn lock_frame(&self, index: usize) -> Result<bindings::DCAMREC_FRAME, i32> {
+        let mut frame = bindings::DCAMREC_FRAME::new(index as i32);
+        match unsafe { bindings::dcamrec_lockframe(self.handle, &mut frame) } {
+            1 => Ok(frame),
+            e => Err(e),
+        }
+    }
+    /// Copy the recorded frame at `index` out of the file, converted to 16 bit pixels
+    pub fn read_frame(&self, index: usize) -> Result<DcamFrame, i32> {
+        let _open = self.check()?;
+        //locking tells us how big the frame is so we can give dcamrec_copyframe somewhere to put it
+        let mut info = self.lock_frame(index)?;
+        let pixel_type =
+            PixelType::from_dcam(info.type_).ok_or(bindings::DCAMERR_DCAMERR_UNKNOWNDATATYPE)?;
+        if info.width <= 0 || info.height <= 0 || info.rowbytes <= 0 {
+            return Err(bindings::DCAMERR_DCAMERR_INVALIDIMAGE);
+        }
+        let (width, height, rowbytes) = (
+            info.width as usize,
+            info.height as usize,
+            info.rowbytes as usize,
+        );
+        let mut raw: Vec<u8> = vec![0; rowbytes * height];
+        info.buf = raw.as_mut_ptr() as *mut libc::c_void;
+        match unsafe { bindings::dcamrec_copyframe(self.handle, &mut info) } {
+            1 => {}
+            e => return Err(e),
+        }
+        let pixels = pixel_type.to_mono16(&raw, width, height, rowbytes);
+        let image =
+            ImageBuffer::<Luma<u16>, Vec<u16>>::from_raw(width as u32, height as u32, pixels)
+                .ok_or(bindings::DCAMERR_DCAMERR_INVALIDIMAGE)?;
+        Ok(DcamFrame::new(
+            image,
+            info.timestamp.as_duration(),
+            info.framestamp,
+        ))
+    }
+    /// Iterate over every frame recorded so far in the current session
+    pub fn frames(&self) -> Result<RecordedFrames<'_>, i32> {
+        Ok(RecordedFrames {
+            recording: self,
+            next: 0,
+            end: self.frame_count()?,
+        })
+    }
+    /// Get the number of bytes reserved for metadata of `kind` at `location`
+    pub fn metadata_capacity(&self, location: MetadataLocation, kind: MetadataKind) -> i32 {
+        let c = &self.capacity;
+        match (location, kind) {
+            (MetadataLocation::File, MetadataKind::Text) => c.file_text,
+            (MetadataLocation::File, MetadataKind::Binary) => c.file_binary,
+            (MetadataLocation::Session, MetadataKind::Text) => c.session_text,
+            (MetadataLocation::Session, MetadataKind::Binary) => c.session_binary,
+            (MetadataLocation::Frame(_), MetadataKind::Text) => c.frame_text,
+            (MetadataLocation::Frame(_), MetadataKind::Binary) => c.frame_binary,
+        }
+    }
+    /// Attach `metadata` to the recording at `location`. File metadata can be written until the
+    /// recording is closed, session and frame metadata until the session ends
+    pub fn write_metadata(
+        &self,
+        location: MetadataLocation,
+        metadata: &UserMetadata,
+    ) -> Result<(), i32> {
+        let _open = self.check()?;
+        let (option, iframe) = location.option_and_frame();
+        let err = match metadata {
+            UserMetadata::Text(text) => {
+                let dut = bindings::DCAM_USERDATATEXT::new(
+                    option,
+                    iframe,
+                    text.as_ptr() as *mut raw::c_char,
+                    text.len() as i32,
+                );
+                unsafe {
+                    bindings::dcamrec_writemetadata(
+                        self.handle,
+                        &dut as *const bindings::DCAM_USERDATATEXT
+                            as *const bindings::DCAM_METADATAHDR,
+                    )
+                }
+            }
+            UserMetadata::Binary(bin) => {
+                let dub = bindings::DCAM_USERDATABIN::new(
+                    option,
+                    iframe,
+                    bin.as_ptr() as *mut libc::c_void,
+                    bin.len() as i32,
+                );
+                unsafe {
+                    bindings::dcamrec_writemetadata(
+                        self.handle,
+                        &dub as *const bindings::DCAM_USERDATABIN
+                            as *const bindings::DCAM_METADATAHDR,
+                    )
+                }
+            }
+        };
+        match err {
+            1 => Ok(()),
+            e => Err(e),
+        }
+    }
+    /// Read back the metadata of `kind` stored at `location`
+    pub fn read_metadata(
+        &self,
+        location: MetadataLocation,
+        kind: MetadataKind,
+    ) -> Result<UserMetadata, i32> {
+        let _open = self.check()?;
+        let (option, iframe) = location.option_and_frame();
+        let capacity = self.metadata_capacity(location, kind);
+        let mut buf: Vec<u8> = vec![0; capacity.max(0) as usize];
+        let (err, len) = match kind {
+            MetadataKind::Text => {
+                let mut dut = bindings::DCAM_USERDATATEXT::new(
+                    option,
+                    iframe,
+                    buf.as_mut_ptr() as *mut raw::c_char,
+                    capacity,
+                );
+                let err = unsafe {
+                    bindings::dcamrec_copymetadata(
+                        self.handle,
+                        &mut dut as *mut bindings::DCAM_USERDATATEXT
+                            as *mut bindings::DCAM_METADATAHDR,
+                    )
+                };
+                (err, dut.text_len)
+            }
+            MetadataKind::Binary => {
+                let mut dub = bindings::DCAM_USERDATABIN::new(
+                    option,
+                    iframe,
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    capacity,
+                );
+                let err = unsafe {
+                    bindings::dcamrec_copymetadata(
+                        self.handle,
+                        &mut dub as *mut bindings::DCAM_USERDATABIN
+                            as *mut bindings::DCAM_METADATAHDR,
+                    )
+                };
+                (err, dub.bin_len)
+            }
+        };
+        if err != 1 {
+            return Err(err);
+        }
+        //DCAM reports how much it actually copied
+        buf.truncate(len.clamp(0, capacity.max(0)) as usize);
+        Ok(to_user_metadata(kind, buf))
+    }
+    /// Read back the metadata of `kind` attached to `count` frames, starting with `first_frame`
+    pub fn read_frame_metadata(
+        &self,
+        first_frame: i32,
+        count: usize,
+        kind: MetadataKind,
+    ) -> Result<Vec<UserMetadata>, i32> {
+        let _open = self.check()?;
+        let unit = self
+            .metadata_capacity(MetadataLocation::Frame(first_frame), kind)
+            .max(0);
+        let mut buf: Vec<u8> = vec![0; unit as usize * count];
+        let mut sizes: Vec<i32> = vec![0; count];
+        let (err, outcount) = match kind {
+            MetadataKind::Text => {
+                let mut codepages: Vec<i32> = vec![0; count];
+                let mut dtb = bindings::DCAM_METADATATEXTBLOCK::new(
+                    first_frame,
+                    count as i32,
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    sizes.as_mut_ptr(),
+                    unit,
+                    codepages.as_mut_ptr(),
+                );
+                let err = unsafe {
+                    bindings::dcamrec_copymetadatablock(
+                        self.handle,
+                        &mut dtb as *mut bindings::DCAM_METADATATEXTBLOCK
+                            as *mut bindings::DCAM_METADATABLOCKHDR,
+                    )
+                };
+                (err, dtb.hdr.outcount)
+            }
+            MetadataKind::Binary => {
+                let mut dbb = bindings::DCAM_METADATABINBLOCK::new(
+                    first_frame,
+                    count as i32,
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    sizes.as_mut_ptr(),
+                    unit,
+                );
+                let err = unsafe {
+                    bindings::dcamrec_copymetadatablock(
+                        self.handle,
+                        &mut dbb as *mut bindings::DCAM_METADATABINBLOCK
+                            as *mut bindings::DCAM_METADATABLOCKHDR,
+                    )
+                };
+                (err, dbb.hdr.outcount)
+            }
+        };
+        if err != 1 {
+            return Err(err);
+        }
+        //each frame's metadata sits in its own unit sized slot
+        Ok(sizes
+            .iter()
+            .take(outcount.clamp(0, count as i32) as usize)
+            .enumerate()
+            .map(|(i, size)| {
+                let start = i * unit as usize;
+                let len = (*size).clamp(0, unit) as usize;
+                to_user_metadata(kind, buf[start..start + len].to_vec())
+            })
+            .collect())
+    }
+}
+
+/// Iterator over the frames stored in a `Recording`, created by `Recording::frames`
+pub struct RecordedFrames<'a> {
+    recording: &'a Recording,
+    next: usize,
+    end: usize,
+}
+
+impl Iterator for RecordedFrames<'_> {
+    type Item = Result<DcamFrame, i32>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.end {
+            return None;
+        }
+        let frame = self.recording.read_frame(self.next);
+        self.next += 1;
+        Some(frame)
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.end - self.next;
+        (remaining, Some(remaining))
+    }
+}
+
+/// wrap bytes copied out of a recording as `UserMetadata`, text is cut at the first null byte
+fn to_user_metadata(kind: MetadataKind, mut bytes: Vec<u8>) -> UserMetadata {
+    match kind {
+        MetadataKind::Text => {
+            if let Some(end) = bytes.iter().position(|b| *b == 0) {
+                bytes.truncate(end);
+            }
+            UserMetadata::Text(String::from_utf8_lossy(&bytes).into_owned())
+        }
+        MetadataKind::Binary => UserMetadata::Binary(bytes),
+    }
+}
+
+/// Automatically close the file when our handle is dropped, unless the API already has
+impl Drop for Recording {
+    fn drop(&mut self) {
+        let mut open = OPEN.write().expect("recording list poisoned");
+        if let Some(i) = open.iter().position(|(id, _)| *id == self.id) {
+            open.swap_remove(i);
+            unsafe {
+                bindings::dcamrec_close(self.handle);
+            }
+        }
+    }
+}
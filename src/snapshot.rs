@@ -0,0 +1,169 @@
+//! Save single frames as 16 bit PNG or TIFF files with the camera settings embedded,
+//! as PNG text chunks or TIFF tags
+
+use crate::frame::DcamFrame;
+use crate::writer::AcquisitionInfo;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+use tiff::encoder::{colortype, TiffEncoder};
+use tiff::tags::Tag;
+
+/// File formats a snapshot can be saved in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotFormat {
+    Png,
+    Tiff,
+}
+
+impl SnapshotFormat {
+    /// Pick a format from the extension of `path`
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<SnapshotFormat> {
+        let extension = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "png" => Some(SnapshotFormat::Png),
+            "tif" | "tiff" => Some(SnapshotFormat::Tiff),
+            _ => None,
+        }
+    }
+}
+
+/// Ways taking a snapshot can fail
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// DCAM returned this error code while capturing the frame
+    Dcam(i32),
+    /// the file couldn't be written
+    Io(io::Error),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Dcam(e) => write!(f, "DCAM error {:#010x}", e),
+            SnapshotError::Io(e) => write!(f, "couldn't save snapshot: {}", e),
+        }
+    }
+}
+
+impl Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+/// Save `frame` to `path` as a PNG or TIFF, depending on the extension, recording the settings in `info`
+pub fn save_snapshot<P: AsRef<Path>>(
+    path: P,
+    frame: &DcamFrame,
+    info: &AcquisitionInfo,
+) -> io::Result<()> {
+    match SnapshotFormat::from_path(&path) {
+        Some(format) => save_snapshot_as(path, format, frame, info),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "snapshots must be saved as .png, .tif or .tiff",
+        )),
+    }
+}
+
+/// Save `frame` to `path` in `format`, recording the settings in `info`
+pub fn save_snapshot_as<P: AsRef<Path>>(
+    path: P,
+    format: SnapshotFormat,
+    frame: &DcamFrame,
+    info: &AcquisitionInfo,
+) -> io::Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    match format {
+        SnapshotFormat::Png => save_png(file, frame, info),
+        SnapshotFormat::Tiff => save_tiff(file, frame, info),
+    }
+}
+
+/// Settings we record as `(key, value)` pairs
+fn settings(frame: &DcamFrame, info: &AcquisitionInfo) -> Vec<(&'static str, String)> {
+    let [left, top, width, height] = info.roi;
+    let mut settings = vec![
+        ("Model", info.model.clone()),
+        ("SerialNumber", info.serial_number.clone()),
+        ("Exposure", format!("{} s", info.exposure)),
+        ("ROI", format!("{},{},{},{}", left, top, width, height)),
+        ("Binning", info.binning.to_string()),
+        ("PixelType", format!("{:?}", info.pixel_type)),
+        (
+            "Timestamp",
+            format!("{} s", frame.timestamp().as_secs_f64()),
+        ),
+        ("FrameStamp", frame.framestamp().to_string()),
+    ];
+    if let Some(temperature) = info.sensor_temperature {
+        settings.push(("SensorTemperature", format!("{} C", temperature)));
+    }
+    if let Some([x, y]) = info.pixel_size {
+        settings.push(("PixelSize", format!("{},{} um", x, y)));
+    }
+    settings
+}
+
+/// write a 16 bit grayscale PNG with a text chunk for each setting
+fn save_png<W: io::Write>(writer: W, frame: &DcamFrame, info: &AcquisitionInfo) -> io::Result<()> {
+    let image = frame.image();
+    let mut encoder = png::Encoder::new(writer, image.width(), image.height());
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Sixteen);
+    for (key, value) in settings(frame, info) {
+        encoder.add_text_chunk(key.to_string(), value)?;
+    }
+    encoder.add_text_chunk(
+        "Software".to_string(),
+        concat!("free_willy ", env!("CARGO_PKG_VERSION")).to_string(),
+    )?;
+    //PNG stores 16 bit samples big endian
+    let data: Vec<u8> = image
+        .as_raw()
+        .iter()
+        .flat_map(|p| p.to_be_bytes())
+        .collect();
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// write a 16 bit grayscale TIFF with the settings in the ImageDescription tag
+fn save_tiff<W: io::Write + io::Seek>(
+    writer: W,
+    frame: &DcamFrame,
+    info: &AcquisitionInfo,
+) -> io::Result<()> {
+    let image = frame.image();
+    let description: Vec<String> = settings(frame, info)
+        .into_iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect();
+    let to_io = |e: tiff::TiffError| io::Error::other(e);
+    let mut encoder = TiffEncoder::new(writer).map_err(to_io)?;
+    let mut tiff = encoder
+        .new_image::<colortype::Gray16>(image.width(), image.height())
+        .map_err(to_io)?;
+    let directory = tiff.encoder();
+    directory
+        .write_tag(Tag::ImageDescription, description.join("\n").as_str())
+        .map_err(to_io)?;
+    directory.write_tag(Tag::Make, "Hamamatsu").map_err(to_io)?;
+    directory
+        .write_tag(Tag::Model, info.model.as_str())
+        .map_err(to_io)?;
+    directory
+        .write_tag(
+            Tag::Software,
+            concat!("free_willy ", env!("CARGO_PKG_VERSION")),
+        )
+        .map_err(to_io)?;
+    tiff.write_data(image.as_raw()).map_err(to_io)
+}
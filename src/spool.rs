@@ -0,0 +1,509 @@
+//! Spooling raw frames to disk as fast as the disk allows.
+//!
+//! Frames are written exactly as they sit in the camera buffer (packed pixels, row padding and all)
+//! to preallocated files in a directory. A `spool.json` sidecar describes the layout of the frames,
+//! the camera settings and the timestamp and framestamp of every frame. `SpoolReader` memory maps
+//! the files back so frames can be accessed by index without copying them
+
+use crate::frame::DcamFrame;
+use crate::pixel::PixelType;
+use crate::writer::{AcquisitionInfo, FrameWriter};
+use image::{ImageBuffer, Luma};
+use memmap2::Mmap;
+use serde_json::{json, Value};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::ops::Index;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Name of the sidecar file in a spool directory
+const SIDECAR: &str = "spool.json";
+
+/// How the frames in a spool are laid out in memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpoolLayout {
+    pub width: usize,
+    pub height: usize,
+    /// bytes between the start of each row
+    pub rowbytes: usize,
+    /// bytes between the start of each frame
+    pub frame_bytes: usize,
+    pub pixel_type: PixelType,
+}
+
+/// Writes raw frames to a directory of preallocated files
+pub struct SpoolWriter {
+    dir: PathBuf,
+    frames_per_file: usize,
+    layout: Option<SpoolLayout>,
+    info: Option<AcquisitionInfo>,
+    /// the file we are currently writing to
+    file: Option<File>,
+    /// names of every file we've created
+    files: Vec<String>,
+    frames_in_file: usize,
+    /// camera timestamp and framestamp of every frame
+    stamps: Vec<(Duration, i32)>,
+    finished: bool,
+}
+
+impl SpoolWriter {
+    /// Create a new spool in the directory at `dir`, which must not exist yet.
+    /// Each file holds up to `frames_per_file` frames, space for these is allocated when the file is created
+    pub fn create<P: AsRef<Path>>(dir: P, frames_per_file: usize) -> io::Result<SpoolWriter> {
+        assert!(frames_per_file > 0, "files must hold at least one frame");
+        fs::create_dir(dir.as_ref())?;
+        Ok(SpoolWriter {
+            dir: dir.as_ref().to_path_buf(),
+            frames_per_file,
+            layout: None,
+            info: None,
+            file: None,
+            files: Vec::new(),
+            frames_in_file: 0,
+            stamps: Vec::new(),
+            finished: false,
+        })
+    }
+    /// Prepare to write raw frames laid out as described by `layout`, captured with the settings in `info`
+    pub fn begin_raw(&mut self, info: &AcquisitionInfo, layout: SpoolLayout) -> io::Result<()> {
+        self.info = Some(info.clone());
+        self.set_layout(layout)
+    }
+    /// Number of frames written so far
+    pub fn frame_count(&self) -> usize {
+        self.stamps.len()
+    }
+    /// Check `layout` matches any frames we've already written
+    fn set_layout(&mut self, layout: SpoolLayout) -> io::Result<()> {
+        if *self.layout.get_or_insert(layout) != layout {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "all frames in a spool must have the same layout",
+            ));
+        }
+        Ok(())
+    }
+    /// Append one frame of raw data exactly as the camera delivered it, `raw` must be at least
+    /// `frame_bytes` long. `begin_raw` must have been called first
+    pub fn write_raw(
+        &mut self,
+        raw: &[u8],
+        timestamp: Duration,
+        framestamp: i32,
+    ) -> io::Result<()> {
+        if self.finished {
+            return Err(io::Error::other("can't write to a finished spool"));
+        }
+        let frame_bytes = match self.layout {
+            Some(layout) => layout.frame_bytes,
+            None => {
+                return Err(io::Error::other(
+                    "the frame layout must be set before writing raw frames",
+                ))
+            }
+        };
+        if raw.len() < frame_bytes {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "raw frame is smaller than the spool's frame size",
+            ));
+        }
+        if self.file.is_none() || self.frames_in_file == self.frames_per_file {
+            self.next_file(frame_bytes)?;
+        }
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(&raw[..frame_bytes])?;
+        }
+        self.frames_in_file += 1;
+        self.stamps.push((timestamp, framestamp));
+        Ok(())
+    }
+    /// Close the current file and start a new, preallocated, one
+    fn next_file(&mut self, frame_bytes: usize) -> io::Result<()> {
+        self.close_file()?;
+        let name = format!("{:06}.raw", self.files.len());
+        let file = File::create(self.dir.join(&name))?;
+        //reserve the space up front so the filesystem doesn't have to grow the file while we stream
+        file.set_len((self.frames_per_file * frame_bytes) as u64)?;
+        self.file = Some(file);
+        self.files.push(name);
+        self.frames_in_file = 0;
+        //the sidecar is always kept up to date with the list of files
+        self.write_sidecar()
+    }
+    /// Trim the unused space off the end of the current file and close it
+    fn close_file(&mut self) -> io::Result<()> {
+        if let (Some(file), Some(layout)) = (self.file.take(), self.layout) {
+            file.set_len((self.frames_in_file * layout.frame_bytes) as u64)?;
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+    /// Write the sidecar describing everything written so far
+    fn write_sidecar(&self) -> io::Result<()> {
+        let layout = self.layout.as_ref();
+        let timestamps: Vec<f64> = self.stamps.iter().map(|s| s.0.as_secs_f64()).collect();
+        let framestamps: Vec<i32> = self.stamps.iter().map(|s| s.1).collect();
+        let sidecar = json!({
+            "width": layout.map(|l| l.width),
+            "height": layout.map(|l| l.height),
+            "rowbytes": layout.map(|l| l.rowbytes),
+            "frame_bytes": layout.map(|l| l.frame_bytes),
+            "pixel_type": layout.map(|l| l.pixel_type.to_dcam()),
+            "pixel_type_name": layout.map(|l| format!("{:?}", l.pixel_type)),
+            "frames_per_file": self.frames_per_file,
+            "frame_count": self.stamps.len(),
+            "files": self.files,
+            "camera": self.info.as_ref().map(|i| i.to_json()),
+            "timestamps": timestamps,
+            "framestamps": framestamps,
+        });
+        let tmp = self.dir.join("spool.json.tmp");
+        fs::write(&tmp, serde_json::to_vec(&sidecar)?)?;
+        fs::rename(tmp, self.dir.join(SIDECAR))
+    }
+}
+
+impl FrameWriter for SpoolWriter {
+    fn begin(&mut self, info: &AcquisitionInfo) -> io::Result<()> {
+        self.info = Some(info.clone());
+        Ok(())
+    }
+    /// Frames written this way have already been unpacked, so they are spooled as 16 bit pixels
+    fn write_frame(&mut self, frame: &DcamFrame) -> io::Result<()> {
+        let image = frame.image();
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        self.set_layout(SpoolLayout {
+            width,
+            height,
+            rowbytes: width * 2,
+            frame_bytes: width * height * 2,
+            pixel_type: PixelType::Mono16,
+        })?;
+        let pixels = image.as_raw();
+        //spools are little endian, so is every machine DCAM runs on
+        let raw =
+            unsafe { std::slice::from_raw_parts(pixels.as_ptr() as *const u8, pixels.len() * 2) };
+        self.write_raw(raw, frame.timestamp(), frame.framestamp())
+    }
+    fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        self.close_file()?;
+        self.write_sidecar()
+    }
+}
+
+impl Drop for SpoolWriter {
+    fn drop(&mut self) {
+        //make sure the sidecar lists every frame even if nobody called finish
+        let _ = self.finish();
+    }
+}
+
+/// Read access to a spool written by `SpoolWriter`. Indexing gives the raw bytes of each frame
+pub struct SpoolReader {
+    layout: SpoolLayout,
+    frames_per_file: usize,
+    maps: Vec<Mmap>,
+    info: Value,
+    stamps: Vec<(Duration, i32)>,
+}
+
+impl SpoolReader {
+    /// Open the spool in the directory at `dir`
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<SpoolReader> {
+        let dir = dir.as_ref();
+        let sidecar: Value = serde_json::from_slice(&fs::read(dir.join(SIDECAR))?)?;
+        let invalid = |what: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("spool sidecar is missing {}", what),
+            )
+        };
+        let field = |name: &str| {
+            sidecar[name]
+                .as_u64()
+                .map(|v| v as usize)
+                .ok_or_else(|| invalid(name))
+        };
+        let pixel_type = sidecar["pixel_type"]
+            .as_i64()
+            .and_then(|v| PixelType::from_dcam(v as i32))
+            .ok_or_else(|| invalid("pixel_type"))?;
+        let layout = SpoolLayout {
+            width: field("width")?,
+            height: field("height")?,
+            rowbytes: field("rowbytes")?,
+            frame_bytes: field("frame_bytes")?,
+            pixel_type,
+        };
+        let frames_per_file = field("frames_per_file")?;
+        //we divide by this to find a frame's file
+        if frames_per_file == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "spool sidecar has no frames per file",
+            ));
+        }
+        let frame_count = field("frame_count")?;
+        let timestamps = sidecar["timestamps"]
+            .as_array()
+            .ok_or_else(|| invalid("timestamps"))?;
+        let framestamps = sidecar["framestamps"]
+            .as_array()
+            .ok_or_else(|| invalid("framestamps"))?;
+        let stamps = timestamps
+            .iter()
+            .zip(framestamps)
+            .take(frame_count)
+            .map(|(t, f)| Some((Duration::from_secs_f64(t.as_f64()?), f.as_i64()? as i32)))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| invalid("timestamps"))?;
+        let mut maps = Vec::new();
+        for name in sidecar["files"]
+            .as_array()
+            .ok_or_else(|| invalid("files"))?
+        {
+            let name = name.as_str().ok_or_else(|| invalid("files"))?;
+            let file = File::open(dir.join(name))?;
+            //the files are never modified once the writer is finished with them
+            maps.push(unsafe { Mmap::map(&file)? });
+        }
+        let reader = SpoolReader {
+            layout,
+            frames_per_file,
+            maps,
+            info: sidecar["camera"].clone(),
+            stamps,
+        };
+        //make sure every frame the sidecar lists is actually on disk
+        if reader.stamps.len() < frame_count
+            || (frame_count > 0 && reader.locate(frame_count - 1).is_none())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "spool is missing frames listed in its sidecar",
+            ));
+        }
+        Ok(reader)
+    }
+    /// Number of frames in the spool
+    pub fn len(&self) -> usize {
+        self.stamps.len()
+    }
+    /// `true` if there are no frames in the spool
+    pub fn is_empty(&self) -> bool {
+        self.stamps.is_empty()
+    }
+    /// How the frames are laid out
+    pub fn layout(&self) -> SpoolLayout {
+        self.layout
+    }
+    /// Camera settings recorded in the sidecar, as JSON
+    pub fn camera_settings(&self) -> &Value {
+        &self.info
+    }
+    /// Camera timestamp of the frame at `index`
+    pub fn timestamp(&self, index: usize) -> Duration {
+        self.stamps[index].0
+    }
+    /// Framestamp of the frame at `index`
+    pub fn framestamp(&self, index: usize) -> i32 {
+        self.stamps[index].1
+    }
+    /// Copy the frame at `index` out of the spool, converted to 16 bit pixels
+    pub fn frame(&self, index: usize) -> DcamFrame {
+        let layout = self.layout;
+        let pixels =
+            layout
+                .pixel_type
+                .to_mono16(&self[index], layout.width, layout.height, layout.rowbytes);
+        let image = ImageBuffer::<Luma<u16>, Vec<u16>>::from_raw(
+            layout.width as u32,
+            layout.height as u32,
+            pixels,
+        )
+        .expect("spool layout doesn't match frame size");
+        DcamFrame::new(image, self.timestamp(index), self.framestamp(index))
+    }
+    /// Find the bytes of the frame at `index`
+    fn locate(&self, index: usize) -> Option<&[u8]> {
+        let map = self.maps.get(index / self.frames_per_file)?;
+        let start = (index % self.frames_per_file) * self.layout.frame_bytes;
+        map.get(start..start + self.layout.frame_bytes)
+    }
+}
+
+///Make it so we can pull one frame's worth of raw data by index
+impl Index<usize> for SpoolReader {
+    type Output = [u8];
+    fn index(&self, index: usize) -> &[u8] {
+        assert!(index < self.len());
+        self.locate(index).expect("spool file is too short")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{frame, info, temp_path};
+
+    /// 2x2 16 bit frames with 2 bytes of padding after each row
+    const LAYOUT: SpoolLayout = SpoolLayout {
+        width: 2,
+        height: 2,
+        rowbytes: 6,
+        frame_bytes: 12,
+        pixel_type: PixelType::Mono16,
+    };
+
+    /// raw bytes of frame `n` laid out as `LAYOUT`, with a few bytes past the end of the frame
+    fn raw(n: u8) -> Vec<u8> {
+        vec![
+            n, 1, n, 2, 0xEE, 0xEE, //
+            n, 3, n, 4, 0xEE, 0xEE, //
+            0xFF, 0xFF,
+        ]
+    }
+
+    #[test]
+    fn raw_frames_across_files() {
+        let dir = temp_path("spool_raw");
+        let mut writer = SpoolWriter::create(&dir, 2).unwrap();
+        writer.begin_raw(&info(), LAYOUT).unwrap();
+        for n in 0..3 {
+            writer
+                .write_raw(
+                    &raw(n),
+                    Duration::from_millis(10 * n as u64),
+                    100 + n as i32,
+                )
+                .unwrap();
+        }
+        writer.finish().unwrap();
+        //the last file is trimmed to the frame it holds
+        assert_eq!(fs::metadata(dir.join("000000.raw")).unwrap().len(), 24);
+        assert_eq!(fs::metadata(dir.join("000001.raw")).unwrap().len(), 12);
+
+        let reader = SpoolReader::open(&dir).unwrap();
+        assert_eq!(reader.len(), 3);
+        assert_eq!(reader.layout(), LAYOUT);
+        assert_eq!(*reader.camera_settings(), info().to_json());
+        for n in 0..3 {
+            //frames are stored as the camera delivered them, padding and all
+            assert_eq!(reader[n], raw(n as u8)[..12]);
+            assert_eq!(reader.timestamp(n), Duration::from_millis(10 * n as u64));
+            assert_eq!(reader.framestamp(n), 100 + n as i32);
+            //little endian, the second byte is the high one
+            let lo = n as u16;
+            let frame = reader.frame(n);
+            assert_eq!(
+                frame.image().as_raw(),
+                &[0x100 | lo, 0x200 | lo, 0x300 | lo, 0x400 | lo],
+                "padding is dropped when converting"
+            );
+            assert_eq!(frame.framestamp(), 100 + n as i32);
+        }
+        drop(reader);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unpacked_frames_round_trip() {
+        let dir = temp_path("spool_frames");
+        let mut writer = SpoolWriter::create(&dir, 4).unwrap();
+        writer.begin(&info()).unwrap();
+        let frame = frame(3, 2, 7);
+        writer.write_frame(&frame).unwrap();
+        drop(writer);
+
+        let reader = SpoolReader::open(&dir).unwrap();
+        let layout = reader.layout();
+        assert_eq!((layout.width, layout.height), (3, 2));
+        assert_eq!(layout.rowbytes, 6);
+        assert_eq!(layout.pixel_type, PixelType::Mono16);
+        let read = reader.frame(0);
+        assert_eq!(read.image(), frame.image());
+        assert_eq!(read.timestamp(), frame.timestamp());
+        assert_eq!(read.framestamp(), 7);
+        drop(reader);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn empty_spool() {
+        let dir = temp_path("spool_empty");
+        let mut writer = SpoolWriter::create(&dir, 4).unwrap();
+        writer.begin_raw(&info(), LAYOUT).unwrap();
+        writer.finish().unwrap();
+        let reader = SpoolReader::open(&dir).unwrap();
+        assert!(reader.is_empty());
+        drop(reader);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_frames_are_caught() {
+        let dir = temp_path("spool_short");
+        let mut writer = SpoolWriter::create(&dir, 4).unwrap();
+        writer.begin_raw(&info(), LAYOUT).unwrap();
+        for n in 0..2 {
+            writer.write_raw(&raw(n), Duration::ZERO, n as i32).unwrap();
+        }
+        writer.finish().unwrap();
+        //lose the end of the second frame
+        File::options()
+            .write(true)
+            .open(dir.join("000000.raw"))
+            .unwrap()
+            .set_len(20)
+            .unwrap();
+        let error = SpoolReader::open(&dir).err().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn zero_frames_per_file_is_invalid() {
+        let dir = temp_path("spool_zero");
+        let mut writer = SpoolWriter::create(&dir, 4).unwrap();
+        writer.begin_raw(&info(), LAYOUT).unwrap();
+        writer.write_raw(&raw(0), Duration::ZERO, 0).unwrap();
+        writer.finish().unwrap();
+        let sidecar = dir.join(SIDECAR);
+        let mut json: Value = serde_json::from_slice(&fs::read(&sidecar).unwrap()).unwrap();
+        json["frames_per_file"] = json!(0);
+        fs::write(&sidecar, serde_json::to_vec(&json).unwrap()).unwrap();
+        let error = SpoolReader::open(&dir).err().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn raw_frames_need_a_layout() {
+        let dir = temp_path("spool_layout");
+        let mut writer = SpoolWriter::create(&dir, 4).unwrap();
+        assert!(writer.write_raw(&raw(0), Duration::ZERO, 0).is_err());
+        writer.begin_raw(&info(), LAYOUT).unwrap();
+        //too short for a frame
+        let error = writer
+            .write_raw(&raw(0)[..8], Duration::ZERO, 0)
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        let other = SpoolLayout {
+            rowbytes: 4,
+            frame_bytes: 8,
+            ..LAYOUT
+        };
+        let error = writer.begin_raw(&info(), other).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(writer.frame_count(), 0);
+        drop(writer);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
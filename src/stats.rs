@@ -0,0 +1,265 @@
+//! Measured frame rate, throughput and latency of a running stream.
+//!
+//! The capture thread records a small sample for every frame and every time a frame is overdue, a
+//! `StatsHandle` turns the samples from the last few seconds into `StreamStats`. Recording a sample is a
+//! push onto a queue behind an uncontended lock, so stats are always on. Comparing the measured frame
+//! rate and lost frames with what the camera is set to shows whether frames go missing before they reach
+//! us, latency and queue depth show whether our consumers are keeping up
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// A summary of the last `window` of a stream
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct StreamStats {
+    /// how far back these stats look
+    pub window: Duration,
+    /// frames handled in the window
+    pub frames: usize,
+    /// frames per second arriving from the camera
+    pub frame_rate: f64,
+    /// bytes per second arriving from the camera, as laid out in the DCAM buffer
+    pub bytes_per_second: f64,
+    /// mean time from the camera reporting a frame to every consumer having it
+    pub mean_latency: Duration,
+    /// longest time from the camera reporting a frame to every consumer having it
+    pub max_latency: Duration,
+    /// frames waiting for the frame consumer after the newest frame, `None` without a consumer
+    pub queue_depth: Option<usize>,
+    /// frames the camera captured but we never saw, in the window
+    pub lost_frames: u64,
+    /// times no frame arrived within the frame timeout, in the window. A stream which goes three frame
+    /// timeouts without a frame counts three
+    pub wait_timeouts: u64,
+    /// frames handled since the stream started
+    pub total_frames: u64,
+    /// frames lost since the stream started
+    pub total_lost_frames: u64,
+    /// frame timeouts since the stream started
+    pub total_wait_timeouts: u64,
+}
+
+impl fmt::Display for StreamStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.1} fps, {:.1} MB/s, latency {:.2?} mean {:.2?} max, {} lost, {} timeouts",
+            self.frame_rate,
+            self.bytes_per_second / 1e6,
+            self.mean_latency,
+            self.max_latency,
+            self.lost_frames,
+            self.wait_timeouts
+        )?;
+        if let Some(depth) = self.queue_depth {
+            write!(f, ", {} queued", depth)?;
+        }
+        Ok(())
+    }
+}
+
+/// What we know about one frame
+struct Sample {
+    /// when the camera reported the frame
+    ready: Instant,
+    bytes: usize,
+    latency: Duration,
+    /// frames lost between this frame and the one before it
+    lost: u64,
+}
+
+struct Window {
+    length: Duration,
+    samples: VecDeque<Sample>,
+    timeouts: VecDeque<Instant>,
+    queue_depth: Option<usize>,
+    total_frames: u64,
+    total_lost_frames: u64,
+    total_wait_timeouts: u64,
+    /// when the last frame arrived, capture started or a timeout was counted
+    quiet_since: Instant,
+    /// how often the camera should deliver frames with its current settings
+    expected_interval: Option<Duration>,
+    /// overrides `expected_interval` as the frame timeout
+    frame_timeout: Option<Duration>,
+}
+
+impl Window {
+    /// Forget anything older than the window
+    fn prune(&mut self, now: Instant) {
+        let length = self.length;
+        while matches!(self.samples.front(), Some(s) if now.duration_since(s.ready) > length) {
+            self.samples.pop_front();
+        }
+        while matches!(self.timeouts.front(), Some(t) if now.duration_since(*t) > length) {
+            self.timeouts.pop_front();
+        }
+    }
+}
+
+/// Shared view of a stream's statistics, clone it to read them from another thread
+#[derive(Clone)]
+pub struct StatsHandle {
+    window: Arc<Mutex<Window>>,
+}
+
+impl StatsHandle {
+    /// Make an empty set of stats covering the last `window`
+    pub(crate) fn new(window: Duration) -> StatsHandle {
+        StatsHandle {
+            window: Arc::new(Mutex::new(Window {
+                length: window,
+                samples: VecDeque::new(),
+                timeouts: VecDeque::new(),
+                queue_depth: None,
+                total_frames: 0,
+                total_lost_frames: 0,
+                total_wait_timeouts: 0,
+                quiet_since: Instant::now(),
+                expected_interval: None,
+                frame_timeout: None,
+            })),
+        }
+    }
+    fn lock(&self) -> MutexGuard<'_, Window> {
+        self.window.lock().expect("capture thread panicked")
+    }
+    /// Record a frame the camera reported at `ready` which every consumer now has
+    pub(crate) fn record_frame(
+        &self,
+        ready: Instant,
+        bytes: usize,
+        lost: u64,
+        queue_depth: Option<usize>,
+    ) {
+        let now = Instant::now();
+        let mut window = self.lock();
+        window.samples.push_back(Sample {
+            ready,
+            bytes,
+            latency: now.duration_since(ready),
+            lost,
+        });
+        window.queue_depth = queue_depth;
+        window.quiet_since = ready;
+        window.total_frames += 1;
+        window.total_lost_frames += lost;
+        window.prune(now);
+    }
+    /// Capture (re)started with settings which should deliver a frame every `expected_interval`,
+    /// `None` if frames come when the camera is triggered
+    pub(crate) fn capture_started(&self, expected_interval: Option<Duration>) {
+        let mut window = self.lock();
+        window.expected_interval = expected_interval;
+        window.quiet_since = Instant::now();
+    }
+    /// The capture thread's wait for a frame timed out. This is only counted as a frame timeout once no
+    /// frame has arrived for longer than the frame timeout, the capture thread stops waiting much more
+    /// often than that to check its messages
+    pub(crate) fn record_timeout(&self) {
+        let now = Instant::now();
+        let mut window = self.lock();
+        let timeout = match window.frame_timeout.or(window.expected_interval) {
+            Some(timeout) => timeout,
+            //triggered frames can't be late
+            None => return,
+        };
+        if now.duration_since(window.quiet_since) <= timeout {
+            return;
+        }
+        window.quiet_since = now;
+        window.timeouts.push_back(now);
+        window.total_wait_timeouts += 1;
+        window.prune(now);
+    }
+    /// Count a frame timeout whenever no frame arrives for `timeout`. By default this is the frame
+    /// interval the camera runs at, and frames are never late when they come from a trigger. `None`
+    /// goes back to the default
+    pub fn set_frame_timeout(&self, timeout: Option<Duration>) {
+        self.lock().frame_timeout = timeout;
+    }
+    /// Change how far back the stats look
+    pub fn set_window(&self, window: Duration) {
+        self.lock().length = window;
+    }
+    /// Summarize the current window
+    pub fn stats(&self) -> StreamStats {
+        let now = Instant::now();
+        let mut window = self.lock();
+        window.prune(now);
+        let frames = window.samples.len();
+        //rates are measured between the first and last frame so a short window isn't diluted
+        let span = match (window.samples.front(), window.samples.back()) {
+            (Some(first), Some(last)) => last.ready.duration_since(first.ready).as_secs_f64(),
+            _ => 0.0,
+        };
+        let (frame_rate, bytes_per_second) = if frames > 1 && span > 0.0 {
+            //the first frame's bytes arrived before the span started
+            let bytes: usize = window.samples.iter().skip(1).map(|s| s.bytes).sum();
+            ((frames - 1) as f64 / span, bytes as f64 / span)
+        } else {
+            (0.0, 0.0)
+        };
+        let total_latency: Duration = window.samples.iter().map(|s| s.latency).sum();
+        StreamStats {
+            window: window.length,
+            frames,
+            frame_rate,
+            bytes_per_second,
+            mean_latency: total_latency.checked_div(frames as u32).unwrap_or_default(),
+            max_latency: window
+                .samples
+                .iter()
+                .map(|s| s.latency)
+                .max()
+                .unwrap_or_default(),
+            queue_depth: window.queue_depth,
+            lost_frames: window.samples.iter().map(|s| s.lost).sum(),
+            wait_timeouts: window.timeouts.len() as u64,
+            total_frames: window.total_frames,
+            total_lost_frames: window.total_lost_frames,
+            total_wait_timeouts: window.total_wait_timeouts,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn timeouts_need_a_quiet_frame_interval() {
+        let stats = StatsHandle::new(Duration::from_secs(5));
+        stats.capture_started(Some(Duration::from_millis(50)));
+        stats.record_timeout();
+        assert_eq!(stats.stats().total_wait_timeouts, 0);
+        thread::sleep(Duration::from_millis(60));
+        stats.record_timeout();
+        //the next poll starts a new interval
+        stats.record_timeout();
+        assert_eq!(stats.stats().total_wait_timeouts, 1);
+        assert_eq!(stats.stats().wait_timeouts, 1);
+    }
+
+    #[test]
+    fn frames_reset_the_timeout() {
+        let stats = StatsHandle::new(Duration::from_secs(5));
+        stats.set_frame_timeout(Some(Duration::from_millis(50)));
+        thread::sleep(Duration::from_millis(60));
+        stats.record_frame(Instant::now(), 16, 0, None);
+        stats.record_timeout();
+        assert_eq!(stats.stats().total_wait_timeouts, 0);
+    }
+
+    #[test]
+    fn triggered_frames_are_never_late() {
+        let stats = StatsHandle::new(Duration::from_secs(5));
+        stats.capture_started(None);
+        thread::sleep(Duration::from_millis(10));
+        stats.record_timeout();
+        assert_eq!(stats.stats().total_wait_timeouts, 0);
+    }
+}
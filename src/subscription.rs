@@ -0,0 +1,344 @@
+//! Feed several consumers from one stream, each at its own pace.
+//!
+//! `DcamStream::add_subscriber` returns a `Subscription` with its own bounded queue. What happens when a
+//! subscriber falls behind is chosen per subscriber with an `OverflowPolicy`, so a live preview can keep
+//! only the newest frame while an analysis task gets every frame. With `OverflowPolicy::Error` a subscriber
+//! which falls behind is disconnected instead. Dropping the `Subscription` removes it from the stream
+
+use crate::async_writer::OverflowPolicy;
+use crate::frame::DcamFrame;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// State shared between a `Subscription` and the capture thread
+struct Shared {
+    capacity: usize,
+    policy: OverflowPolicy,
+    state: Mutex<State>,
+    /// signalled when a frame is queued or the stream stops
+    frame_ready: Condvar,
+    /// signalled when a frame is taken off the queue or the subscription is dropped
+    space_ready: Condvar,
+    dropped: Arc<AtomicU64>,
+}
+
+struct State {
+    frames: VecDeque<DcamFrame>,
+    /// the stream has stopped, no more frames are coming
+    stream_closed: bool,
+    /// the `Subscription` has been dropped
+    subscriber_closed: bool,
+    /// the queue overflowed with `OverflowPolicy::Error`, no more frames are coming
+    overflowed: bool,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("subscriber panicked")
+    }
+}
+
+/// A queue of frames from a `DcamStream`
+pub struct Subscription {
+    shared: Arc<Shared>,
+}
+
+/// The capture thread's end of a `Subscription`
+pub(crate) struct Publisher {
+    shared: Arc<Shared>,
+}
+
+/// Make a connected `Publisher` and `Subscription` holding at most `capacity` frames,
+/// frames thrown away are counted in `dropped`
+pub(crate) fn subscription(
+    capacity: usize,
+    policy: OverflowPolicy,
+    dropped: Arc<AtomicU64>,
+) -> (Publisher, Subscription) {
+    assert!(capacity > 0, "queue must hold at least one frame");
+    let shared = Arc::new(Shared {
+        capacity,
+        policy,
+        state: Mutex::new(State {
+            frames: VecDeque::with_capacity(capacity),
+            stream_closed: false,
+            subscriber_closed: false,
+            overflowed: false,
+        }),
+        frame_ready: Condvar::new(),
+        space_ready: Condvar::new(),
+        dropped,
+    });
+    (
+        Publisher {
+            shared: shared.clone(),
+        },
+        Subscription { shared },
+    )
+}
+
+impl Publisher {
+    /// Queue a copy of `frame`, returns `false` once the subscriber has gone away
+    pub(crate) fn publish(&self, frame: &DcamFrame) -> bool {
+        let mut state = self.shared.lock();
+        if state.subscriber_closed {
+            return false;
+        }
+        if state.frames.len() >= self.shared.capacity {
+            match self.shared.policy {
+                OverflowPolicy::Block => {
+                    while state.frames.len() >= self.shared.capacity && !state.subscriber_closed {
+                        state = self
+                            .shared
+                            .space_ready
+                            .wait(state)
+                            .expect("subscriber panicked");
+                    }
+                    if state.subscriber_closed {
+                        return false;
+                    }
+                }
+                OverflowPolicy::DropOldest => {
+                    state.frames.pop_front();
+                    self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                OverflowPolicy::DropNewest => {
+                    self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                    return true;
+                }
+                //the subscriber gets what's already queued and then nothing more
+                OverflowPolicy::Error => {
+                    self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                    state.overflowed = true;
+                    state.stream_closed = true;
+                    self.shared.frame_ready.notify_all();
+                    return false;
+                }
+            }
+        }
+        state.frames.push_back(frame.clone());
+        self.shared.frame_ready.notify_one();
+        true
+    }
+    /// Number of frames waiting for the subscriber
+    #[cfg(feature = "ralston")]
+    pub(crate) fn queued(&self) -> usize {
+        self.shared.lock().frames.len()
+    }
+}
+
+impl Drop for Publisher {
+    fn drop(&mut self) {
+        self.shared.lock().stream_closed = true;
+        self.shared.frame_ready.notify_all();
+    }
+}
+
+impl Subscription {
+    /// Wait for the next frame, returns `None` once the stream has stopped and the queue is empty
+    pub fn recv(&self) -> Option<DcamFrame> {
+        let mut state = self.shared.lock();
+        while state.frames.is_empty() && !state.stream_closed {
+            state = self
+                .shared
+                .frame_ready
+                .wait(state)
+                .expect("subscriber panicked");
+        }
+        self.take(state)
+    }
+    /// Wait up to `timeout` for the next frame, returns `None` if none arrived
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<DcamFrame> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.lock();
+        while state.frames.is_empty() && !state.stream_closed {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return None;
+            }
+            state = self
+                .shared
+                .frame_ready
+                .wait_timeout(state, remaining)
+                .expect("subscriber panicked")
+                .0;
+        }
+        self.take(state)
+    }
+    /// Get the next frame if there is one waiting
+    pub fn try_recv(&self) -> Option<DcamFrame> {
+        let state = self.shared.lock();
+        self.take(state)
+    }
+    /// Get the newest frame waiting and throw away any older ones
+    pub fn latest(&self) -> Option<DcamFrame> {
+        let mut state = self.shared.lock();
+        let skipped = state.frames.len().saturating_sub(1);
+        self.shared
+            .dropped
+            .fetch_add(skipped as u64, Ordering::Relaxed);
+        let newest = state.frames.pop_back();
+        state.frames.clear();
+        self.shared.space_ready.notify_one();
+        newest
+    }
+    /// Number of frames waiting
+    pub fn queued(&self) -> usize {
+        self.shared.lock().frames.len()
+    }
+    /// Number of frames thrown away because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+    /// `true` once the stream has stopped, frames may still be waiting in the queue
+    pub fn is_closed(&self) -> bool {
+        self.shared.lock().stream_closed
+    }
+    /// `true` if we were disconnected because the queue overflowed with `OverflowPolicy::Error`
+    pub fn overflowed(&self) -> bool {
+        self.shared.lock().overflowed
+    }
+    /// Take the oldest frame off the queue
+    fn take(&self, mut state: MutexGuard<'_, State>) -> Option<DcamFrame> {
+        let frame = state.frames.pop_front();
+        if frame.is_some() {
+            self.shared.space_ready.notify_one();
+        }
+        frame
+    }
+}
+
+impl Iterator for Subscription {
+    type Item = DcamFrame;
+    /// Wait for the next frame, the iterator ends when the stream stops
+    fn next(&mut self) -> Option<DcamFrame> {
+        self.recv()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.subscriber_closed = true;
+        state.frames.clear();
+        //don't leave the capture thread waiting on us
+        self.shared.space_ready.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::frame;
+    use std::thread;
+
+    fn framestamps(subscription: &Subscription) -> Vec<i32> {
+        std::iter::from_fn(|| subscription.try_recv())
+            .map(|f| f.framestamp())
+            .collect()
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let (publisher, subscription) = subscription(2, OverflowPolicy::DropOldest, Arc::default());
+        for i in 0..4 {
+            assert!(publisher.publish(&frame(1, 1, i)));
+        }
+        assert_eq!(subscription.dropped(), 2);
+        assert_eq!(framestamps(&subscription), [2, 3]);
+    }
+
+    #[test]
+    fn drop_newest() {
+        let (publisher, subscription) = subscription(2, OverflowPolicy::DropNewest, Arc::default());
+        for i in 0..4 {
+            assert!(publisher.publish(&frame(1, 1, i)));
+        }
+        assert_eq!(subscription.dropped(), 2);
+        assert_eq!(framestamps(&subscription), [0, 1]);
+    }
+
+    #[test]
+    fn error_disconnects_after_the_queue() {
+        let (publisher, subscription) = subscription(2, OverflowPolicy::Error, Arc::default());
+        assert!(publisher.publish(&frame(1, 1, 0)));
+        assert!(publisher.publish(&frame(1, 1, 1)));
+        assert!(!publisher.publish(&frame(1, 1, 2)));
+        assert!(subscription.overflowed());
+        assert!(subscription.is_closed());
+        //what was queued before the overflow still arrives
+        assert_eq!(subscription.recv().map(|f| f.framestamp()), Some(0));
+        assert_eq!(subscription.recv().map(|f| f.framestamp()), Some(1));
+        assert!(subscription.recv().is_none());
+        assert_eq!(subscription.dropped(), 1);
+    }
+
+    #[test]
+    fn block_waits_for_the_subscriber() {
+        let (publisher, subscription) = subscription(1, OverflowPolicy::Block, Arc::default());
+        assert!(publisher.publish(&frame(1, 1, 0)));
+        let capture = thread::spawn(move || publisher.publish(&frame(1, 1, 1)));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!capture.is_finished(), "the queue is full");
+        assert_eq!(subscription.recv().map(|f| f.framestamp()), Some(0));
+        assert!(capture.join().unwrap());
+        assert_eq!(subscription.recv().map(|f| f.framestamp()), Some(1));
+        assert_eq!(subscription.dropped(), 0);
+    }
+
+    #[test]
+    fn dropping_the_subscription_releases_a_blocked_publisher() {
+        let (publisher, subscription) = subscription(1, OverflowPolicy::Block, Arc::default());
+        assert!(publisher.publish(&frame(1, 1, 0)));
+        let capture = thread::spawn(move || publisher.publish(&frame(1, 1, 1)));
+        thread::sleep(Duration::from_millis(50));
+        drop(subscription);
+        assert!(!capture.join().unwrap());
+    }
+
+    #[test]
+    fn stream_shutdown_ends_the_subscription() {
+        let (publisher, mut subscription) =
+            subscription(4, OverflowPolicy::DropOldest, Arc::default());
+        publisher.publish(&frame(1, 1, 0));
+        publisher.publish(&frame(1, 1, 1));
+        drop(publisher);
+        assert!(subscription.is_closed());
+        assert!(!subscription.overflowed());
+        //the iterator drains the queue and then ends
+        assert_eq!(
+            subscription
+                .by_ref()
+                .map(|f| f.framestamp())
+                .collect::<Vec<_>>(),
+            [0, 1]
+        );
+        assert!(subscription
+            .recv_timeout(Duration::from_millis(10))
+            .is_none());
+    }
+
+    #[test]
+    fn recv_timeout_without_frames() {
+        let (_publisher, subscription) = subscription(1, OverflowPolicy::Block, Arc::default());
+        assert!(subscription
+            .recv_timeout(Duration::from_millis(10))
+            .is_none());
+        assert!(!subscription.is_closed());
+    }
+
+    #[test]
+    fn latest_skips_older_frames() {
+        let dropped = Arc::new(AtomicU64::new(0));
+        let (publisher, subscription) = subscription(4, OverflowPolicy::Block, dropped.clone());
+        for i in 0..3 {
+            publisher.publish(&frame(1, 1, i));
+        }
+        assert_eq!(subscription.latest().map(|f| f.framestamp()), Some(2));
+        assert_eq!(subscription.queued(), 0);
+        //the counter is shared with whoever handed it in
+        assert_eq!(dropped.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,52 @@
+//! Fixtures shared by the unit tests
+
+use crate::frame::DcamFrame;
+use crate::pixel::PixelType;
+use crate::writer::AcquisitionInfo;
+use image::{ImageBuffer, Luma};
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Settings of a made up camera
+pub(crate) fn info() -> AcquisitionInfo {
+    AcquisitionInfo {
+        model: "C11440-22CU".to_string(),
+        serial_number: "S/N: 000001".to_string(),
+        exposure: 0.01,
+        binning: 1,
+        roi: [0, 0, 4, 3],
+        pixel_type: PixelType::Mono16,
+        pixel_size: Some([6.5, 6.5]),
+        sensor_temperature: None,
+    }
+}
+
+/// The pixel at `x`, `y` of the frame with `framestamp` from `frame`, no two pixels are the same in
+/// frames up to 10 pixels across
+pub(crate) fn pixel(framestamp: i32, x: u32, y: u32) -> u16 {
+    (framestamp as u32 * 100 + y * 10 + x + 1) as u16
+}
+
+/// A `width` x `height` frame filled in by `pixel`, taken `10 * framestamp` ms after the first
+pub(crate) fn frame(width: u32, height: u32, framestamp: i32) -> DcamFrame {
+    let image = ImageBuffer::from_fn(width, height, |x, y| Luma([pixel(framestamp, x, y)]));
+    DcamFrame::new(
+        image,
+        Duration::from_millis(10 * framestamp as u64),
+        framestamp,
+    )
+}
+
+/// A one pixel frame for tests which only look at the stamps
+pub(crate) fn stamped(timestamp: Duration, framestamp: i32) -> DcamFrame {
+    DcamFrame::new(ImageBuffer::new(1, 1), timestamp, framestamp)
+}
+
+/// A path for `name` in the temp directory, nothing is left there from an earlier run
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("free_willy_{}_{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&path);
+    let _ = fs::remove_file(&path);
+    path
+}
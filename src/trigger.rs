@@ -0,0 +1,54 @@
+//! Where the camera gets the signal to start each exposure
+
+use crate::bindings;
+
+/// Trigger sources which can be selected through `DCAM_IDPROP_TRIGGERSOURCE`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerSource {
+    /// the camera runs freely at the rate set by the exposure and readout
+    Internal,
+    /// each exposure is started by a signal on the trigger input
+    External,
+    /// each exposure is started by `dcamcap_firetrigger`
+    Software,
+    /// exposures are started by the camera's own pulse generator
+    MasterPulse,
+}
+
+impl TriggerSource {
+    /// Convert a value read from `DCAM_IDPROP_TRIGGERSOURCE` into a `TriggerSource`
+    pub fn from_dcam(value: i32) -> Option<TriggerSource> {
+        match value {
+            bindings::_DCAMPROPMODEVALUE_DCAMPROP_TRIGGERSOURCE__INTERNAL => {
+                Some(TriggerSource::Internal)
+            }
+            bindings::_DCAMPROPMODEVALUE_DCAMPROP_TRIGGERSOURCE__EXTERNAL => {
+                Some(TriggerSource::External)
+            }
+            bindings::_DCAMPROPMODEVALUE_DCAMPROP_TRIGGERSOURCE__SOFTWARE => {
+                Some(TriggerSource::Software)
+            }
+            bindings::_DCAMPROPMODEVALUE_DCAMPROP_TRIGGERSOURCE__MASTERPULSE => {
+                Some(TriggerSource::MasterPulse)
+            }
+            _ => None,
+        }
+    }
+    /// Get the value we need to write to `DCAM_IDPROP_TRIGGERSOURCE` to select this source
+    pub fn to_dcam(self) -> i32 {
+        match self {
+            TriggerSource::Internal => {
+                bindings::_DCAMPROPMODEVALUE_DCAMPROP_TRIGGERSOURCE__INTERNAL
+            }
+            TriggerSource::External => {
+                bindings::_DCAMPROPMODEVALUE_DCAMPROP_TRIGGERSOURCE__EXTERNAL
+            }
+            TriggerSource::Software => {
+                bindings::_DCAMPROPMODEVALUE_DCAMPROP_TRIGGERSOURCE__SOFTWARE
+            }
+            TriggerSource::MasterPulse => {
+                bindings::_DCAMPROPMODEVALUE_DCAMPROP_TRIGGERSOURCE__MASTERPULSE
+            }
+        }
+    }
+}
@@ -0,0 +1,269 @@
+//! Wait for capture and recording events other than a new frame, and ask the camera what it's doing.
+//!
+//! A `WaitHandle` can wait for any combination of `WaitEvents`, e.g. `WaitEvents::EXPOSURE_END` to move
+//! a stage as soon as the sensor stops exposing, while the capture thread keeps waiting for frames on its
+//! own handle. `Camera::wait_handle` opens one on a camera, `DcamStream::wait_handle` on a running stream
+
+use crate::api_generation;
+use crate::bindings;
+use crate::error::DcamError;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{BitAnd, BitOr, BitOrAssign};
+use std::time::Duration;
+
+/// A set of events a `WaitHandle` can wait for, combine them with `|`
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct WaitEvents(i32);
+
+impl WaitEvents {
+    /// a frame has been transferred from the camera
+    pub const TRANSFERRED: WaitEvents =
+        WaitEvents(bindings::DCAMWAIT_EVENT_DCAMWAIT_CAPEVENT_TRANSFERRED);
+    /// a frame is in the buffer and can be read
+    pub const FRAME_READY: WaitEvents =
+        WaitEvents(bindings::DCAMWAIT_EVENT_DCAMWAIT_CAPEVENT_FRAMEREADY);
+    /// the camera has finished a cycle of its buffer
+    pub const CYCLE_END: WaitEvents =
+        WaitEvents(bindings::DCAMWAIT_EVENT_DCAMWAIT_CAPEVENT_CYCLEEND);
+    /// the sensor has finished exposing a frame, it's still being read out
+    pub const EXPOSURE_END: WaitEvents =
+        WaitEvents(bindings::DCAMWAIT_EVENT_DCAMWAIT_CAPEVENT_EXPOSUREEND);
+    /// capture has stopped
+    pub const STOPPED: WaitEvents = WaitEvents(bindings::DCAMWAIT_EVENT_DCAMWAIT_CAPEVENT_STOPPED);
+    /// a frame was reloaded into the buffer
+    pub const RELOAD_FRAME: WaitEvents =
+        WaitEvents(bindings::DCAMWAIT_EVENT_DCAMWAIT_CAPEVENT_RELOADFRAME);
+    /// the recording has stopped
+    pub const RECORD_STOPPED: WaitEvents =
+        WaitEvents(bindings::DCAMWAIT_EVENT_DCAMWAIT_RECEVENT_STOPPED);
+    /// the recording is falling behind
+    pub const RECORD_WARNING: WaitEvents =
+        WaitEvents(bindings::DCAMWAIT_EVENT_DCAMWAIT_RECEVENT_WARNING);
+    /// a frame couldn't be written to the recording
+    pub const RECORD_MISSED: WaitEvents =
+        WaitEvents(bindings::DCAMWAIT_EVENT_DCAMWAIT_RECEVENT_MISSED);
+    /// the disk holding the recording is full
+    pub const RECORD_DISK_FULL: WaitEvents =
+        WaitEvents(bindings::DCAMWAIT_EVENT_DCAMWAIT_RECEVENT_DISKFULL);
+    /// writing to the recording failed
+    pub const RECORD_WRITE_FAULT: WaitEvents =
+        WaitEvents(bindings::DCAMWAIT_EVENT_DCAMWAIT_RECEVENT_WRITEFAULT);
+    /// a frame was skipped by the recording
+    pub const RECORD_SKIPPED: WaitEvents =
+        WaitEvents(bindings::DCAMWAIT_EVENT_DCAMWAIT_RECEVENT_SKIPPED);
+    /// a frame was written to the recording
+    pub const RECORD_WRITE_FRAME: WaitEvents =
+        WaitEvents(bindings::DCAMWAIT_EVENT_DCAMWAIT_RECEVENT_WRITEFRAME);
+    /// every capture event
+    pub const CAPTURE: WaitEvents = WaitEvents(
+        Self::TRANSFERRED.0
+            | Self::FRAME_READY.0
+            | Self::CYCLE_END.0
+            | Self::EXPOSURE_END.0
+            | Self::STOPPED.0
+            | Self::RELOAD_FRAME.0,
+    );
+    /// every recording event
+    pub const RECORDING: WaitEvents = WaitEvents(
+        Self::RECORD_STOPPED.0
+            | Self::RECORD_WARNING.0
+            | Self::RECORD_MISSED.0
+            | Self::RECORD_DISK_FULL.0
+            | Self::RECORD_WRITE_FAULT.0
+            | Self::RECORD_SKIPPED.0
+            | Self::RECORD_WRITE_FRAME.0,
+    );
+
+    const NAMES: [(WaitEvents, &'static str); 13] = [
+        (Self::TRANSFERRED, "TRANSFERRED"),
+        (Self::FRAME_READY, "FRAME_READY"),
+        (Self::CYCLE_END, "CYCLE_END"),
+        (Self::EXPOSURE_END, "EXPOSURE_END"),
+        (Self::STOPPED, "STOPPED"),
+        (Self::RELOAD_FRAME, "RELOAD_FRAME"),
+        (Self::RECORD_STOPPED, "RECORD_STOPPED"),
+        (Self::RECORD_WARNING, "RECORD_WARNING"),
+        (Self::RECORD_MISSED, "RECORD_MISSED"),
+        (Self::RECORD_DISK_FULL, "RECORD_DISK_FULL"),
+        (Self::RECORD_WRITE_FAULT, "RECORD_WRITE_FAULT"),
+        (Self::RECORD_SKIPPED, "RECORD_SKIPPED"),
+        (Self::RECORD_WRITE_FRAME, "RECORD_WRITE_FRAME"),
+    ];
+
+    /// No events
+    pub const fn empty() -> WaitEvents {
+        WaitEvents(0)
+    }
+    /// Build a set from a `DCAMWAIT_EVENT` mask
+    pub const fn from_bits(bits: i32) -> WaitEvents {
+        WaitEvents(bits)
+    }
+    /// The `DCAMWAIT_EVENT` mask for this set
+    pub const fn bits(self) -> i32 {
+        self.0
+    }
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+    /// `true` if every event in `other` is in this set
+    pub const fn contains(self, other: WaitEvents) -> bool {
+        self.0 & other.0 == other.0
+    }
+    /// `true` if any event in `other` is in this set
+    pub const fn intersects(self, other: WaitEvents) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl BitOr for WaitEvents {
+    type Output = WaitEvents;
+    fn bitor(self, other: WaitEvents) -> WaitEvents {
+        WaitEvents(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for WaitEvents {
+    fn bitor_assign(&mut self, other: WaitEvents) {
+        self.0 |= other.0;
+    }
+}
+
+impl BitAnd for WaitEvents {
+    type Output = WaitEvents;
+    fn bitand(self, other: WaitEvents) -> WaitEvents {
+        WaitEvents(self.0 & other.0)
+    }
+}
+
+impl fmt::Debug for WaitEvents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut set = f.debug_set();
+        let mut known = 0;
+        for (event, name) in WaitEvents::NAMES {
+            if self.contains(event) {
+                set.entry(&format_args!("{}", name));
+                known |= event.0;
+            }
+        }
+        //show anything we don't have a name for as a raw mask
+        if self.0 & !known != 0 {
+            set.entry(&format_args!("{:#x}", self.0 & !known));
+        }
+        set.finish()
+    }
+}
+
+/// What the camera is doing, as reported by `dcamcap_status`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureStatus {
+    /// the camera is in an error state
+    Error,
+    /// capture is running
+    Busy,
+    /// a buffer is attached and capture can be started
+    Ready,
+    /// the camera is idle without a buffer
+    Stable,
+    /// the camera is still settling, e.g. cooling down, and can't capture yet
+    Unstable,
+}
+
+impl CaptureStatus {
+    /// Convert a value from `dcamcap_status` into a `CaptureStatus`
+    pub fn from_dcam(value: i32) -> Option<CaptureStatus> {
+        match value {
+            bindings::DCAMCAP_STATUS_DCAMCAP_STATUS_ERROR => Some(CaptureStatus::Error),
+            bindings::DCAMCAP_STATUS_DCAMCAP_STATUS_BUSY => Some(CaptureStatus::Busy),
+            bindings::DCAMCAP_STATUS_DCAMCAP_STATUS_READY => Some(CaptureStatus::Ready),
+            bindings::DCAMCAP_STATUS_DCAMCAP_STATUS_STABLE => Some(CaptureStatus::Stable),
+            bindings::DCAMCAP_STATUS_DCAMCAP_STATUS_UNSTABLE => Some(CaptureStatus::Unstable),
+            _ => None,
+        }
+    }
+}
+
+/// A DCAM wait handle, closed when dropped. It borrows the camera (or stream) it was opened on so
+/// it can't outlive it. A stream can lose its camera and reconnect underneath the handle, once the API it
+/// was opened under has been released every call returns `DCAMERR_INVALIDWAITHANDLE`
+pub struct WaitHandle<'a> {
+    hwait: bindings::HDCAMWAIT,
+    supported: WaitEvents,
+    /// `api_generation` when the handle was opened
+    generation: u64,
+    _camera: PhantomData<&'a ()>,
+}
+
+// The DCAM API expects wait handles to be aborted from a different thread than the one waiting on them
+unsafe impl Send for WaitHandle<'_> {}
+unsafe impl Sync for WaitHandle<'_> {}
+
+impl<'a> WaitHandle<'a> {
+    /// Open a wait handle on the camera `handle`, the caller picks a lifetime which ends before the
+    /// camera is closed
+    pub(crate) fn open(handle: bindings::HDCAM) -> Result<WaitHandle<'a>, i32> {
+        let mut dcwo = bindings::DCAMWAIT_OPEN::new(handle);
+        match unsafe { bindings::dcamwait_open(&mut dcwo) } {
+            1 => Ok(WaitHandle {
+                hwait: dcwo.hwait,
+                supported: WaitEvents(dcwo.supportevent),
+                generation: api_generation(),
+                _camera: PhantomData,
+            }),
+            e => Err(e),
+        }
+    }
+    /// The events this camera can report
+    pub fn supported(&self) -> WaitEvents {
+        self.supported
+    }
+    /// `false` once the API the handle was opened under has been released
+    pub fn is_valid(&self) -> bool {
+        self.generation == api_generation()
+    }
+    /// fail instead of passing DCAM a handle it has already freed
+    fn check(&self) -> Result<(), DcamError> {
+        match self.is_valid() {
+            true => Ok(()),
+            false => Err(DcamError(bindings::DCAMERR_DCAMERR_INVALIDWAITHANDLE)),
+        }
+    }
+    /// Wait until any of `events` happens and return which of them did. Returns a timeout error if
+    /// none happened within `timeout`, `None` waits forever. If the wait is cut short by `abort`
+    /// this returns `DCAMERR_ABORT`
+    pub fn wait(
+        &self,
+        events: WaitEvents,
+        timeout: Option<Duration>,
+    ) -> Result<WaitEvents, DcamError> {
+        let timeout = match timeout {
+            Some(t) => t.as_millis().min(i32::MAX as u128) as i32,
+            None => bindings::DCAMWAIT_TIMEOUT_DCAMWAIT_TIMEOUT_INFINITE,
+        };
+        self.check()?;
+        let mut dws = bindings::DCAMWAIT_START::with_event(events.0, timeout);
+        match unsafe { bindings::dcamwait_start(self.hwait, &mut dws) } {
+            1 => Ok(WaitEvents(dws.eventhappened)),
+            e => Err(DcamError(e)),
+        }
+    }
+    /// Wake up every thread waiting on this handle
+    pub fn abort(&self) -> Result<(), DcamError> {
+        self.check()?;
+        match unsafe { bindings::dcamwait_abort(self.hwait) } {
+            1 => Ok(()),
+            e => Err(DcamError(e)),
+        }
+    }
+}
+
+impl Drop for WaitHandle<'_> {
+    fn drop(&mut self) {
+        //the API closed it for us when it was released
+        if self.is_valid() {
+            unsafe {
+                bindings::dcamwait_close(self.hwait);
+            }
+        }
+    }
+}
@@ -0,0 +1,165 @@
+//! Common interface for writing captured frames to disk, and the camera settings we store alongside them
+
+use crate::bindings;
+use crate::frame::DcamFrame;
+use crate::pixel::PixelType;
+use crate::Camera;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::mpsc::Sender;
+
+/// Something which can save a sequence of frames, i.e. a file format.
+/// `begin` is called once before the first frame, `finish` once after the last
+pub trait FrameWriter: Send {
+    /// Prepare to write frames captured with the settings in `info`
+    fn begin(&mut self, info: &AcquisitionInfo) -> io::Result<()>;
+    /// Append `frame` to the output
+    fn write_frame(&mut self, frame: &DcamFrame) -> io::Result<()>;
+    /// Write anything that can only be written once all frames are known and flush everything to disk.
+    /// No more frames can be written after this
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+/// What a writer was doing when it failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteStage {
+    /// getting ready for the first frame
+    Begin,
+    /// writing the frame with this framestamp
+    Frame(i32),
+    /// finishing the output once the stream stopped
+    Finish,
+}
+
+/// A writer or spool attached to a stream failed. It has been dropped and gets no more frames, capture
+/// and every other writer carry on. Writers aren't `finish`ed, a spool still writes its index for the
+/// frames it has when dropped
+#[derive(Debug)]
+pub struct WriterError {
+    /// the number `DcamStream::add_writer` or `DcamStream::spool_to` returned
+    pub writer: usize,
+    pub stage: WriteStage,
+    pub error: io::Error,
+}
+
+impl fmt::Display for WriterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "writer {} failed ", self.writer)?;
+        match self.stage {
+            WriteStage::Begin => write!(f, "to begin")?,
+            WriteStage::Frame(framestamp) => write!(f, "writing frame {}", framestamp)?,
+            WriteStage::Finish => write!(f, "to finish")?,
+        }
+        write!(f, ": {}", self.error)
+    }
+}
+
+impl Error for WriterError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Log `error` and send it to `consumer`, stop sending if it hung up
+pub(crate) fn report(consumer: &mut Option<Sender<WriterError>>, error: WriterError) {
+    log_event!(
+        ERROR,
+        writer = error.writer,
+        stage = ?error.stage,
+        error = %error.error,
+        "writer failed, dropping it"
+    );
+    if let Some(tx) = consumer {
+        if tx.send(error).is_err() {
+            *consumer = None;
+        }
+    }
+}
+
+/// Camera settings in effect for an acquisition, used to describe the data in saved files
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcquisitionInfo {
+    /// Camera model, i.e. `C11440-22CU`
+    pub model: String,
+    /// Camera serial number as reported by the camera
+    pub serial_number: String,
+    /// Exposure time in seconds
+    pub exposure: f64,
+    /// value of `DCAM_IDPROP_BINNING`, `1`, `2` and `4` are square binning
+    pub binning: i32,
+    /// Sensor region we are reading as `[left, top, width, height]` in sensor pixels
+    pub roi: [usize; 4],
+    /// Pixel format delivered by the camera, frames are always unpacked to 16 bits
+    pub pixel_type: PixelType,
+    /// Size of each image pixel in micrometers on the sensor (including binning), if the camera reports it
+    pub pixel_size: Option<[f64; 2]>,
+    /// Sensor temperature in degrees Celsius when these settings were read, if the camera reports it
+    pub sensor_temperature: Option<f64>,
+}
+
+impl AcquisitionInfo {
+    /// Read the current settings from `camera`
+    pub fn from_camera<T: Camera>(camera: &T) -> Result<AcquisitionInfo, i32> {
+        let binning = camera.dcamprop_getvalue(bindings::_DCAMIDPROP_DCAM_IDPROP_BINNING)? as i32;
+        let roi = [
+            bindings::_DCAMIDPROP_DCAM_IDPROP_SUBARRAYHPOS,
+            bindings::_DCAMIDPROP_DCAM_IDPROP_SUBARRAYVPOS,
+            bindings::_DCAMIDPROP_DCAM_IDPROP_SUBARRAYHSIZE,
+            bindings::_DCAMIDPROP_DCAM_IDPROP_SUBARRAYVSIZE,
+        ]
+        .map(|prop| camera.dcamprop_getvalue(prop).map(|v| v as usize));
+        //not every camera knows its pixel size
+        let pixel_size = match (
+            camera.dcamprop_getvalue(bindings::_DCAMIDPROP_DCAM_IDPROP_IMAGEDETECTOR_PIXELWIDTH),
+            camera.dcamprop_getvalue(bindings::_DCAMIDPROP_DCAM_IDPROP_IMAGEDETECTOR_PIXELHEIGHT),
+        ) {
+            (Ok(w), Ok(h)) => {
+                let [bin_x, bin_y] = binning_factors(binning);
+                Some([w * bin_x as f64, h * bin_y as f64])
+            }
+            _ => None,
+        };
+        Ok(AcquisitionInfo {
+            model: camera.model().unwrap_or_default(),
+            serial_number: camera.serial_number().unwrap_or_default(),
+            exposure: camera.get_exposure()?,
+            binning,
+            roi: [roi[0]?, roi[1]?, roi[2]?, roi[3]?],
+            pixel_type: camera.get_pixel_type()?,
+            pixel_size,
+            sensor_temperature: camera
+                .dcamprop_getvalue(bindings::_DCAMIDPROP_DCAM_IDPROP_SENSORTEMPERATURE)
+                .ok(),
+        })
+    }
+    /// Horizontal and vertical binning factors
+    pub fn binning_factors(&self) -> [u32; 2] {
+        binning_factors(self.binning)
+    }
+    /// Describe these settings as a JSON object, for formats that store their metadata as JSON
+    pub fn to_json(&self) -> Value {
+        json!({
+            "model": self.model,
+            "serial_number": self.serial_number,
+            "exposure": self.exposure,
+            "binning": self.binning,
+            "roi": self.roi,
+            "pixel_type": format!("{:?}", self.pixel_type),
+            "bits": self.pixel_type.bits(),
+            "pixel_size": self.pixel_size,
+            "sensor_temperature": self.sensor_temperature,
+        })
+    }
+}
+
+/// Split a `DCAM_IDPROP_BINNING` value into horizontal and vertical factors.
+/// Square binning is stored as the factor, asymmetric binning as `100 * horizontal + vertical`
+fn binning_factors(binning: i32) -> [u32; 2] {
+    match binning {
+        b if b > 100 => [(b / 100) as u32, (b % 100) as u32],
+        b if b > 0 => [b as u32, b as u32],
+        _ => [1, 1],
+    }
+}
@@ -0,0 +1,441 @@
+//! Chunked Zarr array writer for long acquisitions.
+//!
+//! Frames are stored as a single `uint16` array with dimensions `[t, y, x]`. Frames are buffered
+//! until a full chunk along `t` has been collected, then every chunk in that slab is written and the
+//! array metadata is updated, so other processes can read everything up to the last full chunk
+//! while the acquisition is still running. Per-frame timestamps are added to the attributes by `finish`
+
+use crate::compression::Compression;
+use crate::frame::DcamFrame;
+use crate::writer::{AcquisitionInfo, FrameWriter};
+use image::{ImageBuffer, Luma};
+use serde_json::{json, Value};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Version of the Zarr spec to write
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZarrFormat {
+    V2,
+    V3,
+}
+
+/// Writes frames to a Zarr array stored in a directory
+pub struct ZarrWriter {
+    path: PathBuf,
+    format: ZarrFormat,
+    compression: Compression,
+    /// number of frames in each chunk
+    chunk_frames: usize,
+    /// `[height, width]` of each chunk, `None` for whole frames
+    chunk_size: Option<[usize; 2]>,
+    /// `[height, width]` of the frames, set by the first frame
+    dimensions: Option<[usize; 2]>,
+    info: Option<AcquisitionInfo>,
+    /// frames waiting for their chunk to fill up
+    pending: Vec<ImageBuffer<Luma<u16>, Vec<u16>>>,
+    /// number of frames that have been written to chunks
+    frames_written: usize,
+    /// camera timestamp and framestamp of every frame
+    stamps: Vec<(Duration, i32)>,
+    finished: bool,
+}
+
+impl ZarrWriter {
+    /// Create a new array in the directory at `path`, which must not exist yet
+    pub fn create<P: AsRef<Path>>(path: P, format: ZarrFormat) -> io::Result<ZarrWriter> {
+        fs::create_dir(path.as_ref())?;
+        Ok(ZarrWriter {
+            path: path.as_ref().to_path_buf(),
+            format,
+            compression: Compression::None,
+            chunk_frames: 16,
+            chunk_size: None,
+            dimensions: None,
+            info: None,
+            pending: Vec::new(),
+            frames_written: 0,
+            stamps: Vec::new(),
+            finished: false,
+        })
+    }
+    /// Set the chunk shape, `frames` frames along `t` and `[height, width]` pixels in space.
+    /// `None` uses whole frames. This can't be changed once frames have been written
+    pub fn set_chunk_shape(&mut self, frames: usize, size: Option<[usize; 2]>) {
+        assert!(
+            self.stamps.is_empty(),
+            "can't change chunk shape after writing frames"
+        );
+        assert!(frames > 0, "chunks must contain at least one frame");
+        self.chunk_frames = frames;
+        self.chunk_size = size;
+    }
+    /// Choose how chunks are compressed, zstd level `1` is fast enough to keep up with most cameras.
+    /// LZ4 uses the numcodecs `lz4` codec, which zarr-python reads but other implementations may not.
+    /// This can't be changed once frames have been written
+    pub fn set_compression(&mut self, compression: Compression) {
+        assert!(
+            self.stamps.is_empty(),
+            "can't change compression after writing frames"
+        );
+        self.compression = compression;
+    }
+    /// Number of frames written so far, including ones waiting for their chunk to fill
+    pub fn frame_count(&self) -> usize {
+        self.stamps.len()
+    }
+    /// `[t, y, x]` shape of each chunk
+    fn chunk_shape(&self) -> [usize; 3] {
+        let [height, width] = self.dimensions.unwrap_or([0, 0]);
+        let [cy, cx] = self.chunk_size.unwrap_or([height, width]);
+        [self.chunk_frames, cy.max(1), cx.max(1)]
+    }
+    /// Attributes stored with the array, timestamps are only included once we are finished
+    fn attributes(&self) -> Value {
+        let mut attributes = json!({});
+        if let Some(info) = &self.info {
+            attributes["camera"] = info.to_json();
+        }
+        if self.finished {
+            let first = self.stamps.first().map(|s| s.0).unwrap_or_default();
+            let timestamps: Vec<f64> = self
+                .stamps
+                .iter()
+                .map(|s| s.0.saturating_sub(first).as_secs_f64())
+                .collect();
+            let framestamps: Vec<i32> = self.stamps.iter().map(|s| s.1).collect();
+            attributes["timestamps"] = json!(timestamps);
+            attributes["framestamps"] = json!(framestamps);
+        }
+        attributes
+    }
+    /// Write the array metadata for a `frames` long array
+    fn write_metadata(&self, frames: usize) -> io::Result<()> {
+        let [height, width] = self.dimensions.unwrap_or([0, 0]);
+        let shape = [frames, height, width];
+        let chunks = self.chunk_shape();
+        match self.format {
+            ZarrFormat::V2 => {
+                let compressor = match self.compression {
+                    Compression::None => Value::Null,
+                    Compression::Lz4 => json!({"id": "lz4", "acceleration": 1}),
+                    Compression::Zstd(level) => json!({"id": "zstd", "level": level}),
+                };
+                let zarray = json!({
+                    "zarr_format": 2,
+                    "shape": shape,
+                    "chunks": chunks,
+                    "dtype": "<u2",
+                    "compressor": compressor,
+                    "fill_value": 0,
+                    "order": "C",
+                    "filters": null,
+                    "dimension_separator": ".",
+                });
+                write_json(&self.path.join(".zarray"), &zarray)?;
+                write_json(&self.path.join(".zattrs"), &self.attributes())
+            }
+            ZarrFormat::V3 => {
+                let mut codecs =
+                    vec![json!({"name": "bytes", "configuration": {"endian": "little"}})];
+                match self.compression {
+                    Compression::None => {}
+                    Compression::Lz4 => codecs.push(
+                        json!({"name": "numcodecs.lz4", "configuration": {"acceleration": 1}}),
+                    ),
+                    Compression::Zstd(level) => codecs.push(
+                        json!({"name": "zstd", "configuration": {"level": level, "checksum": false}}),
+                    ),
+                }
+                let metadata = json!({
+                    "zarr_format": 3,
+                    "node_type": "array",
+                    "shape": shape,
+                    "data_type": "uint16",
+                    "chunk_grid": {"name": "regular", "configuration": {"chunk_shape": chunks}},
+                    "chunk_key_encoding": {"name": "default", "configuration": {"separator": "/"}},
+                    "fill_value": 0,
+                    "codecs": codecs,
+                    "attributes": self.attributes(),
+                    "dimension_names": ["t", "y", "x"],
+                });
+                write_json(&self.path.join("zarr.json"), &metadata)
+            }
+        }
+    }
+    /// Path of the chunk at grid position `index`
+    fn chunk_path(&self, index: [usize; 3]) -> PathBuf {
+        match self.format {
+            ZarrFormat::V2 => self
+                .path
+                .join(format!("{}.{}.{}", index[0], index[1], index[2])),
+            ZarrFormat::V3 => self
+                .path
+                .join("c")
+                .join(index[0].to_string())
+                .join(index[1].to_string())
+                .join(index[2].to_string()),
+        }
+    }
+    /// Write every chunk for the frames in `pending`, padding the chunk out along `t` if there aren't enough
+    fn flush_pending(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let [height, width] = self.dimensions.unwrap_or([0, 0]);
+        let [ct, cy, cx] = self.chunk_shape();
+        let t_index = self.frames_written / ct;
+        for y_index in 0..height.div_ceil(cy) {
+            for x_index in 0..width.div_ceil(cx) {
+                //chunks are always full size, anything outside the image is the fill value
+                let mut chunk = vec![0u16; ct * cy * cx];
+                for (t, frame) in self.pending.iter().enumerate() {
+                    let pixels = frame.as_raw();
+                    for y in 0..cy.min(height - y_index * cy) {
+                        let row = (y_index * cy + y) * width + x_index * cx;
+                        let n = cx.min(width - x_index * cx);
+                        let dst = (t * cy + y) * cx;
+                        chunk[dst..dst + n].copy_from_slice(&pixels[row..row + n]);
+                    }
+                }
+                let bytes: Vec<u8> = chunk.iter().flat_map(|p| p.to_le_bytes()).collect();
+                let bytes = match self.compression {
+                    //numcodecs puts the uncompressed size in front of the LZ4 block
+                    Compression::Lz4 => lz4_flex::compress_prepend_size(&bytes),
+                    compression => compression.compress(&bytes)?,
+                };
+                let path = self.chunk_path([t_index, y_index, x_index]);
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(path, bytes)?;
+            }
+        }
+        self.frames_written += self.pending.len();
+        self.pending.clear();
+        //let readers know about the new frames
+        self.write_metadata(self.frames_written)
+    }
+}
+
+impl FrameWriter for ZarrWriter {
+    fn begin(&mut self, info: &AcquisitionInfo) -> io::Result<()> {
+        self.info = Some(info.clone());
+        Ok(())
+    }
+    fn write_frame(&mut self, frame: &DcamFrame) -> io::Result<()> {
+        if self.finished {
+            return Err(io::Error::other("can't write to a finished Zarr array"));
+        }
+        let image = frame.image();
+        let dimensions = [image.height() as usize, image.width() as usize];
+        if *self.dimensions.get_or_insert(dimensions) != dimensions {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "all frames in a Zarr array must be the same size",
+            ));
+        }
+        if self.stamps.is_empty() {
+            //now that we know the frame size readers can open the (empty) array
+            self.write_metadata(0)?;
+        }
+        self.pending.push(image.clone());
+        self.stamps.push((frame.timestamp(), frame.framestamp()));
+        if self.pending.len() == self.chunk_frames {
+            self.flush_pending()?;
+        }
+        Ok(())
+    }
+    fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.flush_pending()?;
+        self.finished = true;
+        self.write_metadata(self.frames_written)
+    }
+}
+
+impl Drop for ZarrWriter {
+    fn drop(&mut self) {
+        //don't lose the last partial chunk if nobody called finish
+        let _ = self.finish();
+    }
+}
+
+/// Replace the JSON file at `path`, going through a temporary file so readers never see half of it
+fn write_json(path: &Path, value: &Value) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec(value)?)?;
+    fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{frame, info, pixel, temp_path};
+
+    const WIDTH: usize = 5;
+    const HEIGHT: usize = 3;
+
+    /// Read a whole chunk back as pixels
+    fn read_chunk(path: &Path, compression: Compression, pixels: usize) -> Vec<u16> {
+        let bytes = fs::read(path).unwrap();
+        let bytes = match compression {
+            Compression::None => bytes,
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(&bytes).unwrap(),
+            Compression::Zstd(_) => zstd::bulk::decompress(&bytes, pixels * 2).unwrap(),
+        };
+        assert_eq!(bytes.len(), pixels * 2, "chunks are always full size");
+        bytes
+            .chunks_exact(2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+            .collect()
+    }
+
+    /// Put the array back together from its chunks, one `Vec` per frame
+    fn read_array(
+        dir: &Path,
+        format: ZarrFormat,
+        compression: Compression,
+        frames: usize,
+        [ct, cy, cx]: [usize; 3],
+    ) -> Vec<Vec<u16>> {
+        let mut array = vec![vec![0u16; WIDTH * HEIGHT]; frames];
+        for t_index in 0..frames.div_ceil(ct) {
+            for y_index in 0..HEIGHT.div_ceil(cy) {
+                for x_index in 0..WIDTH.div_ceil(cx) {
+                    let path = match format {
+                        ZarrFormat::V2 => dir.join(format!("{}.{}.{}", t_index, y_index, x_index)),
+                        ZarrFormat::V3 => dir
+                            .join("c")
+                            .join(t_index.to_string())
+                            .join(y_index.to_string())
+                            .join(x_index.to_string()),
+                    };
+                    let chunk = read_chunk(&path, compression, ct * cy * cx);
+                    for (i, value) in chunk.into_iter().enumerate() {
+                        let (t, y, x) = (
+                            t_index * ct + i / (cy * cx),
+                            y_index * cy + i / cx % cy,
+                            x_index * cx + i % cx,
+                        );
+                        if t < frames && y < HEIGHT && x < WIDTH {
+                            array[t][y * WIDTH + x] = value;
+                        } else {
+                            assert_eq!(value, 0, "outside the array is the fill value");
+                        }
+                    }
+                }
+            }
+        }
+        array
+    }
+
+    fn expected(frames: usize) -> Vec<Vec<u16>> {
+        (0..frames)
+            .map(|t| {
+                (0..HEIGHT * WIDTH)
+                    .map(|i| pixel(t as i32, (i % WIDTH) as u32, (i / WIDTH) as u32))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn write_frames(writer: &mut ZarrWriter, frames: usize) {
+        for t in 0..frames {
+            writer
+                .write_frame(&frame(WIDTH as u32, HEIGHT as u32, t as i32))
+                .unwrap();
+        }
+    }
+
+    fn read_json(path: &Path) -> Value {
+        serde_json::from_slice(&fs::read(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn v2_partial_chunks() {
+        let dir = temp_path("zarr_v2");
+        let mut writer = ZarrWriter::create(&dir, ZarrFormat::V2).unwrap();
+        writer.set_chunk_shape(2, Some([2, 3]));
+        writer.begin(&info()).unwrap();
+        write_frames(&mut writer, 3);
+        //readers only see the full chunk so far
+        assert_eq!(read_json(&dir.join(".zarray"))["shape"], json!([2, 3, 5]));
+        writer.finish().unwrap();
+
+        let zarray = read_json(&dir.join(".zarray"));
+        assert_eq!(zarray["shape"], json!([3, 3, 5]));
+        assert_eq!(zarray["chunks"], json!([2, 2, 3]));
+        assert_eq!(zarray["dtype"], "<u2");
+        assert_eq!(zarray["compressor"], Value::Null);
+        let zattrs = read_json(&dir.join(".zattrs"));
+        assert_eq!(zattrs["camera"], info().to_json());
+        assert_eq!(zattrs["timestamps"], json!([0.0, 0.01, 0.02]));
+        assert_eq!(zattrs["framestamps"], json!([0, 1, 2]));
+        let array = read_array(&dir, ZarrFormat::V2, Compression::None, 3, [2, 2, 3]);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(array, expected(3));
+    }
+
+    #[test]
+    fn v3_zstd_whole_frames() {
+        let dir = temp_path("zarr_v3");
+        let mut writer = ZarrWriter::create(&dir, ZarrFormat::V3).unwrap();
+        writer.set_chunk_shape(2, None);
+        writer.set_compression(Compression::Zstd(1));
+        writer.begin(&info()).unwrap();
+        write_frames(&mut writer, 4);
+        writer.finish().unwrap();
+
+        let metadata = read_json(&dir.join("zarr.json"));
+        assert_eq!(metadata["shape"], json!([4, 3, 5]));
+        assert_eq!(
+            metadata["chunk_grid"]["configuration"]["chunk_shape"],
+            json!([2, 3, 5])
+        );
+        assert_eq!(metadata["codecs"][1]["name"], "zstd");
+        assert_eq!(metadata["attributes"]["framestamps"], json!([0, 1, 2, 3]));
+        let array = read_array(&dir, ZarrFormat::V3, Compression::Zstd(1), 4, [2, 3, 5]);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(array, expected(4));
+    }
+
+    #[test]
+    fn lz4_chunks_carry_their_size() {
+        let dir = temp_path("zarr_lz4");
+        let mut writer = ZarrWriter::create(&dir, ZarrFormat::V2).unwrap();
+        writer.set_chunk_shape(1, Some([3, 2]));
+        writer.set_compression(Compression::Lz4);
+        write_frames(&mut writer, 1);
+        writer.finish().unwrap();
+
+        assert_eq!(
+            read_json(&dir.join(".zarray"))["compressor"],
+            json!({"id": "lz4", "acceleration": 1})
+        );
+        let array = read_array(&dir, ZarrFormat::V2, Compression::Lz4, 1, [1, 3, 2]);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(array, expected(1));
+    }
+
+    #[test]
+    fn frames_must_match_in_size() {
+        let dir = temp_path("zarr_size");
+        let mut writer = ZarrWriter::create(&dir, ZarrFormat::V2).unwrap();
+        write_frames(&mut writer, 1);
+        let error = writer.write_frame(&frame(2, 2, 1)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        writer.finish().unwrap();
+        //a finished array takes no more frames
+        assert!(writer
+            .write_frame(&frame(WIDTH as u32, HEIGHT as u32, 2))
+            .is_err());
+        assert_eq!(writer.frame_count(), 1);
+        drop(writer);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}